@@ -0,0 +1,51 @@
+-- The schema as it was before migrations, so existing databases pick up from here
+CREATE TABLE IF NOT EXISTS users (
+    id INTEGER PRIMARY KEY,
+    email TEXT NOT NULL,
+    role TEXT DEFAULT 'reader',
+    password TEXT NOT NULL,
+    time_created TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    is_verified BOOLEAN DEFAULT FALSE,
+    verification_uuid TEXT NOT NULL,
+    salt TEXT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS sessions (
+    id INTEGER PRIMARY KEY,
+    session_token TEXT NOT NULL,
+    user_id INTEGER NOT NULL,
+    expires_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (user_id) REFERENCES users(id)
+);
+
+CREATE TABLE IF NOT EXISTS galleries (
+    id INTEGER PRIMARY KEY,
+    user_id INTEGER NOT NULL,
+    name TEXT NOT NULL,
+    gallery_text TEXT NOT NULL,
+    status TEXT DEFAULT 'public',
+    time_created TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (user_id) REFERENCES users(id)
+);
+
+CREATE TABLE IF NOT EXISTS original_images (
+    id INTEGER PRIMARY KEY,
+    user_id INTEGER NOT NULL,
+    gallery_id INTEGER NOT NULL,
+    filename TEXT NOT NULL,
+    path TEXT NOT NULL,
+    time_created TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (gallery_id) REFERENCES galleries(id)
+);
+
+CREATE TABLE IF NOT EXISTS modified_images (
+    id INTEGER PRIMARY KEY,
+    user_id INTEGER NOT NULL,
+    original_image_id INTEGER NOT NULL,
+    path TEXT NOT NULL,
+    caption TEXT NOT NULL,
+    time_created TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    time_modified TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    status TEXT DEFAULT 'public',
+    FOREIGN KEY (original_image_id) REFERENCES original_images(id)
+);
@@ -0,0 +1,9 @@
+CREATE TABLE IF NOT EXISTS verification_tokens (
+    id INTEGER PRIMARY KEY,
+    user_id INTEGER NOT NULL,
+    token TEXT NOT NULL UNIQUE,
+    purpose TEXT NOT NULL,
+    time_created TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    time_consumed TIMESTAMP,
+    FOREIGN KEY (user_id) REFERENCES users(id)
+);
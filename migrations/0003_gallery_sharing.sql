@@ -0,0 +1,19 @@
+CREATE TABLE IF NOT EXISTS gallery_members (
+    id INTEGER PRIMARY KEY,
+    gallery_id INTEGER NOT NULL,
+    user_id INTEGER NOT NULL,
+    time_created TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    UNIQUE (gallery_id, user_id),
+    FOREIGN KEY (gallery_id) REFERENCES galleries(id),
+    FOREIGN KEY (user_id) REFERENCES users(id)
+);
+
+CREATE TABLE IF NOT EXISTS gallery_editors (
+    id INTEGER PRIMARY KEY,
+    gallery_id INTEGER NOT NULL,
+    user_id INTEGER NOT NULL,
+    time_created TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    UNIQUE (gallery_id, user_id),
+    FOREIGN KEY (gallery_id) REFERENCES galleries(id),
+    FOREIGN KEY (user_id) REFERENCES users(id)
+);
@@ -0,0 +1,13 @@
+-- Work queued by requests and picked up by the background workers
+CREATE TABLE jobs (
+    id INTEGER PRIMARY KEY,
+    payload TEXT NOT NULL,
+    status TEXT NOT NULL DEFAULT 'queued',
+    attempts INTEGER NOT NULL DEFAULT 0,
+    last_error TEXT,
+    time_created TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    time_started TIMESTAMP,
+    time_finished TIMESTAMP
+);
+
+CREATE INDEX jobs_status ON jobs (status, id);
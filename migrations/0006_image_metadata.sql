@@ -0,0 +1,12 @@
+-- EXIF read from the original upload, one row per original image
+CREATE TABLE image_metadata (
+    original_image_id INTEGER PRIMARY KEY,
+    capture_time TEXT,
+    camera_make TEXT,
+    camera_model TEXT,
+    orientation INTEGER,
+    latitude REAL,
+    longitude REAL,
+    time_created TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (original_image_id) REFERENCES original_images(id)
+);
@@ -0,0 +1,7 @@
+-- How each image was cut out of its original, so it can be cropped again.
+-- Images cropped in the browser before this have no crop.
+ALTER TABLE modified_images ADD COLUMN crop_x INTEGER;
+ALTER TABLE modified_images ADD COLUMN crop_y INTEGER;
+ALTER TABLE modified_images ADD COLUMN crop_width INTEGER;
+ALTER TABLE modified_images ADD COLUMN crop_height INTEGER;
+ALTER TABLE modified_images ADD COLUMN rotation INTEGER;
@@ -0,0 +1,14 @@
+-- Chunked uploads still being sent. The bytes received so far are kept in
+-- IMG_PATH/<id>.part until the upload is finished.
+CREATE TABLE uploads (
+    id TEXT PRIMARY KEY,
+    user_id INTEGER NOT NULL,
+    gallery_id INTEGER NOT NULL,
+    file_name TEXT NOT NULL,
+    length INTEGER NOT NULL,
+    received INTEGER NOT NULL DEFAULT 0,
+    time_created TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    time_modified TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (user_id) REFERENCES users(id),
+    FOREIGN KEY (gallery_id) REFERENCES galleries(id)
+);
@@ -0,0 +1,2 @@
+-- Videos are stored alongside images, everything uploaded before was an image.
+ALTER TABLE modified_images ADD COLUMN media_type TEXT NOT NULL DEFAULT 'image';
@@ -0,0 +1,8 @@
+-- Hashes of each original, to find photos uploaded more than once. The content
+-- hash matches identical files, the perceptual hash (a 64 bit dHash) similar
+-- looking ones. Originals uploaded before this have neither.
+ALTER TABLE original_images ADD COLUMN content_hash TEXT;
+ALTER TABLE original_images ADD COLUMN perceptual_hash INTEGER;
+-- The earlier original in the same gallery this one looks like, if any
+ALTER TABLE original_images ADD COLUMN near_duplicate_of INTEGER REFERENCES original_images(id);
+CREATE INDEX original_images_content_hash ON original_images (gallery_id, content_hash);
@@ -0,0 +1,4 @@
+-- When images and galleries were deleted, so their files can be purged after a
+-- while. Those deleted before this are treated as deleted when last changed.
+ALTER TABLE galleries ADD COLUMN time_deleted TIMESTAMP;
+ALTER TABLE modified_images ADD COLUMN time_deleted TIMESTAMP;
@@ -0,0 +1,54 @@
+-- Stored files are named after the SHA-256 of their bytes, so images with the
+-- same bytes share one file. Each file has a row here counting the image rows
+-- that use it, kept up to date by the triggers below, and is only deleted once
+-- nothing does. Files stored before this keep their names and are counted too.
+CREATE TABLE blobs (
+    path TEXT PRIMARY KEY,
+    refs INTEGER NOT NULL DEFAULT 0
+);
+
+INSERT INTO blobs (path, refs)
+SELECT path, COUNT(*) FROM (
+    SELECT path FROM original_images
+    UNION ALL
+    SELECT path FROM modified_images
+)
+GROUP BY path;
+
+CREATE TRIGGER original_images_add_ref AFTER INSERT ON original_images
+BEGIN
+    INSERT OR IGNORE INTO blobs (path) VALUES (NEW.path);
+    UPDATE blobs SET refs = refs + 1 WHERE path = NEW.path;
+END;
+
+CREATE TRIGGER original_images_move_ref AFTER UPDATE OF path ON original_images
+WHEN OLD.path != NEW.path
+BEGIN
+    UPDATE blobs SET refs = refs - 1 WHERE path = OLD.path;
+    INSERT OR IGNORE INTO blobs (path) VALUES (NEW.path);
+    UPDATE blobs SET refs = refs + 1 WHERE path = NEW.path;
+END;
+
+CREATE TRIGGER original_images_drop_ref AFTER DELETE ON original_images
+BEGIN
+    UPDATE blobs SET refs = refs - 1 WHERE path = OLD.path;
+END;
+
+CREATE TRIGGER modified_images_add_ref AFTER INSERT ON modified_images
+BEGIN
+    INSERT OR IGNORE INTO blobs (path) VALUES (NEW.path);
+    UPDATE blobs SET refs = refs + 1 WHERE path = NEW.path;
+END;
+
+CREATE TRIGGER modified_images_move_ref AFTER UPDATE OF path ON modified_images
+WHEN OLD.path != NEW.path
+BEGIN
+    UPDATE blobs SET refs = refs - 1 WHERE path = OLD.path;
+    INSERT OR IGNORE INTO blobs (path) VALUES (NEW.path);
+    UPDATE blobs SET refs = refs + 1 WHERE path = NEW.path;
+END;
+
+CREATE TRIGGER modified_images_drop_ref AFTER DELETE ON modified_images
+BEGIN
+    UPDATE blobs SET refs = refs - 1 WHERE path = OLD.path;
+END;
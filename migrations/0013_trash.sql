@@ -0,0 +1,7 @@
+-- Who deleted galleries and images, and what they were before, so they can be
+-- listed in the trash and restored. Those deleted before this come back as
+-- private galleries and ready images.
+ALTER TABLE galleries ADD COLUMN deleted_by INTEGER REFERENCES users(id);
+ALTER TABLE galleries ADD COLUMN status_before_deletion TEXT;
+ALTER TABLE modified_images ADD COLUMN deleted_by INTEGER REFERENCES users(id);
+ALTER TABLE modified_images ADD COLUMN status_before_deletion TEXT;
@@ -0,0 +1,3 @@
+-- When the admin was last asked to approve each user, so resending the request
+-- can be limited. Users who signed up before this can ask once straight away.
+ALTER TABLE users ADD COLUMN approval_requested_at INTEGER;
@@ -0,0 +1,12 @@
+-- Originals saved before their own paths were stored point at the image cut from
+-- them, and the file they were saved to is named by nothing. They are flagged
+-- here until `fsck --relink-originals` finds that file, and images made from
+-- them can't be cropped again until then.
+ALTER TABLE original_images ADD COLUMN is_legacy BOOLEAN NOT NULL DEFAULT FALSE;
+
+UPDATE original_images SET is_legacy = TRUE
+WHERE EXISTS (
+    SELECT 1 FROM modified_images
+    WHERE modified_images.original_image_id = original_images.id
+      AND modified_images.path = original_images.path
+);
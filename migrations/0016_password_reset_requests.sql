@@ -0,0 +1,10 @@
+-- When each user was last sent a password reset link, and the addresses that
+-- asked for one recently, so the forgot password form can't flood inboxes
+ALTER TABLE users ADD COLUMN password_reset_requested_at INTEGER;
+
+CREATE TABLE password_reset_requests (
+    ip TEXT NOT NULL,
+    requested_at INTEGER NOT NULL
+);
+
+CREATE INDEX idx_password_reset_requests_ip ON password_reset_requests (ip);
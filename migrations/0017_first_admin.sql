@@ -0,0 +1,9 @@
+-- Only admins can change galleries that aren't theirs or shared with them, and
+-- approve new users. Databases from before there were admins get one: their
+-- first verified, enabled writer. New databases get theirs with `john_rocket admin`.
+UPDATE users SET role = 'admin'
+WHERE id = (
+    SELECT MIN(id) FROM users
+    WHERE role = 'writer' AND is_verified = TRUE AND is_disabled = FALSE
+)
+AND NOT EXISTS (SELECT 1 FROM users WHERE role = 'admin');
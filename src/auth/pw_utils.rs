@@ -43,7 +43,6 @@ pub fn hash_and_salt_password(user_password: &str) -> Result<SaltedPassword, pas
 #[cfg(test)]
 mod tests {
     use super::*;
-    use argon2::password_hash::SaltString;
 
     #[test]
     fn test_hash_and_salt_password_basic() {
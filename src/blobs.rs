@@ -0,0 +1,357 @@
+use crate::constants;
+use crate::db::queries;
+use crate::db::queries::Db;
+use crate::errors;
+use crate::hashing;
+use crate::storage::{self, Reader, Storage};
+use log::error;
+use rocket::http::Status;
+use rocket::tokio::fs::{self, File};
+use rocket::tokio::io::{AsyncRead, ReadBuf};
+use sha2::{Digest, Sha256};
+use std::io;
+use std::path::Path;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+// Originals and served images are stored under the SHA-256 of their bytes, so
+// the same photo uploaded to several galleries, or cropped the same way twice,
+// is stored once. The `blobs` table counts the image rows using each file, and
+// a file is only deleted once nothing does.
+//
+// Renditions are named after the image they were made from rather than their own
+// bytes, they can always be made again. As the name says what the bytes should
+// be, files are checked against it whenever they are read whole.
+
+fn is_hash(text: &str) -> bool {
+    text.len() == 64
+        && text
+            .bytes()
+            .all(|byte| matches!(byte, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+/// The hash a stored file is named after, if it is content addressed
+///
+/// That is `<hash>` or `<hash>.<extension>`; renditions and thumbnails, with
+/// more to their names, are not.
+pub fn hash_of(key: &str) -> Option<&str> {
+    let (hash, extension) = match key.split_once('.') {
+        Some((hash, extension)) => (hash, Some(extension)),
+        None => (key, None),
+    };
+    let plain_extension = extension.is_none_or(|extension| !extension.contains('.'));
+    Some(hash).filter(|hash| is_hash(hash) && plain_extension)
+}
+
+/// Path of the file with this content hash, as stored in the database
+pub fn blob_path(hash: &str, extension: Option<&str>) -> String {
+    match extension {
+        Some(extension) => format!("{}/{}.{}", constants::IMG_PATH, hash, extension),
+        None => format!("{}/{}", constants::IMG_PATH, hash),
+    }
+}
+
+/// Rename a local file after its content hash, beside where it is, returning
+/// where it went and the path to store for it
+pub async fn address(
+    local: &str,
+    extension: Option<&str>,
+) -> Result<(String, String), errors::AppError> {
+    let hash = hashing::content_hash(File::open(local).await?).await?;
+    let path = blob_path(&hash, extension);
+    let addressed = Path::new(local).with_file_name(storage::key(&path));
+    fs::rename(local, &addressed).await?;
+    Ok((addressed.to_string_lossy().to_string(), path))
+}
+
+/// Check that a local copy of the stored file `key` has the bytes its name says
+pub async fn verify_file(key: &str, local: &str) -> Result<(), errors::AppError> {
+    let expected = match hash_of(key) {
+        Some(expected) => expected,
+        None => return Ok(()),
+    };
+    let actual = hashing::content_hash(File::open(local).await?).await?;
+    if actual != expected {
+        error!("{} doesn't match its hash, it is {}", key, actual);
+        return Err(errors::AppError {
+            code: Status::InternalServerError.code,
+            message: format!("{} doesn't match its hash", key),
+        });
+    }
+    Ok(())
+}
+
+/// A stored file being read, that fails at the end if its bytes don't match
+/// the hash it is named after
+///
+/// A response has been started by then, so the client sees the connection cut
+/// rather than getting a corrupt file that looks whole.
+pub struct Verified<R> {
+    key: String,
+    expected: String,
+    hasher: Sha256,
+    inner: R,
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for Verified<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        let before = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        let read = &buf.filled()[before..];
+
+        // Nothing read into room for more is the end of the file
+        if !read.is_empty() || buf.remaining() == 0 {
+            this.hasher.update(read);
+            return Poll::Ready(Ok(()));
+        }
+        let actual = hashing::hex(&this.hasher.clone().finalize());
+        if actual != this.expected {
+            error!("{} doesn't match its hash, it is {}", this.key, actual);
+            return Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} doesn't match its hash", this.key),
+            )));
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Whether `key` is the file `wanted` or one of its renditions or thumbnail
+///
+/// An original and the video made from it can share a hash, so `<hash>.mp4` and
+/// its renditions aren't those of `<hash>`.
+pub fn is_file_or_rendition(key: &str, wanted: &str) -> bool {
+    let rest = key
+        .strip_prefix(wanted)
+        .and_then(|rest| rest.strip_prefix('.'));
+    key == wanted || rest.is_some_and(|rest| rest.matches('.').count() == 1)
+}
+
+/// Delete the files no image uses any more, with their renditions, returning
+/// the keys deleted
+pub async fn delete_unreferenced(
+    db: &Db,
+    storage: &dyn Storage,
+) -> Result<Vec<String>, errors::AppError> {
+    let paths = queries::get_unreferenced_blobs(db).await?;
+    if paths.is_empty() {
+        return Ok(vec![]);
+    }
+
+    // Renditions aren't counted, so they are found by name
+    let keys = storage.list().await?;
+    let mut deleted = vec![];
+    for path in paths {
+        // The claim holds the database until the files are gone, so an image
+        // starting to use one again waits, then stores it afresh
+        let mut tx = db.0.begin().await?;
+        if !queries::claim_blob(&mut tx, &path).await? {
+            continue;
+        }
+        let wanted = storage::key(&path);
+        for key in keys.iter().filter(|key| is_file_or_rendition(key, wanted)) {
+            storage.delete(key).await?;
+            deleted.push(key.clone());
+        }
+        tx.commit().await?;
+    }
+    Ok(deleted)
+}
+
+/// The whole of the stored file `key`, checked as it is read if it is content
+/// addressed
+///
+/// Parts of a file can't be checked, so only whole files should go through this.
+pub fn verified(key: &str, reader: Reader) -> Reader {
+    match hash_of(key) {
+        Some(expected) => Box::pin(Verified {
+            key: key.to_string(),
+            expected: expected.to_string(),
+            hasher: Sha256::new(),
+            inner: reader,
+        }),
+        None => reader,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::queries::tests::{create_image, insert_test_user, test_db};
+    use crate::storage::{LocalStorage, StoredInfo};
+    use rocket::tokio::io::AsyncReadExt;
+
+    // SHA-256 of "hello"
+    static HELLO: &str = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+
+    fn temp_dir() -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("jv-blobs-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir(&dir).unwrap();
+        dir
+    }
+
+    async fn read_all(reader: Reader) -> io::Result<Vec<u8>> {
+        let mut reader = reader;
+        let mut bytes = vec![];
+        reader.read_to_end(&mut bytes).await?;
+        Ok(bytes)
+    }
+
+    #[test]
+    fn test_hash_of() {
+        assert_eq!(hash_of(HELLO), Some(HELLO));
+        assert_eq!(hash_of(&format!("{}.mp4", HELLO)), Some(HELLO));
+        assert_eq!(hash_of(&format!("{}.300.webp", HELLO)), None);
+        assert_eq!(hash_of(&format!("{}.thumbnail.jpg", HELLO)), None);
+        assert_eq!(hash_of(&HELLO.to_uppercase()), None);
+        assert_eq!(hash_of(&HELLO[1..]), None);
+        assert_eq!(hash_of("9b2f5d0e-7c41-4d3a-a2a4-3f0c5e6b1d8a"), None);
+    }
+
+    #[test]
+    fn test_blob_path() {
+        assert_eq!(blob_path(HELLO, None), format!("./img/{}", HELLO));
+        assert_eq!(
+            blob_path(HELLO, Some("mp4")),
+            format!("./img/{}.mp4", HELLO)
+        );
+    }
+
+    #[test]
+    fn test_is_file_or_rendition() {
+        assert!(is_file_or_rendition("abc", "abc"));
+        assert!(is_file_or_rendition("abc.300.webp", "abc"));
+        assert!(is_file_or_rendition("abc.thumbnail.jpg", "abc"));
+        assert!(is_file_or_rendition("abc.mp4.300.jpg", "abc.mp4"));
+        // A video made from an original with the same hash is its own file
+        assert!(!is_file_or_rendition("abc.mp4", "abc"));
+        assert!(!is_file_or_rendition("abc.mp4.300.jpg", "abc"));
+        assert!(!is_file_or_rendition("abcd.300.jpg", "abc"));
+    }
+
+    #[rocket::async_test]
+    async fn test_address_then_verify() {
+        let dir = temp_dir();
+        let local = dir.join("made.jpg").to_string_lossy().to_string();
+        std::fs::write(&local, b"hello").unwrap();
+
+        let (local, path) = address(&local, Some("mp4")).await.unwrap();
+        assert_eq!(path, blob_path(HELLO, Some("mp4")));
+        assert_eq!(local, dir.join(storage::key(&path)).to_string_lossy());
+        assert!(!dir.join("made.jpg").exists());
+        assert!(verify_file(storage::key(&path), &local).await.is_ok());
+
+        std::fs::write(&local, b"hellO").unwrap();
+        let error = verify_file(storage::key(&path), &local).await.unwrap_err();
+        assert_eq!(error.code, 500);
+        // Files named otherwise aren't checked
+        assert!(verify_file("photo.300.jpg", &local).await.is_ok());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[rocket::async_test]
+    async fn test_verified_reads() {
+        let whole = verified(HELLO, Box::pin(&b"hello"[..]));
+        assert_eq!(read_all(whole).await.unwrap(), b"hello");
+
+        let corrupt = verified(HELLO, Box::pin(&b"hellO"[..]));
+        let error = read_all(corrupt).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+
+        let truncated = verified(HELLO, Box::pin(&b"hell"[..]));
+        assert!(read_all(truncated).await.is_err());
+
+        let rendition = verified("photo.300.jpg", Box::pin(&b"anything"[..]));
+        assert_eq!(read_all(rendition).await.unwrap(), b"anything");
+    }
+
+    // Storage that has an image start using `path` again while it is being
+    // listed, after the unused files were found
+    struct ReusedWhileListing {
+        inner: LocalStorage,
+        db: Db,
+        image_id: i64,
+        path: String,
+    }
+
+    #[rocket::async_trait]
+    impl Storage for ReusedWhileListing {
+        async fn put(&self, key: &str, from: &Path) -> Result<(), errors::AppError> {
+            self.inner.put(key, from).await
+        }
+
+        async fn get(&self, key: &str, to: &Path) -> Result<bool, errors::AppError> {
+            self.inner.get(key, to).await
+        }
+
+        async fn stream(
+            &self,
+            key: &str,
+            range: Option<(u64, u64)>,
+        ) -> Result<Option<Reader>, errors::AppError> {
+            self.inner.stream(key, range).await
+        }
+
+        async fn head(&self, key: &str) -> Result<Option<StoredInfo>, errors::AppError> {
+            self.inner.head(key).await
+        }
+
+        async fn delete(&self, key: &str) -> Result<(), errors::AppError> {
+            self.inner.delete(key).await
+        }
+
+        async fn list(&self) -> Result<Vec<String>, errors::AppError> {
+            queries::set_image_path(&self.db, self.image_id, &self.path).await?;
+            self.inner.list().await
+        }
+    }
+
+    #[rocket::async_test]
+    async fn test_files_used_again_while_collecting_are_kept() {
+        let dir = temp_dir();
+        let db = test_db().await;
+        let owner_id = insert_test_user(&db, "owner@example.com").await;
+        let gallery_id = queries::create_gallery(&db, owner_id, "gallery")
+            .await
+            .unwrap();
+        let image = create_image(&db, owner_id, gallery_id, "photo.jpg", "", None)
+            .await
+            .unwrap();
+
+        // A stored crop no image uses any more
+        let path = blob_path(HELLO, Some("jpg"));
+        queries::set_image_path(&db, image.id, &path).await.unwrap();
+        queries::set_image_path(&db, image.id, &image.path)
+            .await
+            .unwrap();
+        std::fs::write(dir.join(storage::key(&path)), b"hello").unwrap();
+
+        let storage = ReusedWhileListing {
+            inner: LocalStorage::new(&dir),
+            db: db.clone(),
+            image_id: image.id,
+            path: path.clone(),
+        };
+        assert!(delete_unreferenced(&db, &storage).await.unwrap().is_empty());
+        assert!(dir.join(storage::key(&path)).exists());
+
+        // Once it is unused again nothing stops it going
+        queries::set_image_path(&db, image.id, &image.path)
+            .await
+            .unwrap();
+        let storage = LocalStorage::new(&dir);
+        assert_eq!(
+            delete_unreferenced(&db, &storage).await.unwrap(),
+            vec![storage::key(&path)]
+        );
+        assert!(!dir.join(storage::key(&path)).exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,257 @@
+use crate::constants;
+use crate::storage::StoredInfo;
+use chrono::{DateTime, Utc};
+use rocket::http::Status;
+use rocket::response::{self, Responder, Response};
+use rocket::Request;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::time::{Duration, UNIX_EPOCH};
+
+/// How long a response may be reused before checking with the server
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CachePolicy {
+    /// Never changes, like files under an image's UUID path. Only the user it
+    /// was sent to may keep it, as it needed their session.
+    Immutable,
+    /// May change, so must be checked every time, which is cheap with an ETag
+    Revalidate,
+}
+
+impl CachePolicy {
+    fn header(&self) -> String {
+        match self {
+            CachePolicy::Immutable => format!(
+                "private, max-age={}, immutable",
+                constants::IMMUTABLE_MAX_AGE
+            ),
+            CachePolicy::Revalidate => "no-cache".to_string(),
+        }
+    }
+}
+
+/// A response with `Cache-Control`, `ETag` and `Last-Modified` headers
+///
+/// If the request's `If-None-Match` has the same ETag the client's copy is still
+/// good, and a bodiless 304 is sent instead of `inner`. If `inner` doesn't
+/// succeed, e.g. a 416 for a bad range, it is sent with `no-store` and no
+/// validators, so the failure isn't kept in place of the file.
+pub struct Cached<R> {
+    inner: R,
+    etag: String,
+    last_modified: Option<DateTime<Utc>>,
+    policy: CachePolicy,
+}
+
+impl<R> Cached<R> {
+    /// Cache a file, with an ETag that changes whenever the file is written
+    pub fn file(inner: R, info: &StoredInfo, policy: CachePolicy) -> Self {
+        let modified = info.last_modified;
+        let since_epoch = modified
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .unwrap_or(Duration::ZERO);
+        Cached {
+            inner,
+            etag: format!("\"{:x}-{:x}\"", info.length, since_epoch.as_nanos()),
+            last_modified: modified.map(DateTime::<Utc>::from),
+            policy,
+        }
+    }
+
+    /// Cache a response made in memory, with an ETag from a hash of its body
+    pub fn content(inner: R, content: &str, policy: CachePolicy) -> Self {
+        let mut hasher = DefaultHasher::new();
+        content.hash(&mut hasher);
+        Cached {
+            inner,
+            etag: format!("\"{:x}\"", hasher.finish()),
+            last_modified: None,
+            policy,
+        }
+    }
+}
+
+/// Whether an `If-None-Match` header lists `etag`
+///
+/// Weak comparison is used, as the spec asks for `If-None-Match`.
+pub fn etag_matches(if_none_match: &str, etag: &str) -> bool {
+    let etag = etag.trim_start_matches("W/");
+    if_none_match.trim() == "*"
+        || if_none_match
+            .split(',')
+            .any(|candidate| candidate.trim().trim_start_matches("W/") == etag)
+}
+
+/// A time as HTTP headers write it, e.g. "Sun, 06 Nov 1994 08:49:37 GMT"
+pub fn http_date(time: &DateTime<Utc>) -> String {
+    time.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+impl<'r, 'o: 'r, R: Responder<'r, 'o>> Responder<'r, 'o> for Cached<R> {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'o> {
+        let not_modified = request
+            .headers()
+            .get("If-None-Match")
+            .any(|if_none_match| etag_matches(if_none_match, &self.etag));
+
+        let mut response = if not_modified {
+            Response::build().status(Status::NotModified).finalize()
+        } else {
+            self.inner.respond_to(request)?
+        };
+
+        if response.status().class().is_client_error()
+            || response.status().class().is_server_error()
+        {
+            response.set_raw_header("Cache-Control", "no-store");
+            return Ok(response);
+        }
+
+        response.set_raw_header("Cache-Control", self.policy.header());
+        response.set_raw_header("ETag", self.etag);
+        if let Some(last_modified) = self.last_modified {
+            response.set_raw_header("Last-Modified", http_date(&last_modified));
+        }
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_etag_matches() {
+        assert!(etag_matches("\"abc\"", "\"abc\""));
+        assert!(etag_matches("\"x\", \"abc\"", "\"abc\""));
+        assert!(etag_matches("W/\"abc\"", "\"abc\""));
+        assert!(etag_matches("*", "\"abc\""));
+        assert!(!etag_matches("\"abd\"", "\"abc\""));
+        assert!(!etag_matches("abc", "\"abc\""));
+        assert!(!etag_matches("", "\"abc\""));
+    }
+
+    #[test]
+    fn test_http_date() {
+        let time = Utc.with_ymd_and_hms(1994, 11, 6, 8, 49, 37).unwrap();
+        assert_eq!(http_date(&time), "Sun, 06 Nov 1994 08:49:37 GMT");
+    }
+
+    #[test]
+    fn test_content_etag_follows_content() {
+        let etag = |content| Cached::content((), content, CachePolicy::Revalidate).etag;
+
+        assert_eq!(etag("body { }"), etag("body { }"));
+        assert_ne!(etag("body { }"), etag("body { color: red }"));
+    }
+
+    #[test]
+    fn test_file_etag_follows_file() {
+        let path = std::env::temp_dir().join(format!("etag-{}", uuid::Uuid::new_v4()));
+        let etag = || {
+            let metadata = std::fs::metadata(&path).unwrap();
+            Cached::file((), &StoredInfo::from(&metadata), CachePolicy::Immutable).etag
+        };
+
+        std::fs::write(&path, b"one").unwrap();
+        let first = etag();
+        assert_eq!(first, etag());
+
+        std::fs::write(&path, b"three").unwrap();
+        assert_ne!(first, etag());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    // Whatever status was asked for, cached as if it were a file
+    #[rocket::get("/<status>")]
+    fn respond(status: u16) -> Cached<(Status, &'static str)> {
+        let status = Status::from_code(status).unwrap();
+        Cached::content((status, "body"), "body", CachePolicy::Immutable)
+    }
+
+    #[test]
+    fn test_failures_are_not_cached() {
+        use rocket::local::blocking::Client;
+
+        let client =
+            Client::untracked(rocket::build().mount("/", rocket::routes![respond])).unwrap();
+        let headers = |status: u16| {
+            let response = client.get(format!("/{}", status)).dispatch();
+            let header = |name| response.headers().get_one(name).map(str::to_string);
+            (header("Cache-Control"), header("ETag"))
+        };
+
+        let (cache_control, etag) = headers(200);
+        assert_eq!(cache_control, Some(CachePolicy::Immutable.header()));
+        assert!(etag.is_some());
+        for status in [416, 500] {
+            assert_eq!(headers(status), (Some("no-store".to_string()), None));
+        }
+    }
+
+    // Sends `uri` twice, the second time with the ETag the first got, returning
+    // the second response's status, body and Cache-Control
+    async fn revalidate(
+        client: &rocket::local::asynchronous::Client,
+        uri: &str,
+        cookie: &rocket::http::Cookie<'static>,
+    ) -> (Status, String, Option<String>) {
+        let first = client
+            .get(uri)
+            .private_cookie(cookie.clone())
+            .dispatch()
+            .await;
+        assert_eq!(first.status(), Status::Ok);
+        let etag = first.headers().get_one("ETag").unwrap().to_string();
+
+        let second = client
+            .get(uri)
+            .private_cookie(cookie.clone())
+            .header(rocket::http::Header::new("If-None-Match", etag.clone()))
+            .dispatch()
+            .await;
+        let status = second.status();
+        let cache_control = second
+            .headers()
+            .get_one("Cache-Control")
+            .map(str::to_string);
+        assert_eq!(second.headers().get_one("ETag"), Some(etag.as_str()));
+        let body = second.into_string().await.unwrap_or_default();
+        (status, body, cache_control)
+    }
+
+    #[rocket::async_test]
+    async fn test_matching_etag_gets_bodiless_304() {
+        use crate::db::queries::{self, Db};
+        use crate::storage;
+        use crate::tests::{log_in_writer, test_client};
+        use rocket_db_pools::Database;
+
+        let (client, dir) = test_client().await;
+        let (user_id, cookie) = log_in_writer(&client, "writer@example.com").await;
+        let db = Db::fetch(client.rocket()).unwrap();
+        let gallery_id = queries::create_gallery(db, user_id, "gallery")
+            .await
+            .unwrap();
+        let image = queries::tests::create_image(db, user_id, gallery_id, "photo.jpg", "", None)
+            .await
+            .unwrap();
+        let key = storage::key(&image.path);
+        std::fs::write(dir.join("img").join(key), b"image bytes").unwrap();
+
+        let (status, body, cache_control) =
+            revalidate(&client, &format!("/img/{}", key), &cookie).await;
+        assert_eq!(status, Status::NotModified);
+        assert!(body.is_empty());
+        assert_eq!(cache_control, Some(CachePolicy::Immutable.header()));
+
+        let (status, body, cache_control) = revalidate(&client, "/css/style.css", &cookie).await;
+        assert_eq!(status, Status::NotModified);
+        assert!(body.is_empty());
+        assert_eq!(cache_control, Some(CachePolicy::Revalidate.header()));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -8,5 +8,5 @@ pub fn not_authorized() -> Redirect {
 
 #[catch(403)]
 pub fn forbidden() -> &'static str {
-    "Access Denied: You need Writer role to perform this action"
+    "Access Denied: You don't have permission to perform this action"
 }
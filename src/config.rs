@@ -0,0 +1,198 @@
+use crate::constants;
+use rocket::serde::Deserialize;
+
+/// Runtime settings for the app, read from the Rocket figment
+///
+/// Every field falls back to the matching value in `constants`, so the app runs
+/// without any extra configuration. Values can be overridden in `Rocket.toml` or
+/// with `ROCKET_`-prefixed environment variables, e.g. `ROCKET_SESSION_LENGTH`.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct AppConfig {
+    /// How long (in seconds) a new or renewed session stays valid
+    #[serde(default = "default_session_length")]
+    pub session_length: i64,
+    /// Sessions used when they have less than this many seconds left are renewed
+    #[serde(default = "default_session_renewal_window")]
+    pub session_renewal_window: i64,
+    /// How often (in seconds) expired sessions are purged from the database
+    #[serde(default = "default_session_purge_interval")]
+    pub session_purge_interval: u64,
+    /// How long (in seconds) a signup verification link can be used for
+    #[serde(default = "default_verification_ttl")]
+    pub verification_ttl: i64,
+    /// How long (in seconds) a pending user has to wait before the admin can be
+    /// asked to approve them again
+    #[serde(default = "default_approval_resend_interval")]
+    pub approval_resend_interval: i64,
+    /// How long (in seconds) a password reset link can be used for
+    #[serde(default = "default_password_reset_ttl")]
+    pub password_reset_ttl: i64,
+    /// How long (in seconds) before another password reset link can be sent to
+    /// the same user, and the window in which one address can ask for a few
+    #[serde(default = "default_password_reset_interval")]
+    pub password_reset_interval: i64,
+    /// How many password reset links one address can ask for in that window
+    #[serde(default = "default_password_reset_ip_limit")]
+    pub password_reset_ip_limit: i64,
+    /// How many background workers process queued jobs
+    #[serde(default = "default_job_workers")]
+    pub job_workers: usize,
+    /// How long (in seconds) an idle worker waits before checking for new jobs
+    #[serde(default = "default_job_poll_interval")]
+    pub job_poll_interval: u64,
+    /// How many times a job is tried before it is marked as failed
+    #[serde(default = "default_job_max_attempts")]
+    pub job_max_attempts: i64,
+    /// Widths (in pixels) of the renditions made for every uploaded image
+    #[serde(default = "default_rendition_sizes")]
+    pub rendition_sizes: Vec<u32>,
+    /// Largest chunk (in bytes) accepted by one request of a chunked upload
+    #[serde(default = "default_upload_chunk_limit")]
+    pub upload_chunk_limit: u64,
+    /// Largest file (in bytes) that can be sent as a chunked upload
+    #[serde(default = "default_upload_max_length")]
+    pub upload_max_length: i64,
+    /// How long (in seconds) an unfinished chunked upload is kept after its last chunk
+    #[serde(default = "default_upload_ttl")]
+    pub upload_ttl: i64,
+    /// Directory on this machine where chunked uploads are put together before
+    /// they are stored, whichever backend stores them
+    #[serde(default = "default_upload_path")]
+    pub upload_path: String,
+    /// How many days deleted galleries and images stay in the trash before they
+    /// and the files only they use are deleted for good
+    #[serde(default = "default_trash_retention_days")]
+    pub trash_retention_days: i64,
+    /// The ffmpeg binary used to transcode videos and grab their poster frames
+    #[serde(default = "default_ffmpeg_path")]
+    pub ffmpeg_path: String,
+    /// Images whose perceptual hashes differ by at most this many bits (of 64)
+    /// are flagged as near duplicates
+    #[serde(default = "default_near_duplicate_distance")]
+    pub near_duplicate_distance: u32,
+    /// Where image files are kept, see `StorageConfig`
+    #[serde(default)]
+    pub storage: StorageConfig,
+}
+
+/// Which `storage::Storage` backend keeps the files, chosen by its `backend`
+///
+/// ```toml
+/// [default.storage]
+/// backend = "s3"
+/// endpoint = "http://localhost:9000"
+/// bucket = "jv"
+/// access_key = "minioadmin"
+/// secret_key = "minioadmin"
+/// ```
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(crate = "rocket::serde", tag = "backend", rename_all = "lowercase")]
+pub enum StorageConfig {
+    /// A directory on this machine
+    Local {
+        #[serde(default = "default_storage_path")]
+        path: String,
+    },
+    /// A bucket of an S3-compatible service, e.g. MinIO
+    S3 {
+        endpoint: String,
+        bucket: String,
+        #[serde(default = "default_s3_region")]
+        region: String,
+        access_key: String,
+        secret_key: String,
+    },
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        StorageConfig::Local {
+            path: default_storage_path(),
+        }
+    }
+}
+
+fn default_session_length() -> i64 {
+    constants::SESSION_LENGTH
+}
+
+fn default_session_renewal_window() -> i64 {
+    constants::SESSION_RENEWAL_WINDOW
+}
+
+fn default_session_purge_interval() -> u64 {
+    constants::SESSION_PURGE_INTERVAL
+}
+
+fn default_verification_ttl() -> i64 {
+    constants::VERIFICATION_TTL
+}
+
+fn default_approval_resend_interval() -> i64 {
+    constants::APPROVAL_RESEND_INTERVAL
+}
+
+fn default_password_reset_ttl() -> i64 {
+    constants::PASSWORD_RESET_TTL
+}
+
+fn default_password_reset_interval() -> i64 {
+    constants::PASSWORD_RESET_INTERVAL
+}
+
+fn default_password_reset_ip_limit() -> i64 {
+    constants::PASSWORD_RESET_IP_LIMIT
+}
+
+fn default_job_workers() -> usize {
+    constants::JOB_WORKERS
+}
+
+fn default_job_poll_interval() -> u64 {
+    constants::JOB_POLL_INTERVAL
+}
+
+fn default_job_max_attempts() -> i64 {
+    constants::JOB_MAX_ATTEMPTS
+}
+
+fn default_rendition_sizes() -> Vec<u32> {
+    constants::RENDITION_SIZES.to_vec()
+}
+
+fn default_upload_chunk_limit() -> u64 {
+    constants::UPLOAD_CHUNK_LIMIT
+}
+
+fn default_upload_max_length() -> i64 {
+    constants::UPLOAD_MAX_LENGTH
+}
+
+fn default_upload_ttl() -> i64 {
+    constants::UPLOAD_TTL
+}
+
+fn default_upload_path() -> String {
+    constants::UPLOAD_PATH.to_string()
+}
+
+fn default_trash_retention_days() -> i64 {
+    constants::TRASH_RETENTION_DAYS
+}
+
+fn default_ffmpeg_path() -> String {
+    constants::FFMPEG.to_string()
+}
+
+fn default_near_duplicate_distance() -> u32 {
+    constants::NEAR_DUPLICATE_DISTANCE
+}
+
+fn default_storage_path() -> String {
+    constants::IMG_PATH.to_string()
+}
+
+fn default_s3_region() -> String {
+    constants::S3_REGION.to_string()
+}
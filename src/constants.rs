@@ -4,16 +4,51 @@ use tera::Tera;
 use log::error;
 
 pub static SESSION_LENGTH: i64 = 60 * 60 * 24 * 7; // 1 week
+pub static SESSION_RENEWAL_WINDOW: i64 = 60 * 60 * 24; // 1 day
+pub static SESSION_PURGE_INTERVAL: u64 = 60 * 60; // 1 hour
 
 pub static IMG_PATH: &str = "./img";
+pub static UPLOAD_PATH: &str = "./uploads";
 pub static MAILTRAP_SEND: &str = "https://send.api.mailtrap.io/api/send";
 pub static JV_EMAIL: &str = "jv@lt20kmph.co.uk";
 pub static VERIFY_NEW_USER_SUBJECT: &str = "Verify new user";
 pub static VERIFY_NEW_USER_CATEGORY: &str = "verify_new_user";
 pub static WELCOME_SUBJECT: &str = "Welcome to JV";
 pub static WELCOME_CATEGORY: &str = "welcome";
+pub static VERIFICATION_TTL: i64 = 60 * 60 * 24 * 7; // 1 week
+pub static APPROVAL_RESEND_INTERVAL: i64 = 60 * 60; // 1 hour
+pub static SIGNUP_TOKEN: &str = "signup";
+pub static PASSWORD_RESET_TTL: i64 = 60 * 60; // 1 hour
+pub static PASSWORD_RESET_INTERVAL: i64 = 60 * 15; // 15 minutes
+pub static PASSWORD_RESET_IP_LIMIT: i64 = 5;
+pub static PASSWORD_RESET_TOKEN: &str = "password_reset";
+pub static PASSWORD_RESET_SUBJECT: &str = "Reset your JV password";
+pub static PASSWORD_RESET_CATEGORY: &str = "password_reset";
 pub static THUMBNAIL_SIZE: u32 = 300;
 pub static THUMBNAIL_EXT: &str = "thumbnail.jpg";
+pub static RENDITION_SIZES: [u32; 4] = [150, 300, 600, 1200];
+pub static AVIF_SPEED: u8 = 8;
+pub static AVIF_QUALITY: u8 = 70;
+pub static WEBP_QUALITY: f32 = 75.0;
+pub static CROP_QUALITY: u8 = 90;
+pub static JOB_WORKERS: usize = 2;
+pub static JOB_POLL_INTERVAL: u64 = 1; // 1 second
+pub static JOB_MAX_ATTEMPTS: i64 = 3;
+pub static UPLOAD_CHUNK_LIMIT: u64 = 8 * 1024 * 1024; // 8 MiB
+pub static UPLOAD_FORM_LIMIT: u64 = 8 * 1024 * 1024; // 8 MiB
+pub static UPLOAD_MAX_LENGTH: i64 = 512 * 1024 * 1024; // 512 MiB
+pub static UPLOAD_TTL: i64 = 60 * 60 * 24; // 1 day
+pub static UPLOAD_PURGE_INTERVAL: u64 = 60 * 60; // 1 hour
+pub static TRASH_RETENTION_DAYS: i64 = 30;
+pub static TRASH_PURGE_INTERVAL: u64 = 60 * 60; // 1 hour
+pub static IMMUTABLE_MAX_AGE: u64 = 60 * 60 * 24 * 365; // 1 year
+pub static MAX_RANGE_LENGTH: u64 = 4 * 1024 * 1024; // 4 MiB
+pub static FFMPEG: &str = "ffmpeg";
+pub static VIDEO_EXT: &str = "mp4";
+pub static VIDEO_MAX_WIDTH: u32 = 1280;
+pub static NEAR_DUPLICATE_DISTANCE: u32 = 8;
+pub static LEGACY_ORIGINAL_WINDOW: i64 = 60; // 1 minute
+pub static S3_REGION: &str = "us-east-1";
 
 lazy_static! {
     pub static ref COLORS: HashMap<&'static str, &'static str> = [
@@ -0,0 +1,343 @@
+use crate::db::queries::Db;
+use crate::errors;
+use log::info;
+use rocket_db_pools::{sqlx, sqlx::Executor, sqlx::Row};
+
+/// A numbered change to the schema, applied at most once per database
+pub struct Migration {
+    pub version: i64,
+    pub description: &'static str,
+    pub sql: &'static str,
+}
+
+/// Every migration, in the order they must be applied.
+///
+/// Never edit a migration that has been released; add a new one instead.
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "initial schema",
+        sql: include_str!("../../migrations/0001_initial_schema.sql"),
+    },
+    Migration {
+        version: 2,
+        description: "verification tokens",
+        sql: include_str!("../../migrations/0002_verification_tokens.sql"),
+    },
+    Migration {
+        version: 3,
+        description: "gallery sharing",
+        sql: include_str!("../../migrations/0003_gallery_sharing.sql"),
+    },
+    Migration {
+        version: 4,
+        description: "disabled users",
+        sql: include_str!("../../migrations/0004_disabled_users.sql"),
+    },
+    Migration {
+        version: 5,
+        description: "job queue",
+        sql: include_str!("../../migrations/0005_jobs.sql"),
+    },
+    Migration {
+        version: 6,
+        description: "image metadata",
+        sql: include_str!("../../migrations/0006_image_metadata.sql"),
+    },
+    Migration {
+        version: 7,
+        description: "image crops",
+        sql: include_str!("../../migrations/0007_image_crops.sql"),
+    },
+    Migration {
+        version: 8,
+        description: "chunked uploads",
+        sql: include_str!("../../migrations/0008_uploads.sql"),
+    },
+    Migration {
+        version: 9,
+        description: "video media type",
+        sql: include_str!("../../migrations/0009_media_type.sql"),
+    },
+    Migration {
+        version: 10,
+        description: "image hashes",
+        sql: include_str!("../../migrations/0010_image_hashes.sql"),
+    },
+    Migration {
+        version: 11,
+        description: "deletion times",
+        sql: include_str!("../../migrations/0011_deletion_times.sql"),
+    },
+    Migration {
+        version: 12,
+        description: "content addressed blobs",
+        sql: include_str!("../../migrations/0012_blobs.sql"),
+    },
+    Migration {
+        version: 13,
+        description: "trash",
+        sql: include_str!("../../migrations/0013_trash.sql"),
+    },
+    Migration {
+        version: 14,
+        description: "approval request times",
+        sql: include_str!("../../migrations/0014_approval_requests.sql"),
+    },
+    Migration {
+        version: 15,
+        description: "legacy originals",
+        sql: include_str!("../../migrations/0015_legacy_originals.sql"),
+    },
+    Migration {
+        version: 16,
+        description: "password reset requests",
+        sql: include_str!("../../migrations/0016_password_reset_requests.sql"),
+    },
+    Migration {
+        version: 17,
+        description: "first admin",
+        sql: include_str!("../../migrations/0017_first_admin.sql"),
+    },
+];
+
+async fn create_schema_version_table(db: &Db) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS schema_version (
+            version INTEGER PRIMARY KEY,
+            description TEXT NOT NULL,
+            time_applied TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+        )
+        "#,
+    )
+    .execute(&db.0)
+    .await?;
+    Ok(())
+}
+
+pub async fn current_version(db: &Db) -> Result<i64, sqlx::Error> {
+    let row = sqlx::query(
+        r#"
+        SELECT COALESCE(MAX(version), 0) FROM schema_version
+        "#,
+    )
+    .fetch_one(&db.0)
+    .await?;
+
+    Ok(row.get(0))
+}
+
+async fn apply(db: &Db, migration: &Migration) -> Result<(), sqlx::Error> {
+    // Each migration and its version row commit together, so a failed
+    // migration leaves the database as it was
+    let mut tx = db.0.begin().await?;
+
+    // A plain string runs every statement in the file, not just the first
+    tx.execute(migration.sql).await?;
+
+    sqlx::query(
+        r#"
+        INSERT INTO schema_version (version, description) VALUES (?1, ?2)
+        "#,
+    )
+    .bind(migration.version)
+    .bind(migration.description)
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+    Ok(())
+}
+
+/// Bring the database up to date by applying any migrations it hasn't seen yet
+pub async fn run(db: &Db) -> Result<(), errors::AppError> {
+    create_schema_version_table(db).await?;
+    let version = current_version(db).await?;
+    info!("Database schema is at version {}", version);
+
+    for migration in MIGRATIONS {
+        if migration.version <= version {
+            continue;
+        }
+        info!(
+            "Applying migration {}: {}",
+            migration.version, migration.description
+        );
+        apply(db, migration).await?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::sqlite::SqlitePoolOptions;
+
+    async fn empty_db() -> Db {
+        let pool = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        Db(pool)
+    }
+
+    async fn column_names(db: &Db, table: &str) -> Vec<String> {
+        sqlx::query("SELECT name FROM pragma_table_info(?1)")
+            .bind(table)
+            .fetch_all(&db.0)
+            .await
+            .unwrap()
+            .iter()
+            .map(|row| row.get(0))
+            .collect()
+    }
+
+    #[test]
+    fn test_migrations_are_numbered_in_order() {
+        for (i, migration) in MIGRATIONS.iter().enumerate() {
+            assert_eq!(migration.version, i as i64 + 1);
+        }
+    }
+
+    #[rocket::async_test]
+    async fn test_migrate_fresh_database() {
+        let db = empty_db().await;
+
+        run(&db).await.unwrap();
+
+        assert_eq!(
+            current_version(&db).await.unwrap(),
+            MIGRATIONS.last().unwrap().version
+        );
+        assert!(column_names(&db, "users")
+            .await
+            .contains(&"is_disabled".to_string()));
+        assert!(!column_names(&db, "gallery_editors").await.is_empty());
+    }
+
+    #[rocket::async_test]
+    async fn test_migrations_run_once() {
+        let db = empty_db().await;
+
+        run(&db).await.unwrap();
+        run(&db).await.unwrap();
+
+        let applied: i64 = sqlx::query("SELECT COUNT(*) FROM schema_version")
+            .fetch_one(&db.0)
+            .await
+            .unwrap()
+            .get(0);
+        assert_eq!(applied, MIGRATIONS.len() as i64);
+    }
+
+    #[rocket::async_test]
+    async fn test_migrate_legacy_database() {
+        let db = empty_db().await;
+        // A database made by the old CREATE TABLE IF NOT EXISTS setup, with no
+        // schema_version table and some data we must keep
+        sqlx::raw_sql(MIGRATIONS[0].sql)
+            .execute(&db.0)
+            .await
+            .unwrap();
+        sqlx::query(
+            r#"
+            INSERT INTO users (email, password, salt, verification_uuid, is_verified, role)
+            VALUES ('john@example.com', '', '', 'uuid', TRUE, 'writer')
+            "#,
+        )
+        .execute(&db.0)
+        .await
+        .unwrap();
+        // Originals were saved to a file of their own, but stored with the path
+        // of the image cut from them
+        sqlx::raw_sql(
+            r#"
+            INSERT INTO galleries (user_id, name, gallery_text) VALUES (1, 'gallery', '');
+            INSERT INTO original_images (user_id, gallery_id, filename, path)
+            VALUES (1, 1, 'photo.jpg', './img/image');
+            INSERT INTO modified_images (user_id, original_image_id, path, caption)
+            VALUES (1, 1, './img/image', '');
+            "#,
+        )
+        .execute(&db.0)
+        .await
+        .unwrap();
+
+        run(&db).await.unwrap();
+
+        assert_eq!(
+            current_version(&db).await.unwrap(),
+            MIGRATIONS.last().unwrap().version
+        );
+        let row =
+            sqlx::query("SELECT role, is_disabled FROM users WHERE email = 'john@example.com'")
+                .fetch_one(&db.0)
+                .await
+                .unwrap();
+        let role: String = row.get(0);
+        let is_disabled: bool = row.get(1);
+        // The only writer becomes the admin, so someone can still manage it all
+        assert_eq!(role, "admin");
+        assert!(!is_disabled);
+        let is_legacy: bool = sqlx::query("SELECT is_legacy FROM original_images")
+            .fetch_one(&db.0)
+            .await
+            .unwrap()
+            .get(0);
+        assert!(is_legacy);
+    }
+
+    #[rocket::async_test]
+    async fn test_first_writer_becomes_admin() {
+        let db = empty_db().await;
+        create_schema_version_table(&db).await.unwrap();
+        for migration in &MIGRATIONS[..16] {
+            apply(&db, migration).await.unwrap();
+        }
+        sqlx::raw_sql(
+            r#"
+            INSERT INTO users (email, password, salt, verification_uuid, is_verified, role)
+            VALUES ('reader@example.com', '', '', 'a', TRUE, 'reader'),
+                   ('pending@example.com', '', '', 'b', FALSE, 'writer'),
+                   ('first@example.com', '', '', 'c', TRUE, 'writer'),
+                   ('second@example.com', '', '', 'd', TRUE, 'writer');
+            "#,
+        )
+        .execute(&db.0)
+        .await
+        .unwrap();
+
+        run(&db).await.unwrap();
+
+        let admins: Vec<String> = sqlx::query("SELECT email FROM users WHERE role = 'admin'")
+            .fetch_all(&db.0)
+            .await
+            .unwrap()
+            .iter()
+            .map(|row| row.get(0))
+            .collect();
+        assert_eq!(admins, vec!["first@example.com"]);
+    }
+
+    #[rocket::async_test]
+    async fn test_failed_migration_is_not_recorded() {
+        let db = empty_db().await;
+        run(&db).await.unwrap();
+
+        let broken = Migration {
+            version: 1000,
+            description: "broken",
+            sql: "CREATE TABLE broken (id INTEGER); SELECT * FROM no_such_table;",
+        };
+
+        assert!(apply(&db, &broken).await.is_err());
+        assert_eq!(
+            current_version(&db).await.unwrap(),
+            MIGRATIONS.last().unwrap().version
+        );
+        assert!(column_names(&db, "broken").await.is_empty());
+    }
+}
@@ -1,129 +1,65 @@
 use crate::auth::pw_utils;
+use crate::blobs;
 use crate::errors;
 use rocket::futures::TryStreamExt;
 
 use crate::constants;
+use crate::hashing;
 use crate::models::models;
 use chrono;
 use log::info;
 use rocket_db_pools::Connection;
-use rocket_db_pools::{sqlx, sqlx::Row, Database};
+use rocket_db_pools::{sqlx, sqlx::sqlite::SqliteRow, sqlx::Row, sqlx::SqliteConnection, Database};
 use uuid::Uuid;
 
-#[derive(Database)]
+#[derive(Database, Clone)]
 #[database("db")]
-pub struct Db(sqlx::SqlitePool);
+pub struct Db(pub(crate) sqlx::SqlitePool);
 
-async fn create_session_table(db: &Db) -> Result<(), sqlx::Error> {
-    sqlx::query(
-        r#"
-        CREATE TABLE IF NOT EXISTS sessions (
-            id INTEGER PRIMARY KEY,
-            session_token TEXT NOT NULL,
-            user_id INTEGER NOT NULL,
-            expires_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
-            FOREIGN KEY (user_id) REFERENCES users(id)
-        )
-        "#,
-    )
-    .execute(&db.0)
-    .await?;
-    info!("Sessions table created");
-    Ok(())
-}
-
-async fn create_user_table(db: &Db) -> Result<(), sqlx::Error> {
-    sqlx::query(
-        r#"
-        CREATE TABLE IF NOT EXISTS users (
-            id INTEGER PRIMARY KEY,
-            email TEXT NOT NULL,
-            role TEXT DEFAULT 'reader',
-            password TEXT NOT NULL,
-            time_created TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
-            is_verified BOOLEAN DEFAULT FALSE,
-            verification_uuid TEXT NOT NULL,
-            salt TEXT NOT NULL
-        )
-        "#,
-    )
-    .execute(&db.0)
-    .await?;
-    info!("Users table created");
-    Ok(())
-}
-
-async fn create_galleries_table(db: &Db) -> Result<(), sqlx::Error> {
-    sqlx::query(
-        r#"
-        CREATE TABLE IF NOT EXISTS galleries (
-            id INTEGER PRIMARY KEY,
-            user_id INTEGER NOT NULL,
-            name TEXT NOT NULL,
-            gallery_text TEXT NOT NULL,
-            status TEXT DEFAULT 'public',
-            time_created TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
-            FOREIGN KEY (user_id) REFERENCES users(id)
-        )
-        "#,
+// Condition on `galleries` that is true when the gallery is visible to the user
+// whose id is bound to ?1 and whose role is bound to ?2.
+// See `models::Visibility` for what each status means.
+const GALLERY_VISIBLE_TO_USER: &str = r#"
+    galleries.status != 'deleted' AND (
+        ?2 = 'admin'
+        OR galleries.user_id = ?1
+        OR galleries.status = 'public'
+        OR (galleries.status = 'family' AND ?2 != 'reader')
+        OR (galleries.status = 'shared' AND EXISTS (
+            SELECT 1 FROM gallery_members
+            WHERE gallery_members.gallery_id = galleries.id AND gallery_members.user_id = ?1
+        ))
     )
-    .execute(&db.0)
-    .await?;
-    info!("Galleries table created");
-    Ok(())
-}
+"#;
 
-async fn create_modified_images_table(db: &Db) -> Result<(), sqlx::Error> {
-    sqlx::query(
-        r#"
-        CREATE TABLE IF NOT EXISTS modified_images (
-            id INTEGER PRIMARY KEY,
-            user_id INTEGER NOT NULL,
-            original_image_id INTEGER NOT NULL,
-            path TEXT NOT NULL,
-            caption TEXT NOT NULL,
-            time_created TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
-            time_modified TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
-            status TEXT DEFAULT 'public',
-            FOREIGN KEY (original_image_id) REFERENCES original_images(id)
-        )
-        "#,
-    )
-    .execute(&db.0)
-    .await?;
-    info!("Modified images table created");
-    Ok(())
+// Condition on `galleries` that is true when the user bound to ?1 with the role
+// bound to ?2 may change the gallery and its images, were it not deleted. Admins
+// can change anything, writers only galleries they created or were made an
+// editor of. A macro so both conditions below can be built from it.
+macro_rules! gallery_edit_rights {
+    () => {
+        r#"(
+        ?2 = 'admin'
+        OR (?2 = 'writer' AND (
+            galleries.user_id = ?1
+            OR EXISTS (
+                SELECT 1 FROM gallery_editors
+                WHERE gallery_editors.gallery_id = galleries.id AND gallery_editors.user_id = ?1
+            )
+        ))
+    )"#
+    };
 }
 
-async fn create_original_images_table(db: &Db) -> Result<(), sqlx::Error> {
-    sqlx::query(
-        r#"
-        CREATE TABLE IF NOT EXISTS original_images (
-            id INTEGER PRIMARY KEY,
-            user_id INTEGER NOT NULL,
-            gallery_id INTEGER NOT NULL,
-            filename TEXT NOT NULL,
-            path TEXT NOT NULL,
-            time_created TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
-            FOREIGN KEY (gallery_id) REFERENCES galleries(id)
-        )
-        "#,
-    )
-    .execute(&db.0)
-    .await?;
-    info!("Original images table created");
-    Ok(())
-}
+// Condition on `galleries` that is true when the user bound to ?1 with the role
+// bound to ?2 may change the gallery and its images
+const GALLERY_EDITABLE_BY_USER: &str =
+    concat!("galleries.status != 'deleted' AND ", gallery_edit_rights!());
 
-pub async fn create_tables(db: &Db) -> Result<(), errors::AppError> {
-    info!("Creating tables");
-    create_user_table(db).await?;
-    create_session_table(db).await?;
-    create_galleries_table(db).await?;
-    create_original_images_table(db).await?;
-    create_modified_images_table(db).await?;
-    Ok(())
-}
+// Condition on `galleries` that is true when the user bound to ?1 with the role
+// bound to ?2 could change the gallery if it were restored, so may see it in the
+// trash and restore it
+const GALLERY_EDITABLE_IF_RESTORED: &str = gallery_edit_rights!();
 
 async fn get_user_id_by_email(db: &Db, email: &str) -> Result<i64, sqlx::Error> {
     let row = sqlx::query(
@@ -138,10 +74,14 @@ async fn get_user_id_by_email(db: &Db, email: &str) -> Result<i64, sqlx::Error>
     Ok(user_id)
 }
 
-pub async fn create_user_session(db: &Db, email: &str) -> Result<String, errors::AppError> {
+pub async fn create_user_session(
+    db: &Db,
+    email: &str,
+    session_length: i64,
+) -> Result<String, errors::AppError> {
     let session_token = Uuid::new_v4();
     let user_id = get_user_id_by_email(db, email).await?;
-    let expires_at = chrono::Utc::now().timestamp() + constants::SESSION_LENGTH;
+    let expires_at = chrono::Utc::now().timestamp() + session_length;
 
     sqlx::query(
         r#"
@@ -157,11 +97,12 @@ pub async fn create_user_session(db: &Db, email: &str) -> Result<String, errors:
     Ok(session_token.to_string())
 }
 
+// New users can't log in until an admin approves them from the admin console
 pub async fn insert_user(
     mut conn: Connection<Db>,
     email: &str,
     password: &str,
-) -> Result<String, errors::AppError> {
+) -> Result<(), errors::AppError> {
     let salted_password = pw_utils::hash_and_salt_password(password);
 
     let salted_password = match salted_password {
@@ -171,21 +112,21 @@ pub async fn insert_user(
         }
     };
 
-    let verification_uuid = Uuid::new_v4().to_string();
-
     sqlx::query(
         r#"
-        INSERT INTO users (email, password, salt, verification_uuid) VALUES (?1, ?2, ?3, ?4)
+        INSERT INTO users (email, password, salt, verification_uuid, approval_requested_at)
+        VALUES (?1, ?2, ?3, ?4, ?5)
         "#,
     )
     .bind(email)
     .bind(salted_password.password_hash)
     .bind(salted_password.salt.to_string())
-    .bind(&verification_uuid)
+    .bind(Uuid::new_v4().to_string())
+    .bind(chrono::Utc::now().timestamp())
     .execute(&mut **conn)
     .await?;
 
-    Ok(verification_uuid)
+    Ok(())
 }
 
 pub async fn verify_password(
@@ -217,6 +158,305 @@ pub async fn verify_password(
     )?)
 }
 
+// Record that a password reset link is being sent to a verified, enabled user
+// and return their id. Returns None for anyone else, or if they were sent one
+// less than `interval` seconds ago
+pub async fn claim_password_reset(
+    db: &Db,
+    email: &str,
+    interval: i64,
+) -> Result<Option<i64>, sqlx::Error> {
+    let now = chrono::Utc::now().timestamp();
+
+    let row = sqlx::query(
+        r#"
+        UPDATE users SET password_reset_requested_at = ?2
+        WHERE email = ?1 AND is_verified = TRUE AND is_disabled = FALSE
+          AND (password_reset_requested_at IS NULL OR password_reset_requested_at <= ?3)
+        RETURNING id
+        "#,
+    )
+    .bind(email)
+    .bind(now)
+    .bind(now - interval)
+    .fetch_optional(&db.0)
+    .await?;
+
+    Ok(row.map(|row| row.get(0)))
+}
+
+// Record a password reset request from an address, returns false if it has
+// already made `limit` requests in the last `interval` seconds
+pub async fn claim_password_reset_ip(
+    db: &Db,
+    ip: &str,
+    interval: i64,
+    limit: i64,
+) -> Result<bool, sqlx::Error> {
+    let now = chrono::Utc::now().timestamp();
+    let mut tx = db.0.begin().await?;
+
+    // Requests older than the interval don't count for anything any more
+    sqlx::query(
+        r#"
+        DELETE FROM password_reset_requests WHERE requested_at <= ?1
+        "#,
+    )
+    .bind(now - interval)
+    .execute(&mut *tx)
+    .await?;
+
+    let row = sqlx::query(
+        r#"
+        SELECT COUNT(*) FROM password_reset_requests WHERE ip = ?1
+        "#,
+    )
+    .bind(ip)
+    .fetch_one(&mut *tx)
+    .await?;
+
+    let requests: i64 = row.get(0);
+    if requests >= limit {
+        tx.commit().await?;
+        return Ok(false);
+    }
+
+    sqlx::query(
+        r#"
+        INSERT INTO password_reset_requests (ip, requested_at) VALUES (?1, ?2)
+        "#,
+    )
+    .bind(ip)
+    .bind(now)
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+
+    Ok(true)
+}
+
+// Set a new password and log the user out everywhere
+pub async fn reset_password(db: &Db, user_id: i64, password: &str) -> Result<(), errors::AppError> {
+    let salted_password = pw_utils::hash_and_salt_password(password)?;
+
+    let mut tx = db.0.begin().await?;
+
+    sqlx::query(
+        r#"
+        UPDATE users SET password = ?1, salt = ?2 WHERE id = ?3
+        "#,
+    )
+    .bind(salted_password.password_hash)
+    .bind(salted_password.salt.to_string())
+    .bind(user_id)
+    .execute(&mut *tx)
+    .await?;
+
+    sqlx::query(
+        r#"
+        DELETE FROM sessions WHERE user_id = ?1
+        "#,
+    )
+    .bind(user_id)
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+
+    Ok(())
+}
+
+pub async fn is_user_verified(db: &Db, email: &str) -> Result<bool, sqlx::Error> {
+    let row = sqlx::query(
+        r#"
+        SELECT is_verified FROM users WHERE email = ?1
+        "#,
+    )
+    .bind(email)
+    .fetch_one(&db.0)
+    .await?;
+
+    let is_verified: bool = row.get(0);
+
+    Ok(is_verified)
+}
+
+// Whether the email belongs to a user still waiting for an admin to approve them
+pub async fn is_user_pending(db: &Db, email: &str) -> Result<bool, sqlx::Error> {
+    let row = sqlx::query(
+        r#"
+        SELECT id FROM users WHERE email = ?1 AND is_verified = FALSE AND is_disabled = FALSE
+        "#,
+    )
+    .bind(email)
+    .fetch_optional(&db.0)
+    .await?;
+
+    Ok(row.is_some())
+}
+
+// Record that the admin is being asked to approve the user again, returns false
+// if they were asked less than `interval` seconds ago
+pub async fn claim_approval_resend(
+    db: &Db,
+    email: &str,
+    interval: i64,
+) -> Result<bool, sqlx::Error> {
+    let now = chrono::Utc::now().timestamp();
+
+    let result = sqlx::query(
+        r#"
+        UPDATE users SET approval_requested_at = ?2
+        WHERE email = ?1 AND (approval_requested_at IS NULL OR approval_requested_at <= ?3)
+        "#,
+    )
+    .bind(email)
+    .bind(now)
+    .bind(now - interval)
+    .execute(&db.0)
+    .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+pub async fn is_user_disabled(db: &Db, email: &str) -> Result<bool, sqlx::Error> {
+    let row = sqlx::query(
+        r#"
+        SELECT is_disabled FROM users WHERE email = ?1
+        "#,
+    )
+    .bind(email)
+    .fetch_optional(&db.0)
+    .await?;
+
+    Ok(row.is_some_and(|row| row.get(0)))
+}
+
+// Create a single-use token for a user, replacing any unused tokens with the same purpose
+pub async fn create_verification_token(
+    db: &Db,
+    user_id: i64,
+    purpose: &str,
+) -> Result<String, sqlx::Error> {
+    let token = Uuid::new_v4().to_string();
+
+    sqlx::query(
+        r#"
+        DELETE FROM verification_tokens
+        WHERE user_id = ?1 AND purpose = ?2 AND time_consumed IS NULL
+        "#,
+    )
+    .bind(user_id)
+    .bind(purpose)
+    .execute(&db.0)
+    .await?;
+
+    sqlx::query(
+        r#"
+        INSERT INTO verification_tokens (user_id, token, purpose) VALUES (?1, ?2, ?3)
+        "#,
+    )
+    .bind(user_id)
+    .bind(&token)
+    .bind(purpose)
+    .execute(&db.0)
+    .await?;
+
+    Ok(token)
+}
+
+// Signup links sent before verification tokens existed carry the user's
+// verification uuid, they have all expired since
+async fn is_legacy_verification_uuid(db: &Db, token: &str) -> Result<bool, sqlx::Error> {
+    let row = sqlx::query(
+        r#"
+        SELECT 1 FROM users WHERE verification_uuid = ?1
+        "#,
+    )
+    .bind(token)
+    .fetch_optional(&db.0)
+    .await?;
+
+    Ok(row.is_some())
+}
+
+// Look up a token without using it up
+pub async fn check_verification_token(
+    db: &Db,
+    token: &str,
+    purpose: &str,
+    ttl: i64,
+) -> Result<models::TokenStatus, sqlx::Error> {
+    let row = sqlx::query(
+        r#"
+        SELECT
+            user_id,
+            time_consumed IS NOT NULL,
+            time_created <= datetime('now', ?3)
+        FROM verification_tokens
+        WHERE token = ?1 AND purpose = ?2
+        "#,
+    )
+    .bind(token)
+    .bind(purpose)
+    .bind(format!("-{} seconds", ttl))
+    .fetch_optional(&db.0)
+    .await?;
+
+    let row = match row {
+        Some(row) => row,
+        None if purpose == constants::SIGNUP_TOKEN
+            && is_legacy_verification_uuid(db, token).await? =>
+        {
+            return Ok(models::TokenStatus::Expired)
+        }
+        None => return Ok(models::TokenStatus::NotFound),
+    };
+
+    let user_id: i64 = row.get(0);
+    let is_consumed: bool = row.get(1);
+    let is_expired: bool = row.get(2);
+
+    if is_consumed {
+        Ok(models::TokenStatus::Used)
+    } else if is_expired {
+        Ok(models::TokenStatus::Expired)
+    } else {
+        Ok(models::TokenStatus::Valid(user_id))
+    }
+}
+
+// Mark a token as used, so that it can only ever be redeemed once
+pub async fn consume_verification_token(
+    db: &Db,
+    token: &str,
+    purpose: &str,
+    ttl: i64,
+) -> Result<models::TokenStatus, sqlx::Error> {
+    let row = sqlx::query(
+        r#"
+        UPDATE verification_tokens SET time_consumed = CURRENT_TIMESTAMP
+        WHERE token = ?1
+          AND purpose = ?2
+          AND time_consumed IS NULL
+          AND time_created > datetime('now', ?3)
+        RETURNING user_id
+        "#,
+    )
+    .bind(token)
+    .bind(purpose)
+    .bind(format!("-{} seconds", ttl))
+    .fetch_optional(&db.0)
+    .await?;
+
+    match row {
+        Some(row) => Ok(models::TokenStatus::Valid(row.get(0))),
+        // Work out why the token couldn't be used
+        None => check_verification_token(db, token, purpose, ttl).await,
+    }
+}
+
 pub async fn get_user_from_session_token(
     session_token: &str,
     pool: &Db,
@@ -230,9 +470,13 @@ pub async fn get_user_from_session_token(
         FROM sessions
         INNER JOIN users ON sessions.user_id = users.id
         WHERE session_token = ?1
+            AND sessions.expires_at > ?2
+            AND users.is_verified = TRUE
+            AND users.is_disabled = FALSE
         "#,
     )
-    .bind(&session_token)
+    .bind(session_token)
+    .bind(chrono::Utc::now().timestamp())
     .fetch_one(&pool.0)
     .await?;
 
@@ -240,11 +484,7 @@ pub async fn get_user_from_session_token(
 
     let user_id: i64 = row.get(0);
     let email: String = row.get(1);
-    let role: models::Role = match row.get(2) {
-        "reader" => models::Role::Reader,
-        "writer" => models::Role::Writer,
-        _ => models::Role::Reader,
-    };
+    let role = models::Role::from_db(row.get(2));
 
     sqlx::Result::Ok(models::User {
         id: user_id,
@@ -253,6 +493,44 @@ pub async fn get_user_from_session_token(
     })
 }
 
+// Push the expiry of a session forward if it is due to expire within `renewal_window`
+pub async fn renew_user_session(
+    db: &Db,
+    session_token: &str,
+    renewal_window: i64,
+    session_length: i64,
+) -> Result<bool, sqlx::Error> {
+    let now = chrono::Utc::now().timestamp();
+
+    let result = sqlx::query(
+        r#"
+        UPDATE sessions SET expires_at = ?2
+        WHERE session_token = ?1 AND expires_at > ?3 AND expires_at <= ?4
+        "#,
+    )
+    .bind(session_token)
+    .bind(now + session_length)
+    .bind(now)
+    .bind(now + renewal_window)
+    .execute(&db.0)
+    .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+pub async fn delete_expired_sessions(db: &Db) -> Result<u64, sqlx::Error> {
+    let result = sqlx::query(
+        r#"
+        DELETE FROM sessions WHERE expires_at <= ?1
+        "#,
+    )
+    .bind(chrono::Utc::now().timestamp())
+    .execute(&db.0)
+    .await?;
+
+    Ok(result.rows_affected())
+}
+
 pub async fn create_gallery(db: &Db, user_id: i64, name: &str) -> Result<i64, sqlx::Error> {
     let row = sqlx::query(
         r#"
@@ -281,11 +559,12 @@ impl models::ImgPath {
 }
 
 async fn insert_original_image(
-    db: &Db,
+    conn: &mut SqliteConnection,
     user_id: i64,
     gallery_id: i64,
     img_path: &models::ImgPath,
     filename: &str,
+    content_hash: Option<&str>,
 ) -> Result<i64, sqlx::Error> {
     let row = sqlx::query(
         r#"
@@ -293,16 +572,18 @@ async fn insert_original_image(
             user_id,
             gallery_id,
             filename,
-            path
-        ) VALUES (?1, ?2, ?3, ?4)
+            path,
+            content_hash
+        ) VALUES (?1, ?2, ?3, ?4, ?5)
         RETURNING id
         "#,
     )
     .bind(user_id)
     .bind(gallery_id)
     .bind(filename)
-    .bind(&img_path.path)
-    .execute(&db.0)
+    .bind(&img_path.original_path)
+    .bind(content_hash)
+    .execute(conn)
     .await?;
 
     let original_image_id = row.last_insert_rowid();
@@ -311,11 +592,13 @@ async fn insert_original_image(
 }
 
 async fn insert_modified_image(
-    db: &Db,
+    conn: &mut SqliteConnection,
     user_id: i64,
     original_image_id: i64,
     img_path: &models::ImgPath,
     caption: &str,
+    crop: Option<&models::Crop>,
+    media_type: models::MediaType,
 ) -> Result<i64, sqlx::Error> {
     let row = sqlx::query(
         r#"
@@ -324,8 +607,14 @@ async fn insert_modified_image(
             original_image_id,
             path,
             caption,
-            status
-        ) VALUES (?1, ?2, ?3, ?4, ?5)
+            status,
+            crop_x,
+            crop_y,
+            crop_width,
+            crop_height,
+            rotation,
+            media_type
+        ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)
         RETURNING id
         "#,
     )
@@ -333,8 +622,14 @@ async fn insert_modified_image(
     .bind(original_image_id)
     .bind(&img_path.path)
     .bind(caption)
-    .bind("public")
-    .execute(&db.0)
+    .bind(models::ImageStatus::Processing.as_str())
+    .bind(crop.map(|crop| crop.x))
+    .bind(crop.map(|crop| crop.y))
+    .bind(crop.map(|crop| crop.width))
+    .bind(crop.map(|crop| crop.height))
+    .bind(crop.map(|crop| crop.rotation.degrees()))
+    .bind(media_type.as_str())
+    .execute(conn)
     .await?;
 
     let modified_image_id = row.last_insert_rowid();
@@ -342,257 +637,450 @@ async fn insert_modified_image(
     Ok(modified_image_id)
 }
 
-// Create new image, return image_path
-pub async fn create_image(
+// Create an image or video from an upload with this content hash, its original
+// stored under the hash. It stays in processing until a worker has made what is
+// shown from the original. Returns None, creating nothing, if an image still in
+// the gallery already has the hash.
+#[allow(clippy::too_many_arguments)]
+pub async fn create_media(
     db: &Db,
     user_id: i64,
     gallery_id: i64,
     original_filename: &str,
     caption: &str,
-) -> Result<models::Image, sqlx::Error> {
-    let img_path = models::ImgPath::new();
+    crop: Option<&models::Crop>,
+    media_type: models::MediaType,
+    content_hash: &str,
+) -> Result<Option<models::Image>, sqlx::Error> {
+    // Writing first takes the database's write lock, so the same file sent twice
+    // at once can't pass the check below twice
+    let mut tx = db.0.begin().await?;
+    let image = insert_media(
+        &mut tx,
+        user_id,
+        gallery_id,
+        original_filename,
+        caption,
+        crop,
+        media_type,
+        Some(content_hash),
+    )
+    .await?;
 
-    let original_image_id =
-        insert_original_image(db, user_id, gallery_id, &img_path, original_filename).await?;
+    let duplicate = sqlx::query(DUPLICATE_QUERY)
+        .bind(gallery_id)
+        .bind(content_hash)
+        .bind(image.id)
+        .fetch_optional(&mut *tx)
+        .await?;
+    if duplicate.is_some() {
+        tx.rollback().await?;
+        return Ok(None);
+    }
+    tx.commit().await?;
 
-    let modified_image_id =
-        insert_modified_image(db, user_id, original_image_id, &img_path, caption).await?;
+    Ok(Some(image))
+}
 
-    Ok(models::Image {
-        id: modified_image_id,
-        path: img_path.path,
-        original_path: Some(img_path.original_path),
-        caption: caption.to_string(),
-    })
+// Forget an image made by `create_media` whose original couldn't be stored,
+// before any jobs were queued for it
+pub async fn forget_image(db: &Db, image_id: i64) -> Result<(), sqlx::Error> {
+    let mut tx = db.0.begin().await?;
+
+    let row = sqlx::query(
+        r#"
+        DELETE FROM modified_images WHERE id = ?1 RETURNING original_image_id
+        "#,
+    )
+    .bind(image_id)
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    if let Some(row) = row {
+        let original_image_id: i64 = row.get(0);
+        sqlx::query(
+            r#"
+            DELETE FROM original_images WHERE id = ?1
+            "#,
+        )
+        .bind(original_image_id)
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    tx.commit().await?;
+
+    Ok(())
 }
 
-pub async fn get_gallery_images(db: &Db, gallery_id: i64) -> Vec<models::Image> {
-    let mut images = vec![];
+// Insert an original and the image shown from it. A video's path ends in the
+// extension of the transcoded video so it is served with the right content
+// type, its renditions are of the poster frame.
+#[allow(clippy::too_many_arguments)]
+async fn insert_media(
+    conn: &mut SqliteConnection,
+    user_id: i64,
+    gallery_id: i64,
+    original_filename: &str,
+    caption: &str,
+    crop: Option<&models::Crop>,
+    media_type: models::MediaType,
+    content_hash: Option<&str>,
+) -> Result<models::Image, sqlx::Error> {
+    let img_path = models::ImgPath::new();
+    let img_path = models::ImgPath {
+        path: match media_type {
+            models::MediaType::Image => img_path.path,
+            models::MediaType::Video => format!("{}.{}", img_path.path, constants::VIDEO_EXT),
+        },
+        original_path: match content_hash {
+            Some(content_hash) => blobs::blob_path(content_hash, None),
+            None => img_path.original_path,
+        },
+    };
 
-    let mut rows = sqlx::query(
+    let original_image_id = insert_original_image(
+        conn,
+        user_id,
+        gallery_id,
+        &img_path,
+        original_filename,
+        content_hash,
+    )
+    .await?;
+
+    let modified_image_id = insert_modified_image(
+        conn,
+        user_id,
+        original_image_id,
+        &img_path,
+        caption,
+        crop,
+        media_type,
+    )
+    .await?;
+
+    Ok(models::Image {
+        id: modified_image_id,
+        path: img_path.path,
+        original_path: Some(img_path.original_path),
+        caption: caption.to_string(),
+        status: models::ImageStatus::Processing,
+        media_type,
+        near_duplicate: false,
+    })
+}
+
+// The original the current version of an image was cut from, and the crop
+// used, as long as the image is in the gallery
+pub async fn get_image_source(
+    db: &Db,
+    gallery_id: i64,
+    image_id: i64,
+) -> Result<Option<models::ImageSource>, sqlx::Error> {
+    let row = sqlx::query(
         r#"
         SELECT
-            modified_images.id,
-            modified_images.path,
-            modified_images.caption
-        FROM original_images
-        JOIN modified_images ON original_images.id = modified_images.original_image_id
-        WHERE original_images.gallery_id = ?1
+          original_images.path,
+          modified_images.crop_x,
+          modified_images.crop_y,
+          modified_images.crop_width,
+          modified_images.crop_height,
+          modified_images.rotation,
+          original_images.is_legacy
+        FROM modified_images
+        JOIN original_images ON original_images.id = modified_images.original_image_id
+        WHERE modified_images.id = ?1
+          AND original_images.gallery_id = ?2
+          AND modified_images.status NOT IN ('deleted', 'superseded')
+          AND modified_images.media_type = 'image'
         "#,
     )
+    .bind(image_id)
     .bind(gallery_id)
-    .fetch(&db.0);
+    .fetch_optional(&db.0)
+    .await?;
 
-    while let Ok(row) = rows.try_next().await {
-        let row = match row {
-            Some(row) => row,
-            None => break,
+    Ok(row.map(|row| {
+        let crop = match (row.get(1), row.get(2), row.get(3), row.get(4)) {
+            (Some(x), Some(y), Some(width), Some(height)) => Some(models::Crop {
+                x,
+                y,
+                width,
+                height,
+                rotation: models::Rotation::from_degrees(row.get::<Option<i64>, _>(5).unwrap_or(0))
+                    .unwrap_or(models::Rotation::None),
+            }),
+            _ => None,
         };
-        let id: i64 = row.get(0);
-        let path: String = row.get(1);
-        let caption: String = row.get(2);
-        images.push(models::Image { id, path, original_path: None, caption });
-    }
+        models::ImageSource {
+            original_path: row.get(0),
+            is_legacy: row.get(6),
+            crop,
+        }
+    }))
+}
+
+// Every version of an image that hasn't been deleted, newest first
+pub async fn get_image_versions(
+    db: &Db,
+    image_id: i64,
+) -> Result<Vec<models::ImageVersion>, sqlx::Error> {
+    let rows = sqlx::query(
+        r#"
+        SELECT versions.id, versions.path, versions.time_created
+        FROM modified_images AS image
+        JOIN modified_images AS versions ON versions.original_image_id = image.original_image_id
+        WHERE image.id = ?1 AND versions.status != 'deleted'
+        ORDER BY versions.id DESC
+        "#,
+    )
+    .bind(image_id)
+    .fetch_all(&db.0)
+    .await?;
 
-    images
+    Ok(rows
+        .iter()
+        .map(|row| models::ImageVersion {
+            id: row.get(0),
+            path: row.get(1),
+            is_current: row.get::<i64, _>(0) == image_id,
+            time_created: row.get(2),
+        })
+        .collect())
 }
 
-pub async fn verify_user(db: &Db, verification_uuid: &str) -> Result<String, sqlx::Error> {
+// Make a new version of an image, cut from the same original with a new crop.
+// The old version is kept as history but no longer shown. Returns None if the
+// image is gone or has already been replaced.
+pub async fn edit_image(
+    db: &Db,
+    user_id: i64,
+    image_id: i64,
+    crop: &models::Crop,
+) -> Result<Option<models::Image>, sqlx::Error> {
+    let mut tx = db.0.begin().await?;
+
     let row = sqlx::query(
         r#"
-        UPDATE users SET is_verified = TRUE WHERE verification_uuid = ?1 RETURNING email
+        UPDATE modified_images SET status = 'superseded', time_modified = CURRENT_TIMESTAMP
+        WHERE id = ?1 AND status NOT IN ('deleted', 'superseded')
+        RETURNING original_image_id, caption
         "#,
     )
-    .bind(verification_uuid)
-    .fetch_one(&db.0)
+    .bind(image_id)
+    .fetch_optional(&mut *tx)
     .await?;
 
-    let email: String = row.get(0);
-
-    Ok(email)
-}
+    let (original_image_id, caption): (i64, String) = match row {
+        Some(row) => (row.get(0), row.get(1)),
+        None => return Ok(None),
+    };
 
-pub async fn get_galleries(db: &Db) -> Result<Vec<models::GalleryTile>, sqlx::Error> {
-    let mut galleries = vec![];
+    let path = models::ImgPath::new().path;
 
-    let mut rows = sqlx::query(
+    let row = sqlx::query(
         r#"
-        SELECT 
-          galleries.id AS id,
-          galleries.name AS name,
-          galleries.time_created AS time_created,
-          count(modified_images.id) AS n_images,
-          max(modified_images.path) AS last_image,
-          users.email AS created_by
-        FROM galleries 
-        LEFT JOIN original_images ON galleries.id = original_images.gallery_id
-        LEFT JOIN modified_images ON original_images.id = modified_images.original_image_id AND modified_images.status = 'public'
-        LEFT JOIN users ON galleries.user_id = users.id
-        WHERE galleries.status != 'deleted'
-        GROUP BY galleries.id, galleries.name, galleries.time_created
-        ORDER BY galleries.time_created DESC
+        INSERT INTO modified_images (
+            user_id,
+            original_image_id,
+            path,
+            caption,
+            status,
+            crop_x,
+            crop_y,
+            crop_width,
+            crop_height,
+            rotation
+        ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)
+        RETURNING id, (SELECT path FROM original_images WHERE id = ?2)
         "#,
     )
-    .fetch(&db.0);
+    .bind(user_id)
+    .bind(original_image_id)
+    .bind(&path)
+    .bind(&caption)
+    .bind(models::ImageStatus::Processing.as_str())
+    .bind(crop.x)
+    .bind(crop.y)
+    .bind(crop.width)
+    .bind(crop.height)
+    .bind(crop.rotation.degrees())
+    .fetch_one(&mut *tx)
+    .await?;
 
-    while let Ok(row) = rows.try_next().await {
-        let row = match row {
-            Some(row) => row,
-            None => break,
-        };
-        let id: i64 = row.get(0);
-        let name: String = row.get(1);
-        let time_created: String = row.get(2);
-        let mut n_images: i64 = row.get(3);
-        let last_image: Option<String> = row.get(4);
-        let created_by: String = row.get(5);
-        if last_image.is_none() {
-            n_images = 0;
-        }
-        galleries.push(models::GalleryTile::new(
-            id,
-            name,
-            last_image,
-            n_images,
-            time_created,
-            created_by,
-        ));
-    }
+    tx.commit().await?;
 
-    Ok(galleries)
+    Ok(Some(models::Image {
+        id: row.get(0),
+        path,
+        original_path: row.get(1),
+        caption,
+        status: models::ImageStatus::Processing,
+        media_type: models::MediaType::Image,
+        near_duplicate: false,
+    }))
 }
 
-pub async fn get_gallery(
+fn upload_from_row(row: &SqliteRow) -> models::Upload {
+    models::Upload {
+        id: row.get(0),
+        gallery_id: row.get(1),
+        file_name: row.get(2),
+        length: row.get(3),
+        received: row.get(4),
+    }
+}
+
+pub async fn create_upload(
     db: &Db,
+    user_id: i64,
     gallery_id: i64,
-) -> Result<models::GalleryContents, errors::AppError> {
-    let mut rows = sqlx::query(
+    file_name: &str,
+    length: i64,
+) -> Result<models::Upload, sqlx::Error> {
+    let row = sqlx::query(
         r#"
-        WITH images AS (SELECT 
-            original_images.gallery_id AS gallery_id,
-            modified_images.id AS image_id,
-            modified_images.path AS path,
-            modified_images.caption AS caption
-        FROM modified_images 
-        LEFT JOIN original_images ON original_images.id = modified_images.original_image_id
-        WHERE original_images.gallery_id = ?1 AND modified_images.status = 'public')
-        SELECT 
-          galleries.name as gallery_name,
-          galleries.time_created as gallery_time_created,
-          galleries.gallery_text as gallery_text,
-          images.image_id as image_id,
-          images.path as image_path,
-          images.caption as image_caption
-        FROM images
-        RIGHT JOIN galleries on images.gallery_id = galleries.id
-        WHERE galleries.id = ?1
+        INSERT INTO uploads (id, user_id, gallery_id, file_name, length)
+        VALUES (?1, ?2, ?3, ?4, ?5)
+        RETURNING id, gallery_id, file_name, length, received
         "#,
     )
+    .bind(Uuid::new_v4().to_string())
+    .bind(user_id)
     .bind(gallery_id)
-    .fetch(&db.0);
+    .bind(file_name)
+    .bind(length)
+    .fetch_one(&db.0)
+    .await?;
 
-    let mut images = vec![];
-    let mut name = None;
-    let mut time_created = None;
+    Ok(upload_from_row(&row))
+}
 
-    while let Ok(row) = rows.try_next().await {
-        let row = match row {
-            Some(row) => row,
-            None => break,
-        };
-        if name.is_none() {
-            info!("Getting name");
-            name = Some(row.get(0));
-            info!("Name: {}", name.clone().unwrap());
-        }
-        if time_created.is_none() {
-            info!("Getting time created");
-            time_created = Some(row.get(1));
-            info!("Time created: {}", time_created.clone().unwrap());
-        }
-        let image_id: i64 = row.get(3);
-        let path: String = row.get(4);
-        let caption: String = row.get(5);
-        if path.is_empty() {
-            continue;
-        }
-        images.push(models::Image {
-            id: image_id,
-            path,
-            original_path: None,
-            caption,
-        });
-    }
+// Uploads can only be continued by whoever started them
+pub async fn get_upload(
+    db: &Db,
+    upload_id: &str,
+    user_id: i64,
+) -> Result<Option<models::Upload>, sqlx::Error> {
+    let row = sqlx::query(
+        r#"
+        SELECT id, gallery_id, file_name, length, received
+        FROM uploads WHERE id = ?1 AND user_id = ?2
+        "#,
+    )
+    .bind(upload_id)
+    .bind(user_id)
+    .fetch_optional(&db.0)
+    .await?;
 
-    Ok(models::GalleryContents {
-        id: gallery_id,
-        name: name.ok_or("Couldn't get gallery name".to_string())?,
-        images,
-        time_created: time_created.ok_or("Couldn't get gallery time created".to_string())?,
-    })
+    Ok(row.as_ref().map(upload_from_row))
 }
 
-pub async fn delete_gallery(db: &Db, gallery_id: i64) -> Result<(), sqlx::Error> {
+// Record that the bytes up to `to` have arrived. Only moves on from `from`, so
+// when two requests send the same chunk only one of them counts.
+pub async fn advance_upload(
+    db: &Db,
+    upload_id: &str,
+    from: i64,
+    to: i64,
+) -> Result<bool, sqlx::Error> {
     let result = sqlx::query(
         r#"
-        UPDATE galleries SET status = 'deleted' WHERE id = ?1
+        UPDATE uploads SET received = ?3, time_modified = CURRENT_TIMESTAMP
+        WHERE id = ?1 AND received = ?2 AND ?3 <= length
         "#,
     )
-    .bind(gallery_id)
+    .bind(upload_id)
+    .bind(from)
+    .bind(to)
     .execute(&db.0)
-    .await;
+    .await?;
 
-    match result {
-        Ok(_) => Ok(()),
-        Err(e) => Err(e),
-    }
+    Ok(result.rows_affected() == 1)
 }
 
-pub async fn update_gallery(
+// Take a complete upload to finish it, so that only one request can. Returns
+// false if it has gone, or hasn't all arrived.
+pub async fn claim_upload(db: &Db, upload_id: &str) -> Result<bool, sqlx::Error> {
+    let row = sqlx::query(
+        r#"
+        DELETE FROM uploads WHERE id = ?1 AND received = length RETURNING id
+        "#,
+    )
+    .bind(upload_id)
+    .fetch_optional(&db.0)
+    .await?;
+
+    Ok(row.is_some())
+}
+
+// Put back an upload that was claimed but couldn't be finished, so it can be
+// finished again
+pub async fn restore_upload(
     db: &Db,
-    gallery_id: i64,
-    update: models::GalleryUpdate<'_>,
+    upload: &models::Upload,
+    user_id: i64,
 ) -> Result<(), sqlx::Error> {
-    let result = sqlx::query(
+    sqlx::query(
         r#"
-        UPDATE galleries SET name = ?1 WHERE id = ?2
+        INSERT INTO uploads (id, user_id, gallery_id, file_name, length, received)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6)
         "#,
     )
-    .bind(update.name)
-    .bind(gallery_id)
+    .bind(&upload.id)
+    .bind(user_id)
+    .bind(upload.gallery_id)
+    .bind(&upload.file_name)
+    .bind(upload.length)
+    .bind(upload.received)
     .execute(&db.0)
-    .await;
+    .await?;
 
-    match result {
-        Ok(_) => Ok(()),
-        Err(e) => Err(e),
-    }
+    Ok(())
 }
 
-pub async fn delete_image(db: &Db, image_id: i64) -> Result<(), sqlx::Error> {
-    let row = sqlx::query(
+pub async fn delete_upload(db: &Db, upload_id: &str) -> Result<(), sqlx::Error> {
+    sqlx::query("DELETE FROM uploads WHERE id = ?1")
+        .bind(upload_id)
+        .execute(&db.0)
+        .await?;
+    Ok(())
+}
+
+// Forget uploads that haven't had a chunk in `ttl` seconds, returning them so
+// their partial files can be removed
+pub async fn delete_stale_uploads(db: &Db, ttl: i64) -> Result<Vec<models::Upload>, sqlx::Error> {
+    let rows = sqlx::query(
         r#"
-        UPDATE modified_images SET status = 'deleted' WHERE id = ?1
+        DELETE FROM uploads
+        WHERE time_modified < datetime('now', '-' || ?1 || ' seconds')
+        RETURNING id, gallery_id, file_name, length, received
         "#,
     )
-    .bind(image_id)
-    .execute(&db.0)
+    .bind(ttl)
+    .fetch_all(&db.0)
     .await?;
 
-    Ok(())
+    Ok(rows.iter().map(upload_from_row).collect())
 }
 
-pub async fn update_image_caption(
+pub async fn set_image_status(
     db: &Db,
     image_id: i64,
-    caption: &str,
+    status: models::ImageStatus,
 ) -> Result<(), sqlx::Error> {
+    // Never bring a deleted or replaced image back, but a deleted one is restored
+    // as it would have been
     sqlx::query(
         r#"
-        UPDATE modified_images SET caption = ?1, time_modified = CURRENT_TIMESTAMP WHERE id = ?2
+        UPDATE modified_images SET
+          status = CASE status WHEN 'deleted' THEN 'deleted' ELSE ?1 END,
+          status_before_deletion = CASE status WHEN 'deleted' THEN ?1 END
+        WHERE id = ?2 AND status != 'superseded'
         "#,
     )
-    .bind(caption)
+    .bind(status.as_str())
     .bind(image_id)
     .execute(&db.0)
     .await?;
@@ -600,15 +1088,3206 @@ pub async fn update_image_caption(
     Ok(())
 }
 
-pub async fn delete_user_session(db: &Db, session_token: &str) -> Result<(), sqlx::Error> {
+// Point an image at the file it was made into, once that is stored
+pub async fn set_image_path(db: &Db, image_id: i64, path: &str) -> Result<(), sqlx::Error> {
+    sqlx::query("UPDATE modified_images SET path = ?1 WHERE id = ?2")
+        .bind(path)
+        .bind(image_id)
+        .execute(&db.0)
+        .await?;
+
+    Ok(())
+}
+
+// Metadata belongs to the original, so every edit of an image shares it
+pub async fn save_image_metadata(
+    db: &Db,
+    image_id: i64,
+    metadata: &models::ImageMetadata,
+) -> Result<(), sqlx::Error> {
     sqlx::query(
         r#"
-        DELETE FROM sessions WHERE session_token = ?1
+        INSERT OR REPLACE INTO image_metadata (
+            original_image_id,
+            capture_time,
+            camera_make,
+            camera_model,
+            orientation,
+            latitude,
+            longitude
+        )
+        SELECT original_image_id, ?2, ?3, ?4, ?5, ?6, ?7
+        FROM modified_images WHERE id = ?1
         "#,
     )
-    .bind(session_token)
+    .bind(image_id)
+    .bind(&metadata.capture_time)
+    .bind(&metadata.camera_make)
+    .bind(&metadata.camera_model)
+    .bind(metadata.orientation)
+    .bind(metadata.latitude)
+    .bind(metadata.longitude)
     .execute(&db.0)
     .await?;
 
     Ok(())
 }
+
+pub async fn get_image_metadata(
+    db: &Db,
+    image_id: i64,
+) -> Result<Option<models::ImageMetadata>, sqlx::Error> {
+    let row = sqlx::query(
+        r#"
+        SELECT
+            image_metadata.capture_time,
+            image_metadata.camera_make,
+            image_metadata.camera_model,
+            image_metadata.orientation,
+            image_metadata.latitude,
+            image_metadata.longitude
+        FROM image_metadata
+        INNER JOIN modified_images
+            ON modified_images.original_image_id = image_metadata.original_image_id
+        WHERE modified_images.id = ?1
+        "#,
+    )
+    .bind(image_id)
+    .fetch_optional(&db.0)
+    .await?;
+
+    Ok(row.map(|row| models::ImageMetadata {
+        capture_time: row.get(0),
+        camera_make: row.get(1),
+        camera_model: row.get(2),
+        orientation: row.get(3),
+        latitude: row.get(4),
+        longitude: row.get(5),
+    }))
+}
+
+// Another image still in a gallery made from an original with a content hash
+const DUPLICATE_QUERY: &str = r#"
+    SELECT modified_images.id
+    FROM original_images
+    JOIN modified_images ON modified_images.original_image_id = original_images.id
+    WHERE original_images.gallery_id = ?1
+      AND original_images.content_hash = ?2
+      AND modified_images.id != ?3
+      AND modified_images.status IN ('public', 'processing')
+    LIMIT 1
+"#;
+
+// Store the perceptual hash of an image's original, and flag it if an earlier
+// image in the same gallery looks like it. Returns the id of that image.
+pub async fn save_perceptual_hash(
+    db: &Db,
+    image_id: i64,
+    perceptual_hash: u64,
+    max_distance: u32,
+) -> Result<Option<i64>, sqlx::Error> {
+    let mut tx = db.0.begin().await?;
+
+    // SQLite integers are signed, the bits are kept as they are
+    let row = sqlx::query(
+        r#"
+        UPDATE original_images SET perceptual_hash = ?2
+        WHERE id = (SELECT original_image_id FROM modified_images WHERE id = ?1)
+        RETURNING id, gallery_id
+        "#,
+    )
+    .bind(image_id)
+    .bind(perceptual_hash as i64)
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    let (original_image_id, gallery_id): (i64, i64) = match row {
+        Some(row) => (row.get(0), row.get(1)),
+        None => return Ok(None),
+    };
+
+    let rows = sqlx::query(
+        r#"
+        SELECT original_images.id, original_images.perceptual_hash, modified_images.id
+        FROM original_images
+        JOIN modified_images ON modified_images.original_image_id = original_images.id
+        WHERE original_images.gallery_id = ?1
+          AND original_images.id < ?2
+          AND original_images.perceptual_hash IS NOT NULL
+          AND modified_images.status IN ('public', 'processing')
+        ORDER BY original_images.id
+        "#,
+    )
+    .bind(gallery_id)
+    .bind(original_image_id)
+    .fetch_all(&mut *tx)
+    .await?;
+
+    let similar = rows.iter().find(|row| {
+        hashing::distance(row.get::<i64, _>(1) as u64, perceptual_hash) <= max_distance
+    });
+
+    sqlx::query("UPDATE original_images SET near_duplicate_of = ?2 WHERE id = ?1")
+        .bind(original_image_id)
+        .bind(similar.map(|row| row.get::<i64, _>(0)))
+        .execute(&mut *tx)
+        .await?;
+
+    tx.commit().await?;
+
+    Ok(similar.map(|row| row.get(2)))
+}
+
+// Images across all galleries that look alike, in groups. Only images with a
+// perceptual hash that are still shown are compared.
+pub async fn get_near_duplicates(
+    db: &Db,
+    max_distance: u32,
+) -> Result<Vec<Vec<models::DuplicateImage>>, sqlx::Error> {
+    let rows = sqlx::query(
+        r#"
+        SELECT
+          modified_images.id,
+          modified_images.path,
+          original_images.filename,
+          original_images.perceptual_hash,
+          galleries.id,
+          galleries.name
+        FROM modified_images
+        JOIN original_images ON original_images.id = modified_images.original_image_id
+        JOIN galleries ON galleries.id = original_images.gallery_id
+        WHERE modified_images.status = 'public'
+          AND galleries.status != 'deleted'
+          AND original_images.perceptual_hash IS NOT NULL
+        ORDER BY original_images.id
+        "#,
+    )
+    .fetch_all(&db.0)
+    .await?;
+
+    let hashes: Vec<u64> = rows.iter().map(|row| row.get::<i64, _>(3) as u64).collect();
+
+    Ok(hashing::clusters(&hashes, max_distance)
+        .into_iter()
+        .map(|cluster| {
+            cluster
+                .into_iter()
+                .map(|i| models::DuplicateImage {
+                    id: rows[i].get(0),
+                    path: rows[i].get(1),
+                    original_filename: rows[i].get(2),
+                    gallery_id: rows[i].get(4),
+                    gallery_name: rows[i].get(5),
+                })
+                .collect()
+        })
+        .collect())
+}
+
+// A single image in a gallery the user can see, None if there is no such image
+pub async fn get_gallery_image(
+    db: &Db,
+    gallery_id: i64,
+    image_id: i64,
+    user: &models::User,
+) -> Result<Option<models::Image>, sqlx::Error> {
+    let query = format!(
+        r#"
+        SELECT
+          modified_images.id,
+          modified_images.path,
+          modified_images.caption,
+          modified_images.status,
+          modified_images.media_type,
+          original_images.near_duplicate_of IS NOT NULL
+        FROM modified_images
+        INNER JOIN original_images ON original_images.id = modified_images.original_image_id
+        INNER JOIN galleries ON galleries.id = original_images.gallery_id
+        WHERE modified_images.id = ?3
+          AND galleries.id = ?4
+          AND modified_images.status != 'deleted'
+          AND {}
+        "#,
+        GALLERY_VISIBLE_TO_USER
+    );
+
+    let row = sqlx::query(&query)
+        .bind(user.id)
+        .bind(user.role.as_str())
+        .bind(image_id)
+        .bind(gallery_id)
+        .fetch_optional(&db.0)
+        .await?;
+
+    Ok(row.map(|row| models::Image {
+        id: row.get(0),
+        path: row.get(1),
+        original_path: None,
+        caption: row.get(2),
+        status: models::ImageStatus::from_status(row.get(3)),
+        media_type: models::MediaType::from_media_type(row.get(4)),
+        near_duplicate: row.get(5),
+    }))
+}
+
+// Let a user log in, using up any signup links still outstanding for them.
+// Returns their email, or None if there is no unverified user with that id
+pub async fn verify_user(db: &Db, user_id: i64) -> Result<Option<String>, sqlx::Error> {
+    let mut tx = db.0.begin().await?;
+
+    let row = sqlx::query(
+        r#"
+        UPDATE users SET is_verified = TRUE
+        WHERE id = ?1 AND is_verified = FALSE
+        RETURNING email
+        "#,
+    )
+    .bind(user_id)
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    let row = match row {
+        Some(row) => row,
+        None => return Ok(None),
+    };
+
+    sqlx::query(
+        r#"
+        UPDATE verification_tokens SET time_consumed = CURRENT_TIMESTAMP
+        WHERE user_id = ?1 AND purpose = ?2 AND time_consumed IS NULL
+        "#,
+    )
+    .bind(user_id)
+    .bind(constants::SIGNUP_TOKEN)
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+
+    let email: String = row.get(0);
+
+    Ok(Some(email))
+}
+
+const USER_SUMMARY_COLUMNS: &str = r#"
+    users.id,
+    users.email,
+    users.role,
+    users.is_verified,
+    users.is_disabled,
+    users.time_created,
+    (SELECT COUNT(*) FROM sessions
+        WHERE sessions.user_id = users.id AND sessions.expires_at > ?1) AS session_count
+"#;
+
+fn user_summary_from_row(row: &sqlx::sqlite::SqliteRow) -> models::UserSummary {
+    models::UserSummary {
+        id: row.get(0),
+        email: row.get(1),
+        role: models::Role::from_db(row.get(2)),
+        is_verified: row.get(3),
+        is_disabled: row.get(4),
+        time_created: row.get(5),
+        session_count: row.get(6),
+    }
+}
+
+// Every user, pending signups first, for the admin console
+pub async fn get_users(db: &Db) -> Result<Vec<models::UserSummary>, sqlx::Error> {
+    let query = format!(
+        "SELECT {} FROM users ORDER BY users.is_verified ASC, users.email ASC",
+        USER_SUMMARY_COLUMNS
+    );
+
+    let rows = sqlx::query(&query)
+        .bind(chrono::Utc::now().timestamp())
+        .fetch_all(&db.0)
+        .await?;
+
+    Ok(rows.iter().map(user_summary_from_row).collect())
+}
+
+pub async fn get_user_summary(
+    db: &Db,
+    user_id: i64,
+) -> Result<Option<models::UserSummary>, sqlx::Error> {
+    let query = format!("SELECT {} FROM users WHERE users.id = ?2", USER_SUMMARY_COLUMNS);
+
+    let row = sqlx::query(&query)
+        .bind(chrono::Utc::now().timestamp())
+        .bind(user_id)
+        .fetch_optional(&db.0)
+        .await?;
+
+    Ok(row.as_ref().map(user_summary_from_row))
+}
+
+pub async fn set_user_role(db: &Db, user_id: i64, role: &models::Role) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        r#"
+        UPDATE users SET role = ?1 WHERE id = ?2
+        "#,
+    )
+    .bind(role.as_str())
+    .bind(user_id)
+    .execute(&db.0)
+    .await?;
+
+    Ok(())
+}
+
+// Make the user with this email an admin who can log in, returns false if
+// there is no such user
+pub async fn make_admin(db: &Db, email: &str) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query(
+        r#"
+        UPDATE users SET role = ?1, is_verified = TRUE, is_disabled = FALSE
+        WHERE email = ?2
+        "#,
+    )
+    .bind(models::Role::Admin.as_str())
+    .bind(email)
+    .execute(&db.0)
+    .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+// Disabling a user also logs them out everywhere
+pub async fn set_user_disabled(db: &Db, user_id: i64, disabled: bool) -> Result<(), sqlx::Error> {
+    let mut tx = db.0.begin().await?;
+
+    sqlx::query(
+        r#"
+        UPDATE users SET is_disabled = ?1 WHERE id = ?2
+        "#,
+    )
+    .bind(disabled)
+    .bind(user_id)
+    .execute(&mut *tx)
+    .await?;
+
+    if disabled {
+        sqlx::query(
+            r#"
+            DELETE FROM sessions WHERE user_id = ?1
+            "#,
+        )
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    tx.commit().await?;
+
+    Ok(())
+}
+
+// Log a user out everywhere, returning how many sessions were ended
+pub async fn delete_user_sessions(db: &Db, user_id: i64) -> Result<u64, sqlx::Error> {
+    let result = sqlx::query(
+        r#"
+        DELETE FROM sessions WHERE user_id = ?1
+        "#,
+    )
+    .bind(user_id)
+    .execute(&db.0)
+    .await?;
+
+    Ok(result.rows_affected())
+}
+
+pub async fn get_galleries(
+    db: &Db,
+    user: &models::User,
+) -> Result<Vec<models::GalleryTile>, sqlx::Error> {
+    let mut galleries = vec![];
+
+    let query = format!(
+        r#"
+        SELECT 
+          galleries.id AS id,
+          galleries.name AS name,
+          galleries.time_created AS time_created,
+          count(modified_images.id) AS n_images,
+          max(modified_images.path) AS last_image,
+          users.email AS created_by,
+          galleries.status AS status,
+          ({}) AS can_edit
+        FROM galleries 
+        LEFT JOIN original_images ON galleries.id = original_images.gallery_id
+        LEFT JOIN modified_images ON original_images.id = modified_images.original_image_id AND modified_images.status = 'public'
+        LEFT JOIN users ON galleries.user_id = users.id
+        WHERE {}
+        GROUP BY galleries.id, galleries.name, galleries.time_created
+        ORDER BY galleries.time_created DESC
+        "#,
+        GALLERY_EDITABLE_BY_USER, GALLERY_VISIBLE_TO_USER
+    );
+
+    let mut rows = sqlx::query(&query)
+        .bind(user.id)
+        .bind(user.role.as_str())
+        .fetch(&db.0);
+
+    while let Ok(row) = rows.try_next().await {
+        let row = match row {
+            Some(row) => row,
+            None => break,
+        };
+        let id: i64 = row.get(0);
+        let name: String = row.get(1);
+        let time_created: String = row.get(2);
+        let mut n_images: i64 = row.get(3);
+        let last_image: Option<String> = row.get(4);
+        let created_by: String = row.get(5);
+        let status: String = row.get(6);
+        let can_edit: bool = row.get(7);
+        if last_image.is_none() {
+            n_images = 0;
+        }
+        galleries.push(models::GalleryTile::new(
+            id,
+            name,
+            models::Visibility::from_status(&status),
+            last_image,
+            n_images,
+            time_created,
+            created_by,
+            can_edit,
+        ));
+    }
+
+    Ok(galleries)
+}
+
+pub async fn get_gallery(
+    db: &Db,
+    gallery_id: i64,
+    user: &models::User,
+) -> Result<models::GalleryContents, errors::AppError> {
+    // Images are ordered by their original, so an edited image keeps its place
+    let query = format!(
+        r#"
+        WITH images AS (SELECT 
+            original_images.gallery_id AS gallery_id,
+            modified_images.id AS image_id,
+            modified_images.path AS path,
+            modified_images.caption AS caption,
+            modified_images.status AS status,
+            modified_images.original_image_id AS original_image_id,
+            modified_images.media_type AS media_type,
+            original_images.near_duplicate_of IS NOT NULL AS near_duplicate
+        FROM modified_images 
+        LEFT JOIN original_images ON original_images.id = modified_images.original_image_id
+        WHERE original_images.gallery_id = ?3 AND modified_images.status IN ('public', 'processing'))
+        SELECT 
+          galleries.name as gallery_name,
+          galleries.time_created as gallery_time_created,
+          galleries.gallery_text as gallery_text,
+          images.image_id as image_id,
+          images.path as image_path,
+          images.caption as image_caption,
+          galleries.status as gallery_status,
+          ({}) as can_edit,
+          images.status as image_status,
+          images.media_type as media_type,
+          images.near_duplicate as near_duplicate
+        FROM images
+        RIGHT JOIN galleries on images.gallery_id = galleries.id
+        WHERE galleries.id = ?3 AND {}
+        ORDER BY images.original_image_id
+        "#,
+        GALLERY_EDITABLE_BY_USER, GALLERY_VISIBLE_TO_USER
+    );
+
+    let mut rows = sqlx::query(&query)
+        .bind(user.id)
+        .bind(user.role.as_str())
+        .bind(gallery_id)
+        .fetch(&db.0);
+
+    let mut images = vec![];
+    let mut name = None;
+    let mut time_created = None;
+    let mut status: Option<String> = None;
+    let mut can_edit = false;
+
+    while let Ok(row) = rows.try_next().await {
+        let row = match row {
+            Some(row) => row,
+            None => break,
+        };
+        if name.is_none() {
+            info!("Getting name");
+            name = Some(row.get(0));
+            info!("Name: {}", name.clone().unwrap());
+        }
+        if time_created.is_none() {
+            info!("Getting time created");
+            time_created = Some(row.get(1));
+            info!("Time created: {}", time_created.clone().unwrap());
+        }
+        if status.is_none() {
+            status = Some(row.get(6));
+            can_edit = row.get(7);
+        }
+        let image_id: i64 = row.get(3);
+        let path: String = row.get(4);
+        let caption: String = row.get(5);
+        if path.is_empty() {
+            continue;
+        }
+        let image_status: String = row.get(8);
+        let media_type: String = row.get(9);
+        images.push(models::Image {
+            id: image_id,
+            path,
+            original_path: None,
+            caption,
+            status: models::ImageStatus::from_status(&image_status),
+            media_type: models::MediaType::from_media_type(&media_type),
+            near_duplicate: row.get(10),
+        });
+    }
+
+    // Galleries the user isn't allowed to see look exactly like missing ones
+    let name = name.ok_or(errors::AppError {
+        code: 404,
+        message: "Gallery not found".to_string(),
+    })?;
+
+    Ok(models::GalleryContents {
+        id: gallery_id,
+        name,
+        visibility: models::Visibility::from_status(&status.unwrap_or_default()),
+        can_edit,
+        images,
+        time_created: time_created.ok_or("Couldn't get gallery time created".to_string())?,
+    })
+}
+
+// Check whether an image file may be served to a user.
+// The path has to belong to a known modified image that hasn't been deleted, in
+// a gallery that hasn't been deleted and that the user can see, or to one in the
+// trash that the user could restore. Originals are never served, since they keep
+// their EXIF (GPS included).
+pub async fn can_view_image_file(
+    db: &Db,
+    user: &models::User,
+    image_path: &str,
+) -> Result<bool, sqlx::Error> {
+    let query = format!(
+        r#"
+        SELECT 1
+        FROM modified_images
+        JOIN original_images ON original_images.id = modified_images.original_image_id
+        JOIN galleries ON galleries.id = original_images.gallery_id
+        WHERE modified_images.path = ?3
+          AND (
+            (modified_images.status != 'deleted' AND {})
+            OR (
+              (modified_images.status = 'deleted' OR galleries.status = 'deleted')
+              AND {}
+            )
+          )
+        LIMIT 1
+        "#,
+        GALLERY_VISIBLE_TO_USER, GALLERY_EDITABLE_IF_RESTORED
+    );
+
+    let row = sqlx::query(&query)
+        .bind(user.id)
+        .bind(user.role.as_str())
+        .bind(image_path)
+        .fetch_optional(&db.0)
+        .await?;
+
+    Ok(row.is_some())
+}
+
+pub async fn can_edit_gallery(
+    db: &Db,
+    user: &models::User,
+    gallery_id: i64,
+) -> Result<bool, sqlx::Error> {
+    let query = format!(
+        r#"
+        SELECT 1 FROM galleries WHERE galleries.id = ?3 AND {}
+        "#,
+        GALLERY_EDITABLE_BY_USER
+    );
+
+    let row = sqlx::query(&query)
+        .bind(user.id)
+        .bind(user.role.as_str())
+        .bind(gallery_id)
+        .fetch_optional(&db.0)
+        .await?;
+
+    Ok(row.is_some())
+}
+
+// Only admins and the creator of a gallery can decide who else may see or edit it
+pub async fn can_manage_gallery(
+    db: &Db,
+    user: &models::User,
+    gallery_id: i64,
+) -> Result<bool, sqlx::Error> {
+    let row = sqlx::query(
+        r#"
+        SELECT 1 FROM galleries
+        WHERE id = ?3 AND status != 'deleted' AND (?2 = 'admin' OR (?2 = 'writer' AND user_id = ?1))
+        "#,
+    )
+    .bind(user.id)
+    .bind(user.role.as_str())
+    .bind(gallery_id)
+    .fetch_optional(&db.0)
+    .await?;
+
+    Ok(row.is_some())
+}
+
+pub async fn get_image_gallery_id(db: &Db, image_id: i64) -> Result<Option<i64>, sqlx::Error> {
+    let row = sqlx::query(
+        r#"
+        SELECT original_images.gallery_id
+        FROM modified_images
+        JOIN original_images ON original_images.id = modified_images.original_image_id
+        WHERE modified_images.id = ?1
+        "#,
+    )
+    .bind(image_id)
+    .fetch_optional(&db.0)
+    .await?;
+
+    Ok(row.map(|row| row.get(0)))
+}
+
+pub async fn get_gallery_editors(
+    db: &Db,
+    gallery_id: i64,
+) -> Result<Vec<models::GalleryUser>, sqlx::Error> {
+    let mut editors = vec![];
+
+    let mut rows = sqlx::query(
+        r#"
+        SELECT users.id, users.email
+        FROM gallery_editors
+        JOIN users ON users.id = gallery_editors.user_id
+        WHERE gallery_editors.gallery_id = ?1
+        ORDER BY users.email
+        "#,
+    )
+    .bind(gallery_id)
+    .fetch(&db.0);
+
+    while let Some(row) = rows.try_next().await? {
+        editors.push(models::GalleryUser {
+            user_id: row.get(0),
+            email: row.get(1),
+        });
+    }
+
+    Ok(editors)
+}
+
+// Let a writer edit a gallery, returns false if there is no writer with that email
+pub async fn add_gallery_editor(
+    db: &Db,
+    gallery_id: i64,
+    email: &str,
+) -> Result<bool, sqlx::Error> {
+    sqlx::query(
+        r#"
+        INSERT OR IGNORE INTO gallery_editors (gallery_id, user_id)
+        SELECT ?1, id FROM users WHERE email = ?2 AND role IN ('writer', 'admin')
+        "#,
+    )
+    .bind(gallery_id)
+    .bind(email)
+    .execute(&db.0)
+    .await?;
+
+    let row = sqlx::query(
+        r#"
+        SELECT 1 FROM gallery_editors
+        JOIN users ON users.id = gallery_editors.user_id
+        WHERE gallery_editors.gallery_id = ?1 AND users.email = ?2
+        "#,
+    )
+    .bind(gallery_id)
+    .bind(email)
+    .fetch_optional(&db.0)
+    .await?;
+
+    Ok(row.is_some())
+}
+
+pub async fn remove_gallery_editor(
+    db: &Db,
+    gallery_id: i64,
+    user_id: i64,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        r#"
+        DELETE FROM gallery_editors WHERE gallery_id = ?1 AND user_id = ?2
+        "#,
+    )
+    .bind(gallery_id)
+    .bind(user_id)
+    .execute(&db.0)
+    .await?;
+
+    Ok(())
+}
+
+pub async fn update_gallery_visibility(
+    db: &Db,
+    gallery_id: i64,
+    visibility: models::Visibility,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        r#"
+        UPDATE galleries SET status = ?1 WHERE id = ?2 AND status != 'deleted'
+        "#,
+    )
+    .bind(visibility.as_str())
+    .bind(gallery_id)
+    .execute(&db.0)
+    .await?;
+
+    Ok(())
+}
+
+pub async fn get_gallery_members(
+    db: &Db,
+    gallery_id: i64,
+) -> Result<Vec<models::GalleryUser>, sqlx::Error> {
+    let mut members = vec![];
+
+    let mut rows = sqlx::query(
+        r#"
+        SELECT users.id, users.email
+        FROM gallery_members
+        JOIN users ON users.id = gallery_members.user_id
+        WHERE gallery_members.gallery_id = ?1
+        ORDER BY users.email
+        "#,
+    )
+    .bind(gallery_id)
+    .fetch(&db.0);
+
+    while let Some(row) = rows.try_next().await? {
+        members.push(models::GalleryUser {
+            user_id: row.get(0),
+            email: row.get(1),
+        });
+    }
+
+    Ok(members)
+}
+
+// Share a gallery with a user. Only verified, enabled users are added, and
+// nothing is returned, so callers can't tell whether an email has an account
+pub async fn add_gallery_member(db: &Db, gallery_id: i64, email: &str) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        r#"
+        INSERT OR IGNORE INTO gallery_members (gallery_id, user_id)
+        SELECT ?1, id FROM users
+        WHERE email = ?2 AND is_verified = TRUE AND is_disabled = FALSE
+        "#,
+    )
+    .bind(gallery_id)
+    .bind(email)
+    .execute(&db.0)
+    .await?;
+
+    Ok(())
+}
+
+pub async fn remove_gallery_member(
+    db: &Db,
+    gallery_id: i64,
+    user_id: i64,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        r#"
+        DELETE FROM gallery_members WHERE gallery_id = ?1 AND user_id = ?2
+        "#,
+    )
+    .bind(gallery_id)
+    .bind(user_id)
+    .execute(&db.0)
+    .await?;
+
+    Ok(())
+}
+
+// Move a gallery to the trash, remembering who did it and how it was shared
+pub async fn delete_gallery(db: &Db, gallery_id: i64, user_id: i64) -> Result<(), sqlx::Error> {
+    let result = sqlx::query(
+        r#"
+        UPDATE galleries SET
+          status = 'deleted',
+          status_before_deletion = status,
+          time_deleted = CURRENT_TIMESTAMP,
+          deleted_by = ?2
+        WHERE id = ?1 AND status != 'deleted'
+        "#,
+    )
+    .bind(gallery_id)
+    .bind(user_id)
+    .execute(&db.0)
+    .await;
+
+    match result {
+        Ok(_) => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+pub async fn update_gallery(
+    db: &Db,
+    gallery_id: i64,
+    update: models::GalleryUpdate<'_>,
+) -> Result<(), sqlx::Error> {
+    let result = sqlx::query(
+        r#"
+        UPDATE galleries SET name = ?1 WHERE id = ?2
+        "#,
+    )
+    .bind(update.name)
+    .bind(gallery_id)
+    .execute(&db.0)
+    .await;
+
+    match result {
+        Ok(_) => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+// Move an image to the trash, remembering who did it and what it was
+pub async fn delete_image(db: &Db, image_id: i64, user_id: i64) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        r#"
+        UPDATE modified_images SET
+          status = 'deleted',
+          status_before_deletion = status,
+          time_deleted = CURRENT_TIMESTAMP,
+          deleted_by = ?2
+        WHERE id = ?1 AND status NOT IN ('deleted', 'superseded')
+        "#,
+    )
+    .bind(image_id)
+    .bind(user_id)
+    .execute(&db.0)
+    .await?;
+
+    Ok(())
+}
+
+// Whether the gallery is in the trash and the user could restore it
+pub async fn can_restore_gallery(
+    db: &Db,
+    user: &models::User,
+    gallery_id: i64,
+) -> Result<bool, sqlx::Error> {
+    let query = format!(
+        r#"
+        SELECT 1 FROM galleries WHERE galleries.id = ?3 AND galleries.status = 'deleted' AND {}
+        "#,
+        GALLERY_EDITABLE_IF_RESTORED
+    );
+
+    let row = sqlx::query(&query)
+        .bind(user.id)
+        .bind(user.role.as_str())
+        .bind(gallery_id)
+        .fetch_optional(&db.0)
+        .await?;
+
+    Ok(row.is_some())
+}
+
+// Take a gallery out of the trash, shared as it was. Galleries deleted before
+// that was remembered come back private.
+pub async fn restore_gallery(db: &Db, gallery_id: i64) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        r#"
+        UPDATE galleries SET
+          status = COALESCE(status_before_deletion, 'private'),
+          status_before_deletion = NULL,
+          time_deleted = NULL,
+          deleted_by = NULL
+        WHERE id = ?1 AND status = 'deleted'
+        "#,
+    )
+    .bind(gallery_id)
+    .execute(&db.0)
+    .await?;
+
+    Ok(())
+}
+
+// Take an image out of the trash. Images deleted before their status was
+// remembered were all ready. Returns false, leaving it there, if the same photo
+// has been uploaded to its gallery since.
+pub async fn restore_image(db: &Db, image_id: i64) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query(
+        r#"
+        UPDATE modified_images SET
+          status = COALESCE(status_before_deletion, 'public'),
+          status_before_deletion = NULL,
+          time_deleted = NULL,
+          deleted_by = NULL
+        WHERE id = ?1 AND status = 'deleted'
+          AND NOT EXISTS (
+            SELECT 1
+            FROM original_images AS restored
+            JOIN original_images
+              ON original_images.gallery_id = restored.gallery_id
+              AND original_images.content_hash = restored.content_hash
+            JOIN modified_images AS live ON live.original_image_id = original_images.id
+            WHERE restored.id = modified_images.original_image_id
+              AND live.id != ?1
+              AND live.status IN ('public', 'processing')
+          )
+        "#,
+    )
+    .bind(image_id)
+    .execute(&db.0)
+    .await?;
+    if result.rows_affected() > 0 {
+        return Ok(true);
+    }
+
+    // Not restored while still deleted means the photo was uploaded again
+    let row = sqlx::query("SELECT status FROM modified_images WHERE id = ?1")
+        .bind(image_id)
+        .fetch_optional(&db.0)
+        .await?;
+    Ok(row.is_none_or(|row| row.get::<String, _>(0) != "deleted"))
+}
+
+// Deleted galleries the user could restore, most recently deleted first
+pub async fn get_trashed_galleries(
+    db: &Db,
+    user: &models::User,
+) -> Result<Vec<models::TrashedGallery>, sqlx::Error> {
+    let query = format!(
+        r#"
+        SELECT
+          galleries.id,
+          galleries.name,
+          (
+            SELECT COUNT(*)
+            FROM modified_images
+            JOIN original_images ON original_images.id = modified_images.original_image_id
+            WHERE original_images.gallery_id = galleries.id
+              AND modified_images.status NOT IN ('deleted', 'superseded')
+          ),
+          users.email,
+          COALESCE(galleries.time_deleted, galleries.time_created) AS deleted
+        FROM galleries
+        LEFT JOIN users ON users.id = galleries.deleted_by
+        WHERE galleries.status = 'deleted' AND {}
+        ORDER BY deleted DESC, galleries.id DESC
+        "#,
+        GALLERY_EDITABLE_IF_RESTORED
+    );
+
+    let rows = sqlx::query(&query)
+        .bind(user.id)
+        .bind(user.role.as_str())
+        .fetch_all(&db.0)
+        .await?;
+
+    Ok(rows
+        .iter()
+        .map(|row| models::TrashedGallery {
+            id: row.get(0),
+            name: row.get(1),
+            image_count: row.get(2),
+            deleted_by: row.get(3),
+            time_deleted_human: models::human_time(row.get(4)),
+        })
+        .collect())
+}
+
+// Deleted images the user could restore, most recently deleted first. Those in
+// deleted galleries come back with their gallery, so aren't listed.
+pub async fn get_trashed_images(
+    db: &Db,
+    user: &models::User,
+) -> Result<Vec<models::TrashedImage>, sqlx::Error> {
+    let query = format!(
+        r#"
+        SELECT
+          modified_images.id,
+          modified_images.path,
+          modified_images.caption,
+          original_images.filename,
+          modified_images.media_type,
+          COALESCE(modified_images.status_before_deletion, 'public') = 'public',
+          galleries.id,
+          galleries.name,
+          users.email,
+          COALESCE(modified_images.time_deleted, modified_images.time_modified) AS deleted
+        FROM modified_images
+        JOIN original_images ON original_images.id = modified_images.original_image_id
+        JOIN galleries ON galleries.id = original_images.gallery_id
+        LEFT JOIN users ON users.id = modified_images.deleted_by
+        WHERE modified_images.status = 'deleted' AND {}
+        ORDER BY deleted DESC, modified_images.id DESC
+        "#,
+        GALLERY_EDITABLE_BY_USER
+    );
+
+    let rows = sqlx::query(&query)
+        .bind(user.id)
+        .bind(user.role.as_str())
+        .fetch_all(&db.0)
+        .await?;
+
+    Ok(rows
+        .iter()
+        .map(|row| models::TrashedImage {
+            id: row.get(0),
+            path: row.get(1),
+            caption: row.get(2),
+            original_filename: row.get(3),
+            media_type: models::MediaType::from_media_type(row.get(4)),
+            ready: row.get(5),
+            gallery_id: row.get(6),
+            gallery_name: row.get(7),
+            deleted_by: row.get(8),
+            time_deleted_human: models::human_time(row.get(9)),
+        })
+        .collect())
+}
+
+// Every image the database knows of, with what should be on disk for it
+pub async fn get_stored_images(db: &Db) -> Result<Vec<models::StoredImage>, sqlx::Error> {
+    let rows = sqlx::query(
+        r#"
+        SELECT
+          modified_images.path,
+          original_images.path,
+          modified_images.media_type,
+          modified_images.status IN ('public', 'superseded') AND galleries.status != 'deleted',
+          modified_images.status = 'public' AND galleries.status != 'deleted'
+        FROM modified_images
+        JOIN original_images ON original_images.id = modified_images.original_image_id
+        JOIN galleries ON galleries.id = original_images.gallery_id
+        ORDER BY modified_images.id
+        "#,
+    )
+    .fetch_all(&db.0)
+    .await?;
+
+    Ok(rows
+        .iter()
+        .map(|row| models::StoredImage {
+            path: row.get(0),
+            original_path: row.get(1),
+            media_type: models::MediaType::from_media_type(row.get(2)),
+            needs_files: row.get(3),
+            needs_renditions: row.get(4),
+        })
+        .collect())
+}
+
+// Originals saved before their own paths were stored, oldest first
+pub async fn get_legacy_originals(db: &Db) -> Result<Vec<models::LegacyOriginal>, sqlx::Error> {
+    let rows = sqlx::query(
+        r#"
+        SELECT
+          original_images.id,
+          (SELECT MAX(id) FROM modified_images WHERE original_image_id = original_images.id),
+          original_images.path,
+          CAST(strftime('%s', original_images.time_created) AS INTEGER)
+        FROM original_images
+        WHERE original_images.is_legacy
+        ORDER BY original_images.id
+        "#,
+    )
+    .fetch_all(&db.0)
+    .await?;
+
+    Ok(rows
+        .iter()
+        .map(|row| models::LegacyOriginal {
+            id: row.get(0),
+            image_id: row.get(1),
+            path: row.get(2),
+            time_created: row.get(3),
+        })
+        .collect())
+}
+
+// Point a legacy original at the file it was really saved to. The triggers move
+// its reference from the image's file to that one. Returns whether it changed.
+pub async fn relink_original(
+    db: &Db,
+    original_image_id: i64,
+    path: &str,
+) -> Result<bool, sqlx::Error> {
+    let row = sqlx::query(
+        r#"
+        UPDATE original_images SET path = ?2, is_legacy = FALSE
+        WHERE id = ?1 AND is_legacy
+        RETURNING id
+        "#,
+    )
+    .bind(original_image_id)
+    .bind(path)
+    .fetch_optional(&db.0)
+    .await?;
+
+    Ok(row.is_some())
+}
+
+// Forget the originals, and every image made from them, that have been deleted
+// for more than `days` days: either the gallery was deleted, or every version of
+// the image was deleted or replaced. Returns how many originals went.
+// Their files are left for `get_unreferenced_blobs` to find.
+pub async fn purge_deleted_images(db: &Db, days: i64) -> Result<u64, sqlx::Error> {
+    let mut tx = db.0.begin().await?;
+
+    let rows = sqlx::query(
+        r#"
+        SELECT original_images.id
+        FROM original_images
+        JOIN galleries ON galleries.id = original_images.gallery_id
+        WHERE (
+          galleries.status = 'deleted'
+          AND COALESCE(galleries.time_deleted, galleries.time_created) < datetime('now', ?1)
+        ) OR (
+          NOT EXISTS (
+            SELECT 1 FROM modified_images
+            WHERE modified_images.original_image_id = original_images.id
+              AND modified_images.status NOT IN ('deleted', 'superseded')
+          ) AND EXISTS (
+            SELECT 1 FROM modified_images
+            WHERE modified_images.original_image_id = original_images.id
+              AND modified_images.status = 'deleted'
+              AND COALESCE(modified_images.time_deleted, modified_images.time_modified)
+                < datetime('now', ?1)
+          )
+        )
+        "#,
+    )
+    .bind(format!("-{} days", days))
+    .fetch_all(&mut *tx)
+    .await?;
+    let ids: Vec<i64> = rows.iter().map(|row| row.get(0)).collect();
+
+    // Images that looked like a purged one no longer look like anything, and
+    // jobs still waiting to work on one have nothing to work on
+    for id in &ids {
+        for statement in [
+            r#"
+            DELETE FROM jobs WHERE json_extract(payload, '$.image_id') IN (
+                SELECT id FROM modified_images WHERE original_image_id = ?1
+            )
+            "#,
+            "DELETE FROM image_metadata WHERE original_image_id = ?1",
+            "UPDATE original_images SET near_duplicate_of = NULL WHERE near_duplicate_of = ?1",
+            "DELETE FROM modified_images WHERE original_image_id = ?1",
+            "DELETE FROM original_images WHERE id = ?1",
+        ] {
+            sqlx::query(statement).bind(id).execute(&mut *tx).await?;
+        }
+    }
+
+    tx.commit().await?;
+
+    Ok(ids.len() as u64)
+}
+
+// Forget galleries deleted for more than `days` days once their images have been
+// purged, returning how many went. Those with uploads still going wait for them
+// to finish or be abandoned.
+pub async fn purge_deleted_galleries(db: &Db, days: i64) -> Result<u64, sqlx::Error> {
+    let mut tx = db.0.begin().await?;
+
+    let rows = sqlx::query(
+        r#"
+        SELECT id FROM galleries
+        WHERE status = 'deleted'
+          AND COALESCE(time_deleted, time_created) < datetime('now', ?1)
+          AND NOT EXISTS (SELECT 1 FROM original_images WHERE gallery_id = galleries.id)
+          AND NOT EXISTS (SELECT 1 FROM uploads WHERE gallery_id = galleries.id)
+        "#,
+    )
+    .bind(format!("-{} days", days))
+    .fetch_all(&mut *tx)
+    .await?;
+    let ids: Vec<i64> = rows.iter().map(|row| row.get(0)).collect();
+
+    for id in &ids {
+        for statement in [
+            "DELETE FROM gallery_members WHERE gallery_id = ?1",
+            "DELETE FROM gallery_editors WHERE gallery_id = ?1",
+            "DELETE FROM galleries WHERE id = ?1",
+        ] {
+            sqlx::query(statement).bind(id).execute(&mut *tx).await?;
+        }
+    }
+
+    tx.commit().await?;
+
+    Ok(ids.len() as u64)
+}
+
+// Paths of stored files no image uses any more, which can be deleted
+pub async fn get_unreferenced_blobs(db: &Db) -> Result<Vec<String>, sqlx::Error> {
+    let rows = sqlx::query("SELECT path FROM blobs WHERE refs <= 0 ORDER BY path")
+        .fetch_all(&db.0)
+        .await?;
+
+    Ok(rows.iter().map(|row| row.get(0)).collect())
+}
+
+// Stop counting a file so it can be deleted, unless something started using it
+// again. Returns whether it was claimed.
+pub async fn claim_blob(conn: &mut SqliteConnection, path: &str) -> Result<bool, sqlx::Error> {
+    let row = sqlx::query("DELETE FROM blobs WHERE path = ?1 AND refs <= 0 RETURNING path")
+        .bind(path)
+        .fetch_optional(conn)
+        .await?;
+
+    Ok(row.is_some())
+}
+
+pub async fn update_image_caption(
+    db: &Db,
+    image_id: i64,
+    caption: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        r#"
+        UPDATE modified_images SET caption = ?1, time_modified = CURRENT_TIMESTAMP WHERE id = ?2
+        "#,
+    )
+    .bind(caption)
+    .bind(image_id)
+    .execute(&db.0)
+    .await?;
+
+    Ok(())
+}
+
+pub async fn delete_user_session(db: &Db, session_token: &str) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        r#"
+        DELETE FROM sessions WHERE session_token = ?1
+        "#,
+    )
+    .bind(session_token)
+    .execute(&db.0)
+    .await?;
+
+    Ok(())
+}
+
+pub async fn enqueue_job(db: &Db, job: &models::Job) -> Result<i64, errors::AppError> {
+    let payload = serde_json::to_string(job)?;
+
+    let row = sqlx::query(
+        r#"
+        INSERT INTO jobs (payload) VALUES (?1) RETURNING id
+        "#,
+    )
+    .bind(payload)
+    .fetch_one(&db.0)
+    .await?;
+
+    Ok(row.get(0))
+}
+
+// Take the oldest queued job, if any. Claiming is a single UPDATE so two
+// workers can never get the same job.
+pub async fn claim_next_job(db: &Db) -> Result<Option<models::QueuedJob>, errors::AppError> {
+    let row = sqlx::query(
+        r#"
+        UPDATE jobs
+        SET status = 'running', attempts = attempts + 1, time_started = CURRENT_TIMESTAMP
+        WHERE id = (SELECT id FROM jobs WHERE status = 'queued' ORDER BY id LIMIT 1)
+        RETURNING id, attempts, payload
+        "#,
+    )
+    .fetch_optional(&db.0)
+    .await?;
+
+    let row = match row {
+        Some(row) => row,
+        None => return Ok(None),
+    };
+
+    let payload: String = row.get(2);
+
+    Ok(Some(models::QueuedJob {
+        id: row.get(0),
+        attempts: row.get(1),
+        job: serde_json::from_str(&payload)?,
+    }))
+}
+
+// Finished jobs aren't kept, only failures are worth looking at later
+pub async fn complete_job(db: &Db, job_id: i64) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        r#"
+        DELETE FROM jobs WHERE id = ?1
+        "#,
+    )
+    .bind(job_id)
+    .execute(&db.0)
+    .await?;
+
+    Ok(())
+}
+
+// Put a job back in the queue, or mark it failed once it has used up its attempts.
+// Returns true if the job will be retried.
+pub async fn fail_job(
+    db: &Db,
+    job_id: i64,
+    error: &str,
+    max_attempts: i64,
+) -> Result<bool, sqlx::Error> {
+    let row = sqlx::query(
+        r#"
+        UPDATE jobs
+        SET status = CASE WHEN attempts >= ?3 THEN 'failed' ELSE 'queued' END,
+            last_error = ?2,
+            time_finished = CURRENT_TIMESTAMP
+        WHERE id = ?1
+        RETURNING status
+        "#,
+    )
+    .bind(job_id)
+    .bind(error)
+    .bind(max_attempts)
+    .fetch_one(&db.0)
+    .await?;
+
+    let status: String = row.get(0);
+
+    Ok(status == "queued")
+}
+
+// Jobs left running when the server stopped will never finish, so queue them again
+pub async fn requeue_interrupted_jobs(db: &Db) -> Result<u64, sqlx::Error> {
+    let result = sqlx::query(
+        r#"
+        UPDATE jobs SET status = 'queued' WHERE status = 'running'
+        "#,
+    )
+    .execute(&db.0)
+    .await?;
+
+    Ok(result.rows_affected())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::db::migrations;
+    use rocket_db_pools::sqlx::sqlite::SqlitePoolOptions;
+
+    pub(crate) async fn test_db() -> Db {
+        // A single connection keeps every query on the same in-memory database
+        let pool = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        let db = Db(pool);
+        migrations::run(&db).await.unwrap();
+        db
+    }
+
+    pub(crate) async fn insert_test_user(db: &Db, email: &str) -> i64 {
+        sqlx::query(
+            r#"
+            INSERT INTO users (email, password, salt, verification_uuid, is_verified)
+            VALUES (?1, '', '', '', TRUE)
+            "#,
+        )
+        .bind(email)
+        .execute(&db.0)
+        .await
+        .unwrap()
+        .last_insert_rowid()
+    }
+
+    // Images made without uploading anything, for the tests that don't care
+    // about their bytes
+    pub(crate) async fn create_image(
+        db: &Db,
+        user_id: i64,
+        gallery_id: i64,
+        original_filename: &str,
+        caption: &str,
+        crop: Option<&models::Crop>,
+    ) -> Result<models::Image, sqlx::Error> {
+        let mut conn = db.0.acquire().await?;
+        let media_type = models::MediaType::Image;
+        insert_media(
+            &mut conn,
+            user_id,
+            gallery_id,
+            original_filename,
+            caption,
+            crop,
+            media_type,
+            None,
+        )
+        .await
+    }
+
+    pub(crate) async fn create_video(
+        db: &Db,
+        user_id: i64,
+        gallery_id: i64,
+        original_filename: &str,
+        caption: &str,
+    ) -> Result<models::Image, sqlx::Error> {
+        let mut conn = db.0.acquire().await?;
+        let media_type = models::MediaType::Video;
+        insert_media(
+            &mut conn,
+            user_id,
+            gallery_id,
+            original_filename,
+            caption,
+            None,
+            media_type,
+            None,
+        )
+        .await
+    }
+
+    // Store an image's original under a content hash, returning its new path
+    pub(crate) async fn save_content_hash(
+        db: &Db,
+        image_id: i64,
+        content_hash: &str,
+    ) -> Result<String, sqlx::Error> {
+        let path = blobs::blob_path(content_hash, None);
+        sqlx::query(
+            r#"
+            UPDATE original_images SET content_hash = ?2, path = ?3
+            WHERE id = (SELECT original_image_id FROM modified_images WHERE id = ?1)
+            "#,
+        )
+        .bind(image_id)
+        .bind(content_hash)
+        .bind(&path)
+        .execute(&db.0)
+        .await?;
+
+        Ok(path)
+    }
+
+    // An image saved the way it was before originals had their own paths, with
+    // both rows naming the image's file, as migration 15 leaves it. Returns the
+    // image's id and path.
+    pub(crate) async fn create_legacy_image(
+        db: &Db,
+        user_id: i64,
+        gallery_id: i64,
+    ) -> (i64, String) {
+        let path = models::ImgPath::new().path;
+        let original_image_id = sqlx::query(
+            r#"
+            INSERT INTO original_images (user_id, gallery_id, filename, path, is_legacy)
+            VALUES (?1, ?2, 'photo.jpg', ?3, TRUE)
+            "#,
+        )
+        .bind(user_id)
+        .bind(gallery_id)
+        .bind(&path)
+        .execute(&db.0)
+        .await
+        .unwrap()
+        .last_insert_rowid();
+        let image_id = sqlx::query(
+            r#"
+            INSERT INTO modified_images (user_id, original_image_id, path, caption, status)
+            VALUES (?1, ?2, ?3, '', 'public')
+            "#,
+        )
+        .bind(user_id)
+        .bind(original_image_id)
+        .bind(&path)
+        .execute(&db.0)
+        .await
+        .unwrap()
+        .last_insert_rowid();
+        (image_id, path)
+    }
+
+    async fn set_session_expiry(db: &Db, session_token: &str, expires_at: i64) {
+        sqlx::query("UPDATE sessions SET expires_at = ?1 WHERE session_token = ?2")
+            .bind(expires_at)
+            .bind(session_token)
+            .execute(&db.0)
+            .await
+            .unwrap();
+    }
+
+    async fn get_session_expiry(db: &Db, session_token: &str) -> i64 {
+        sqlx::query("SELECT expires_at FROM sessions WHERE session_token = ?1")
+            .bind(session_token)
+            .fetch_one(&db.0)
+            .await
+            .unwrap()
+            .get(0)
+    }
+
+    #[rocket::async_test]
+    async fn test_valid_session_returns_user() {
+        let db = test_db().await;
+        insert_test_user(&db, "john@example.com").await;
+        let token = create_user_session(&db, "john@example.com", 3600)
+            .await
+            .unwrap();
+
+        let user = get_user_from_session_token(&token, &db).await.unwrap();
+        assert_eq!(user.email, "john@example.com");
+    }
+
+    #[rocket::async_test]
+    async fn test_expired_session_is_rejected() {
+        let db = test_db().await;
+        insert_test_user(&db, "john@example.com").await;
+        let token = create_user_session(&db, "john@example.com", 3600)
+            .await
+            .unwrap();
+        set_session_expiry(&db, &token, chrono::Utc::now().timestamp() - 1).await;
+
+        let user = get_user_from_session_token(&token, &db).await;
+        assert!(matches!(user, Err(sqlx::Error::RowNotFound)));
+    }
+
+    #[rocket::async_test]
+    async fn test_session_renewed_inside_window() {
+        let db = test_db().await;
+        insert_test_user(&db, "john@example.com").await;
+        let token = create_user_session(&db, "john@example.com", 3600)
+            .await
+            .unwrap();
+        let nearly_expired = chrono::Utc::now().timestamp() + 60;
+        set_session_expiry(&db, &token, nearly_expired).await;
+
+        let renewed = renew_user_session(&db, &token, 600, 3600).await.unwrap();
+
+        assert!(renewed);
+        assert!(get_session_expiry(&db, &token).await > nearly_expired + 3000);
+    }
+
+    #[rocket::async_test]
+    async fn test_session_not_renewed_outside_window() {
+        let db = test_db().await;
+        insert_test_user(&db, "john@example.com").await;
+        let token = create_user_session(&db, "john@example.com", 3600)
+            .await
+            .unwrap();
+        let expires_at = get_session_expiry(&db, &token).await;
+
+        let renewed = renew_user_session(&db, &token, 600, 7200).await.unwrap();
+
+        assert!(!renewed);
+        assert_eq!(get_session_expiry(&db, &token).await, expires_at);
+    }
+
+    #[rocket::async_test]
+    async fn test_expired_session_is_not_renewed() {
+        let db = test_db().await;
+        insert_test_user(&db, "john@example.com").await;
+        let token = create_user_session(&db, "john@example.com", 3600)
+            .await
+            .unwrap();
+        set_session_expiry(&db, &token, chrono::Utc::now().timestamp() - 1).await;
+
+        let renewed = renew_user_session(&db, &token, 600, 3600).await.unwrap();
+
+        assert!(!renewed);
+    }
+
+    #[rocket::async_test]
+    async fn test_delete_expired_sessions() {
+        let db = test_db().await;
+        insert_test_user(&db, "john@example.com").await;
+        let live = create_user_session(&db, "john@example.com", 3600)
+            .await
+            .unwrap();
+        let expired = create_user_session(&db, "john@example.com", 3600)
+            .await
+            .unwrap();
+        set_session_expiry(&db, &expired, chrono::Utc::now().timestamp() - 1).await;
+
+        let purged = delete_expired_sessions(&db).await.unwrap();
+
+        assert_eq!(purged, 1);
+        assert!(get_user_from_session_token(&live, &db).await.is_ok());
+    }
+
+    #[rocket::async_test]
+    async fn test_session_of_unverified_user_is_rejected() {
+        let db = test_db().await;
+        insert_test_user(&db, "john@example.com").await;
+        let token = create_user_session(&db, "john@example.com", 3600)
+            .await
+            .unwrap();
+        sqlx::query("UPDATE users SET is_verified = FALSE")
+            .execute(&db.0)
+            .await
+            .unwrap();
+
+        assert!(get_user_from_session_token(&token, &db).await.is_err());
+    }
+
+    #[rocket::async_test]
+    async fn test_is_user_pending_only_for_unverified_users() {
+        let db = test_db().await;
+        insert_test_user(&db, "verified@example.com").await;
+        sqlx::query(
+            r#"
+            INSERT INTO users (email, password, salt, verification_uuid)
+            VALUES ('pending@example.com', '', '', 'uuid')
+            "#,
+        )
+        .execute(&db.0)
+        .await
+        .unwrap();
+
+        assert!(is_user_pending(&db, "pending@example.com").await.unwrap());
+        assert!(!is_user_pending(&db, "verified@example.com").await.unwrap());
+        assert!(!is_user_pending(&db, "nobody@example.com").await.unwrap());
+    }
+
+    #[rocket::async_test]
+    async fn test_approval_resend_is_limited() {
+        let db = test_db().await;
+        insert_test_user(&db, "pending@example.com").await;
+
+        assert!(claim_approval_resend(&db, "pending@example.com", 3600)
+            .await
+            .unwrap());
+        assert!(!claim_approval_resend(&db, "pending@example.com", 3600)
+            .await
+            .unwrap());
+
+        sqlx::query("UPDATE users SET approval_requested_at = approval_requested_at - 3600")
+            .execute(&db.0)
+            .await
+            .unwrap();
+        assert!(claim_approval_resend(&db, "pending@example.com", 3600)
+            .await
+            .unwrap());
+    }
+
+    #[rocket::async_test]
+    async fn test_make_admin() {
+        let db = test_db().await;
+        let user_id = insert_test_user(&db, "admin@example.com").await;
+        sqlx::query("UPDATE users SET is_verified = FALSE, is_disabled = TRUE")
+            .execute(&db.0)
+            .await
+            .unwrap();
+
+        assert!(make_admin(&db, "admin@example.com").await.unwrap());
+        assert!(!make_admin(&db, "nobody@example.com").await.unwrap());
+
+        let user = get_user_summary(&db, user_id).await.unwrap().unwrap();
+        assert_eq!(user.role, models::Role::Admin);
+        assert!(user.is_verified);
+        assert!(!user.is_disabled);
+    }
+
+    #[rocket::async_test]
+    async fn test_password_reset_is_limited() {
+        let db = test_db().await;
+        let user_id = insert_test_user(&db, "john@example.com").await;
+        let disabled = insert_test_user(&db, "jane@example.com").await;
+        set_user_disabled(&db, disabled, true).await.unwrap();
+
+        assert_eq!(
+            claim_password_reset(&db, "john@example.com", 3600)
+                .await
+                .unwrap(),
+            Some(user_id)
+        );
+        assert_eq!(
+            claim_password_reset(&db, "john@example.com", 3600)
+                .await
+                .unwrap(),
+            None
+        );
+        assert_eq!(
+            claim_password_reset(&db, "jane@example.com", 3600)
+                .await
+                .unwrap(),
+            None
+        );
+
+        sqlx::query(
+            "UPDATE users SET password_reset_requested_at = password_reset_requested_at - 3600",
+        )
+        .execute(&db.0)
+        .await
+        .unwrap();
+        assert_eq!(
+            claim_password_reset(&db, "john@example.com", 3600)
+                .await
+                .unwrap(),
+            Some(user_id)
+        );
+    }
+
+    #[rocket::async_test]
+    async fn test_password_reset_ip_is_limited() {
+        let db = test_db().await;
+
+        for _ in 0..2 {
+            assert!(claim_password_reset_ip(&db, "10.0.0.1", 3600, 2)
+                .await
+                .unwrap());
+        }
+        assert!(!claim_password_reset_ip(&db, "10.0.0.1", 3600, 2)
+            .await
+            .unwrap());
+        assert!(claim_password_reset_ip(&db, "10.0.0.2", 3600, 2)
+            .await
+            .unwrap());
+
+        sqlx::query("UPDATE password_reset_requests SET requested_at = requested_at - 3600")
+            .execute(&db.0)
+            .await
+            .unwrap();
+        assert!(claim_password_reset_ip(&db, "10.0.0.1", 3600, 2)
+            .await
+            .unwrap());
+    }
+
+    #[rocket::async_test]
+    async fn test_disabled_user_is_logged_out_and_rejected() {
+        let db = test_db().await;
+        let user_id = insert_test_user(&db, "john@example.com").await;
+        let token = create_user_session(&db, "john@example.com", 3600)
+            .await
+            .unwrap();
+
+        set_user_disabled(&db, user_id, true).await.unwrap();
+        assert!(get_user_from_session_token(&token, &db).await.is_err());
+        assert!(is_user_disabled(&db, "john@example.com").await.unwrap());
+
+        // A session created behind the admin's back is still refused
+        let token = create_user_session(&db, "john@example.com", 3600)
+            .await
+            .unwrap();
+        assert!(get_user_from_session_token(&token, &db).await.is_err());
+
+        set_user_disabled(&db, user_id, false).await.unwrap();
+        assert!(get_user_from_session_token(&token, &db).await.is_ok());
+    }
+
+    #[rocket::async_test]
+    async fn test_set_user_role() {
+        let db = test_db().await;
+        let user_id = insert_test_user(&db, "john@example.com").await;
+        let token = create_user_session(&db, "john@example.com", 3600)
+            .await
+            .unwrap();
+
+        set_user_role(&db, user_id, &models::Role::Writer)
+            .await
+            .unwrap();
+
+        let user = get_user_from_session_token(&token, &db).await.unwrap();
+        assert_eq!(user.role, models::Role::Writer);
+    }
+
+    #[rocket::async_test]
+    async fn test_approving_user_uses_up_signup_links() {
+        let db = test_db().await;
+        let user_id = insert_test_user(&db, "john@example.com").await;
+        sqlx::query("UPDATE users SET is_verified = FALSE")
+            .execute(&db.0)
+            .await
+            .unwrap();
+        let token = create_verification_token(&db, user_id, constants::SIGNUP_TOKEN)
+            .await
+            .unwrap();
+
+        let email = verify_user(&db, user_id).await.unwrap();
+
+        assert_eq!(email.as_deref(), Some("john@example.com"));
+        assert!(is_user_verified(&db, "john@example.com").await.unwrap());
+        assert_eq!(
+            check_verification_token(&db, &token, constants::SIGNUP_TOKEN, 3600)
+                .await
+                .unwrap(),
+            models::TokenStatus::Used
+        );
+    }
+
+    #[rocket::async_test]
+    async fn test_verify_user_only_verifies_once() {
+        let db = test_db().await;
+        let user_id = insert_test_user(&db, "john@example.com").await;
+
+        assert_eq!(verify_user(&db, user_id).await.unwrap(), None);
+        assert_eq!(verify_user(&db, user_id + 1).await.unwrap(), None);
+    }
+
+    #[rocket::async_test]
+    async fn test_delete_user_sessions() {
+        let db = test_db().await;
+        let user_id = insert_test_user(&db, "john@example.com").await;
+        insert_test_user(&db, "jane@example.com").await;
+        create_user_session(&db, "john@example.com", 3600)
+            .await
+            .unwrap();
+        create_user_session(&db, "john@example.com", 3600)
+            .await
+            .unwrap();
+        let other = create_user_session(&db, "jane@example.com", 3600)
+            .await
+            .unwrap();
+
+        let summary = get_user_summary(&db, user_id).await.unwrap().unwrap();
+        assert_eq!(summary.session_count, 2);
+
+        assert_eq!(delete_user_sessions(&db, user_id).await.unwrap(), 2);
+
+        let summary = get_user_summary(&db, user_id).await.unwrap().unwrap();
+        assert_eq!(summary.session_count, 0);
+        assert!(get_user_from_session_token(&other, &db).await.is_ok());
+    }
+
+    #[rocket::async_test]
+    async fn test_get_users_lists_pending_first() {
+        let db = test_db().await;
+        insert_test_user(&db, "alice@example.com").await;
+        sqlx::query(
+            r#"
+            INSERT INTO users (email, password, salt, verification_uuid)
+            VALUES ('zed@example.com', '', '', 'uuid')
+            "#,
+        )
+        .execute(&db.0)
+        .await
+        .unwrap();
+
+        let users = get_users(&db).await.unwrap();
+
+        let emails: Vec<&str> = users.iter().map(|user| user.email.as_str()).collect();
+        assert_eq!(emails, vec!["zed@example.com", "alice@example.com"]);
+        assert!(!users[0].is_verified);
+        assert!(!users[0].is_disabled);
+    }
+
+    #[rocket::async_test]
+    async fn test_verification_token_is_single_use() {
+        let db = test_db().await;
+        let user_id = insert_test_user(&db, "john@example.com").await;
+        let token = create_verification_token(&db, user_id, constants::SIGNUP_TOKEN)
+            .await
+            .unwrap();
+
+        let first = consume_verification_token(&db, &token, constants::SIGNUP_TOKEN, 3600)
+            .await
+            .unwrap();
+        let second = consume_verification_token(&db, &token, constants::SIGNUP_TOKEN, 3600)
+            .await
+            .unwrap();
+
+        assert_eq!(first, models::TokenStatus::Valid(user_id));
+        assert_eq!(second, models::TokenStatus::Used);
+    }
+
+    #[rocket::async_test]
+    async fn test_verification_token_expires() {
+        let db = test_db().await;
+        let user_id = insert_test_user(&db, "john@example.com").await;
+        let token = create_verification_token(&db, user_id, constants::SIGNUP_TOKEN)
+            .await
+            .unwrap();
+        sqlx::query("UPDATE verification_tokens SET time_created = datetime('now', '-2 hours')")
+            .execute(&db.0)
+            .await
+            .unwrap();
+
+        let status = consume_verification_token(&db, &token, constants::SIGNUP_TOKEN, 3600)
+            .await
+            .unwrap();
+
+        assert_eq!(status, models::TokenStatus::Expired);
+    }
+
+    #[rocket::async_test]
+    async fn test_verification_token_checks_purpose() {
+        let db = test_db().await;
+        let user_id = insert_test_user(&db, "john@example.com").await;
+        let token = create_verification_token(&db, user_id, constants::SIGNUP_TOKEN)
+            .await
+            .unwrap();
+
+        let status = consume_verification_token(&db, &token, "something_else", 3600)
+            .await
+            .unwrap();
+
+        assert_eq!(status, models::TokenStatus::NotFound);
+    }
+
+    #[rocket::async_test]
+    async fn test_legacy_signup_link_has_expired() {
+        let db = test_db().await;
+        sqlx::query(
+            r#"
+            INSERT INTO users (email, password, salt, verification_uuid)
+            VALUES ('pending@example.com', '', '', 'old-uuid')
+            "#,
+        )
+        .execute(&db.0)
+        .await
+        .unwrap();
+
+        let signup = consume_verification_token(&db, "old-uuid", constants::SIGNUP_TOKEN, 3600)
+            .await
+            .unwrap();
+        let reset =
+            consume_verification_token(&db, "old-uuid", constants::PASSWORD_RESET_TOKEN, 3600)
+                .await
+                .unwrap();
+
+        assert_eq!(signup, models::TokenStatus::Expired);
+        assert_eq!(reset, models::TokenStatus::NotFound);
+    }
+
+    #[rocket::async_test]
+    async fn test_new_verification_token_replaces_unused_one() {
+        let db = test_db().await;
+        let user_id = insert_test_user(&db, "john@example.com").await;
+        let old = create_verification_token(&db, user_id, constants::SIGNUP_TOKEN)
+            .await
+            .unwrap();
+        let new = create_verification_token(&db, user_id, constants::SIGNUP_TOKEN)
+            .await
+            .unwrap();
+
+        let old_status = check_verification_token(&db, &old, constants::SIGNUP_TOKEN, 3600)
+            .await
+            .unwrap();
+        let new_status = check_verification_token(&db, &new, constants::SIGNUP_TOKEN, 3600)
+            .await
+            .unwrap();
+
+        assert_eq!(old_status, models::TokenStatus::NotFound);
+        assert_eq!(new_status, models::TokenStatus::Valid(user_id));
+    }
+
+    #[rocket::async_test]
+    async fn test_reset_password_changes_password_and_ends_sessions() {
+        let db = test_db().await;
+        let user_id = insert_test_user(&db, "john@example.com").await;
+        let token = create_user_session(&db, "john@example.com", 3600)
+            .await
+            .unwrap();
+
+        reset_password(&db, user_id, "new password").await.unwrap();
+
+        assert!(verify_password(&db, "john@example.com", "new password")
+            .await
+            .unwrap());
+        assert!(get_user_from_session_token(&token, &db).await.is_err());
+    }
+
+    fn test_user(id: i64, role: models::Role) -> models::User {
+        models::User {
+            id,
+            email: format!("user{}@example.com", id),
+            role,
+        }
+    }
+
+    async fn visible_gallery_ids(db: &Db, user: &models::User) -> Vec<i64> {
+        get_galleries(db, user)
+            .await
+            .unwrap()
+            .iter()
+            .map(|gallery| gallery.id)
+            .collect()
+    }
+
+    #[rocket::async_test]
+    async fn test_gallery_visibility_rules() {
+        let db = test_db().await;
+        let owner = test_user(
+            insert_test_user(&db, "owner@example.com").await,
+            models::Role::Writer,
+        );
+        let writer = test_user(
+            insert_test_user(&db, "writer@example.com").await,
+            models::Role::Writer,
+        );
+        let reader = test_user(
+            insert_test_user(&db, "reader@example.com").await,
+            models::Role::Reader,
+        );
+        let member = test_user(
+            insert_test_user(&db, "member@example.com").await,
+            models::Role::Reader,
+        );
+
+        let public = create_gallery(&db, owner.id, "public").await.unwrap();
+        let family = create_gallery(&db, owner.id, "family").await.unwrap();
+        let shared = create_gallery(&db, owner.id, "shared").await.unwrap();
+        let private = create_gallery(&db, owner.id, "private").await.unwrap();
+        let deleted = create_gallery(&db, owner.id, "deleted").await.unwrap();
+        update_gallery_visibility(&db, family, models::Visibility::Family)
+            .await
+            .unwrap();
+        update_gallery_visibility(&db, shared, models::Visibility::Shared)
+            .await
+            .unwrap();
+        update_gallery_visibility(&db, private, models::Visibility::Private)
+            .await
+            .unwrap();
+        add_gallery_member(&db, shared, "member@example.com")
+            .await
+            .unwrap();
+        delete_gallery(&db, deleted, owner.id).await.unwrap();
+
+        let mut owner_sees = visible_gallery_ids(&db, &owner).await;
+        owner_sees.sort();
+        assert_eq!(owner_sees, vec![public, family, shared, private]);
+
+        let mut writer_sees = visible_gallery_ids(&db, &writer).await;
+        writer_sees.sort();
+        assert_eq!(writer_sees, vec![public, family]);
+
+        assert_eq!(visible_gallery_ids(&db, &reader).await, vec![public]);
+
+        let mut member_sees = visible_gallery_ids(&db, &member).await;
+        member_sees.sort();
+        assert_eq!(member_sees, vec![public, shared]);
+    }
+
+    #[rocket::async_test]
+    async fn test_hidden_gallery_is_not_found() {
+        let db = test_db().await;
+        let owner = test_user(
+            insert_test_user(&db, "owner@example.com").await,
+            models::Role::Writer,
+        );
+        let reader = test_user(
+            insert_test_user(&db, "reader@example.com").await,
+            models::Role::Reader,
+        );
+        let private = create_gallery(&db, owner.id, "private").await.unwrap();
+        update_gallery_visibility(&db, private, models::Visibility::Private)
+            .await
+            .unwrap();
+
+        let gallery = get_gallery(&db, private, &owner).await.unwrap();
+        assert_eq!(gallery.visibility, models::Visibility::Private);
+
+        let error = get_gallery(&db, private, &reader).await.unwrap_err();
+        assert_eq!(error.code, 404);
+    }
+
+    #[rocket::async_test]
+    async fn test_add_gallery_member_only_adds_active_users() {
+        let db = test_db().await;
+        let owner_id = insert_test_user(&db, "owner@example.com").await;
+        let gallery_id = create_gallery(&db, owner_id, "shared").await.unwrap();
+        let unverified = insert_test_user(&db, "unverified@example.com").await;
+        let disabled = insert_test_user(&db, "disabled@example.com").await;
+        sqlx::query("UPDATE users SET is_verified = FALSE WHERE id = ?1")
+            .bind(unverified)
+            .execute(&db.0)
+            .await
+            .unwrap();
+        sqlx::query("UPDATE users SET is_disabled = TRUE WHERE id = ?1")
+            .bind(disabled)
+            .execute(&db.0)
+            .await
+            .unwrap();
+
+        for email in [
+            "nobody@example.com",
+            "unverified@example.com",
+            "disabled@example.com",
+        ] {
+            add_gallery_member(&db, gallery_id, email).await.unwrap();
+        }
+
+        assert!(get_gallery_members(&db, gallery_id)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[rocket::async_test]
+    async fn test_only_owner_can_edit_gallery() {
+        let db = test_db().await;
+        let owner = test_user(
+            insert_test_user(&db, "owner@example.com").await,
+            models::Role::Writer,
+        );
+        let writer = test_user(
+            insert_test_user(&db, "writer@example.com").await,
+            models::Role::Writer,
+        );
+        let gallery_id = create_gallery(&db, owner.id, "private").await.unwrap();
+
+        assert!(can_edit_gallery(&db, &owner, gallery_id).await.unwrap());
+        assert!(!can_edit_gallery(&db, &writer, gallery_id).await.unwrap());
+    }
+
+    #[rocket::async_test]
+    async fn test_image_file_follows_gallery_visibility() {
+        let db = test_db().await;
+        let owner = test_user(
+            insert_test_user(&db, "owner@example.com").await,
+            models::Role::Writer,
+        );
+        let reader = test_user(
+            insert_test_user(&db, "reader@example.com").await,
+            models::Role::Reader,
+        );
+        let gallery_id = create_gallery(&db, owner.id, "gallery").await.unwrap();
+        let image = create_image(&db, owner.id, gallery_id, "photo.jpg", "", None)
+            .await
+            .unwrap();
+
+        assert!(can_view_image_file(&db, &reader, &image.path)
+            .await
+            .unwrap());
+
+        update_gallery_visibility(&db, gallery_id, models::Visibility::Private)
+            .await
+            .unwrap();
+
+        assert!(can_view_image_file(&db, &owner, &image.path).await.unwrap());
+        assert!(!can_view_image_file(&db, &reader, &image.path)
+            .await
+            .unwrap());
+    }
+
+    #[rocket::async_test]
+    async fn test_original_files_are_never_served() {
+        let db = test_db().await;
+        let owner = test_user(
+            insert_test_user(&db, "owner@example.com").await,
+            models::Role::Writer,
+        );
+        let gallery_id = create_gallery(&db, owner.id, "gallery").await.unwrap();
+        let image = create_image(&db, owner.id, gallery_id, "photo.jpg", "", None)
+            .await
+            .unwrap();
+
+        assert!(
+            !can_view_image_file(&db, &owner, &image.original_path.unwrap())
+                .await
+                .unwrap()
+        );
+        assert!(
+            !can_view_image_file(&db, &test_user(0, models::Role::Admin), "./img/x")
+                .await
+                .unwrap()
+        );
+    }
+
+    #[rocket::async_test]
+    async fn test_original_image_path_is_recorded() {
+        let db = test_db().await;
+        let owner_id = insert_test_user(&db, "owner@example.com").await;
+        let gallery_id = create_gallery(&db, owner_id, "gallery").await.unwrap();
+
+        let image = create_image(&db, owner_id, gallery_id, "photo.jpg", "", None)
+            .await
+            .unwrap();
+
+        let row = sqlx::query("SELECT path FROM original_images")
+            .fetch_one(&db.0)
+            .await
+            .unwrap();
+        let original_path: String = row.get(0);
+        assert_eq!(Some(original_path), image.original_path);
+        assert_ne!(image.original_path, Some(image.path));
+    }
+
+    #[rocket::async_test]
+    async fn test_crop_is_recorded() {
+        let db = test_db().await;
+        let owner_id = insert_test_user(&db, "owner@example.com").await;
+        let gallery_id = create_gallery(&db, owner_id, "gallery").await.unwrap();
+        let crop = models::Crop {
+            x: 10,
+            y: 20,
+            width: 300,
+            height: 200,
+            rotation: models::Rotation::Quarter,
+        };
+
+        let image = create_image(&db, owner_id, gallery_id, "photo.jpg", "", Some(&crop))
+            .await
+            .unwrap();
+
+        let row = sqlx::query(
+            "SELECT crop_x, crop_y, crop_width, crop_height, rotation FROM modified_images WHERE id = ?1",
+        )
+        .bind(image.id)
+        .fetch_one(&db.0)
+        .await
+        .unwrap();
+        let stored: (i64, i64, i64, i64, i64) =
+            (row.get(0), row.get(1), row.get(2), row.get(3), row.get(4));
+        assert_eq!(stored, (10, 20, 300, 200, 90));
+    }
+
+    async fn age_deletions(db: &Db) {
+        for table in ["galleries", "modified_images"] {
+            sqlx::query(&format!(
+                "UPDATE {} SET time_deleted = datetime('now', '-10 days') WHERE time_deleted IS NOT NULL",
+                table
+            ))
+            .execute(&db.0)
+            .await
+            .unwrap();
+        }
+    }
+
+    #[rocket::async_test]
+    async fn test_purge_deleted_images() {
+        let db = test_db().await;
+        let owner_id = insert_test_user(&db, "owner@example.com").await;
+        let gallery_id = create_gallery(&db, owner_id, "gallery").await.unwrap();
+        let deleted_gallery_id = create_gallery(&db, owner_id, "deleted").await.unwrap();
+        let kept = create_image(&db, owner_id, gallery_id, "kept.jpg", "", None)
+            .await
+            .unwrap();
+        let edited = create_image(&db, owner_id, gallery_id, "edited.jpg", "", None)
+            .await
+            .unwrap();
+        let crop = models::Crop::whole();
+        let edit = edit_image(&db, owner_id, edited.id, &crop)
+            .await
+            .unwrap()
+            .unwrap();
+        let in_deleted_gallery =
+            create_image(&db, owner_id, deleted_gallery_id, "photo.jpg", "", None)
+                .await
+                .unwrap();
+        save_image_metadata(
+            &db,
+            in_deleted_gallery.id,
+            &models::ImageMetadata::default(),
+        )
+        .await
+        .unwrap();
+
+        delete_gallery(&db, deleted_gallery_id, owner_id)
+            .await
+            .unwrap();
+        assert_eq!(purge_deleted_images(&db, 5).await.unwrap(), 0);
+        assert!(get_unreferenced_blobs(&db).await.unwrap().is_empty());
+
+        // The version replaced by an edit stays while the edit is shown
+        age_deletions(&db).await;
+        assert_eq!(purge_deleted_images(&db, 5).await.unwrap(), 1);
+        let mut expected = vec![
+            in_deleted_gallery.original_path.unwrap(),
+            in_deleted_gallery.path,
+        ];
+        expected.sort();
+        assert_eq!(get_unreferenced_blobs(&db).await.unwrap(), expected);
+        for path in &expected {
+            assert!(claim_blob(&mut db.0.acquire().await.unwrap(), path)
+                .await
+                .unwrap());
+        }
+
+        delete_image(&db, edit.id, owner_id).await.unwrap();
+        age_deletions(&db).await;
+        assert_eq!(purge_deleted_images(&db, 5).await.unwrap(), 1);
+        let paths = get_unreferenced_blobs(&db).await.unwrap();
+        assert_eq!(paths.len(), 3);
+        for path in [edited.original_path.unwrap(), edited.path, edit.path] {
+            assert!(paths.contains(&path));
+        }
+        assert!(!paths.contains(&kept.path));
+        assert_eq!(purge_deleted_images(&db, 5).await.unwrap(), 0);
+
+        // The deleted gallery goes once its images have
+        assert_eq!(purge_deleted_galleries(&db, 20).await.unwrap(), 0);
+        assert_eq!(purge_deleted_galleries(&db, 5).await.unwrap(), 1);
+        assert_eq!(purge_deleted_galleries(&db, 5).await.unwrap(), 0);
+    }
+
+    #[rocket::async_test]
+    async fn test_purged_images_lose_their_jobs() {
+        let db = test_db().await;
+        let owner_id = insert_test_user(&db, "owner@example.com").await;
+        let gallery_id = create_gallery(&db, owner_id, "gallery").await.unwrap();
+        let kept = create_image(&db, owner_id, gallery_id, "kept.jpg", "", None)
+            .await
+            .unwrap();
+        let purged = create_image(&db, owner_id, gallery_id, "purged.jpg", "", None)
+            .await
+            .unwrap();
+        let mut kept_jobs = vec![];
+        for image in [&kept, &purged] {
+            for status in ["queued", "failed"] {
+                let job = models::Job::ReadMetadata {
+                    image_id: image.id,
+                    original_path: image.original_path.clone().unwrap(),
+                };
+                let job_id = enqueue_job(&db, &job).await.unwrap();
+                sqlx::query("UPDATE jobs SET status = ?1 WHERE id = ?2")
+                    .bind(status)
+                    .bind(job_id)
+                    .execute(&db.0)
+                    .await
+                    .unwrap();
+                if image.id == kept.id {
+                    kept_jobs.push(job_id);
+                }
+            }
+        }
+
+        delete_image(&db, purged.id, owner_id).await.unwrap();
+        age_deletions(&db).await;
+        assert_eq!(purge_deleted_images(&db, 5).await.unwrap(), 1);
+
+        let jobs: Vec<i64> = sqlx::query_scalar("SELECT id FROM jobs ORDER BY id")
+            .fetch_all(&db.0)
+            .await
+            .unwrap();
+        assert_eq!(jobs, kept_jobs);
+    }
+
+    async fn blob_refs(db: &Db, path: &str) -> i64 {
+        sqlx::query("SELECT refs FROM blobs WHERE path = ?1")
+            .bind(path)
+            .fetch_one(&db.0)
+            .await
+            .unwrap()
+            .get(0)
+    }
+
+    #[rocket::async_test]
+    async fn test_shared_files_are_counted() {
+        let db = test_db().await;
+        let owner_id = insert_test_user(&db, "owner@example.com").await;
+        let gallery_id = create_gallery(&db, owner_id, "gallery").await.unwrap();
+        let other_gallery_id = create_gallery(&db, owner_id, "other").await.unwrap();
+        let hash = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+
+        // The same photo in two galleries, cropped the same way
+        let mut images = vec![];
+        for gallery_id in [gallery_id, other_gallery_id] {
+            let image = create_image(&db, owner_id, gallery_id, "photo.jpg", "", None)
+                .await
+                .unwrap();
+            let original_path = save_content_hash(&db, image.id, hash).await.unwrap();
+            assert_eq!(original_path, blobs::blob_path(hash, None));
+            set_image_path(&db, image.id, &blobs::blob_path(hash, Some("jpg")))
+                .await
+                .unwrap();
+            images.push(image);
+        }
+        assert_eq!(blob_refs(&db, &blobs::blob_path(hash, None)).await, 2);
+
+        // The paths made up before the files were stored are no longer used
+        let mut unused = vec![];
+        for image in &images {
+            unused.push(image.path.clone());
+            unused.push(image.original_path.clone().unwrap());
+        }
+        unused.sort();
+        assert_eq!(get_unreferenced_blobs(&db).await.unwrap(), unused);
+
+        delete_gallery(&db, other_gallery_id, owner_id)
+            .await
+            .unwrap();
+        age_deletions(&db).await;
+        assert_eq!(purge_deleted_images(&db, 5).await.unwrap(), 1);
+        for path in [
+            blobs::blob_path(hash, None),
+            blobs::blob_path(hash, Some("jpg")),
+        ] {
+            assert_eq!(blob_refs(&db, &path).await, 1);
+            assert!(!claim_blob(&mut db.0.acquire().await.unwrap(), &path)
+                .await
+                .unwrap());
+        }
+
+        delete_image(&db, images[0].id, owner_id).await.unwrap();
+        age_deletions(&db).await;
+        purge_deleted_images(&db, 5).await.unwrap();
+        let unreferenced = get_unreferenced_blobs(&db).await.unwrap();
+        assert!(unreferenced.contains(&blobs::blob_path(hash, None)));
+        assert!(unreferenced.contains(&blobs::blob_path(hash, Some("jpg"))));
+    }
+
+    #[rocket::async_test]
+    async fn test_edit_image_keeps_history() {
+        let db = test_db().await;
+        let owner = test_user(
+            insert_test_user(&db, "owner@example.com").await,
+            models::Role::Writer,
+        );
+        let gallery_id = create_gallery(&db, owner.id, "gallery").await.unwrap();
+        let image = create_image(&db, owner.id, gallery_id, "photo.jpg", "Caption", None)
+            .await
+            .unwrap();
+        set_image_status(&db, image.id, models::ImageStatus::Ready)
+            .await
+            .unwrap();
+        let crop = models::Crop {
+            x: 5,
+            y: 6,
+            width: 70,
+            height: 80,
+            rotation: models::Rotation::Half,
+        };
+
+        let edited = edit_image(&db, owner.id, image.id, &crop)
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_ne!(edited.id, image.id);
+        assert_ne!(edited.path, image.path);
+        assert_eq!(edited.original_path, image.original_path);
+        assert_eq!(edited.caption, "Caption");
+
+        // Only the new version is shown, but both are kept
+        let gallery = get_gallery(&db, gallery_id, &owner).await.unwrap();
+        let ids: Vec<i64> = gallery.images.iter().map(|image| image.id).collect();
+        assert_eq!(ids, vec![edited.id]);
+        let versions = get_image_versions(&db, edited.id).await.unwrap();
+        let ids: Vec<(i64, bool)> = versions.iter().map(|v| (v.id, v.is_current)).collect();
+        assert_eq!(ids, vec![(edited.id, true), (image.id, false)]);
+
+        let source = get_image_source(&db, gallery_id, edited.id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(Some(source.original_path), image.original_path);
+        assert_eq!(source.crop, Some(crop));
+
+        // Old versions can't be edited or brought back by a late job
+        assert!(edit_image(&db, owner.id, image.id, &crop)
+            .await
+            .unwrap()
+            .is_none());
+        assert!(get_image_source(&db, gallery_id, image.id)
+            .await
+            .unwrap()
+            .is_none());
+        set_image_status(&db, image.id, models::ImageStatus::Ready)
+            .await
+            .unwrap();
+        let gallery = get_gallery(&db, gallery_id, &owner).await.unwrap();
+        assert_eq!(gallery.images.len(), 1);
+    }
+
+    #[rocket::async_test]
+    async fn test_legacy_originals_are_relinked() {
+        let db = test_db().await;
+        let owner_id = insert_test_user(&db, "owner@example.com").await;
+        let gallery_id = create_gallery(&db, owner_id, "gallery").await.unwrap();
+        create_image(&db, owner_id, gallery_id, "photo.jpg", "", None)
+            .await
+            .unwrap();
+        let (image_id, path) = create_legacy_image(&db, owner_id, gallery_id).await;
+
+        let source = get_image_source(&db, gallery_id, image_id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(source.is_legacy);
+        assert_eq!(source.original_path, path);
+        let legacy = get_legacy_originals(&db).await.unwrap();
+        assert_eq!(legacy.len(), 1);
+        assert_eq!(legacy[0].image_id, image_id);
+        assert_eq!(legacy[0].path, path);
+        assert_eq!(blob_refs(&db, &path).await, 2);
+
+        let original_path = models::ImgPath::new().original_path;
+        assert!(relink_original(&db, legacy[0].id, &original_path)
+            .await
+            .unwrap());
+        assert!(!relink_original(&db, legacy[0].id, &path).await.unwrap());
+
+        let source = get_image_source(&db, gallery_id, image_id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(!source.is_legacy);
+        assert_eq!(source.original_path, original_path);
+        assert!(get_legacy_originals(&db).await.unwrap().is_empty());
+        assert_eq!(blob_refs(&db, &path).await, 1);
+        assert_eq!(blob_refs(&db, &original_path).await, 1);
+    }
+
+    #[rocket::async_test]
+    async fn test_image_source_needs_the_right_gallery() {
+        let db = test_db().await;
+        let owner_id = insert_test_user(&db, "owner@example.com").await;
+        let gallery_id = create_gallery(&db, owner_id, "gallery").await.unwrap();
+        let other_gallery_id = create_gallery(&db, owner_id, "other").await.unwrap();
+        let image = create_image(&db, owner_id, gallery_id, "photo.jpg", "", None)
+            .await
+            .unwrap();
+
+        let source = get_image_source(&db, gallery_id, image.id)
+            .await
+            .unwrap()
+            .unwrap();
+        // Cropped in the browser, before crops were stored
+        assert_eq!(source.crop, None);
+        assert!(get_image_source(&db, other_gallery_id, image.id)
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[rocket::async_test]
+    async fn test_videos_are_listed_with_images() {
+        let db = test_db().await;
+        let owner_id = insert_test_user(&db, "owner@example.com").await;
+        let owner = test_user(owner_id, models::Role::Writer);
+        let gallery_id = create_gallery(&db, owner_id, "gallery").await.unwrap();
+        let image = create_image(&db, owner_id, gallery_id, "photo.jpg", "", None)
+            .await
+            .unwrap();
+        let video = create_video(&db, owner_id, gallery_id, "clip.mov", "Clip")
+            .await
+            .unwrap();
+        assert!(video.path.ends_with(".mp4"));
+
+        let gallery = get_gallery(&db, gallery_id, &owner).await.unwrap();
+        let media_types: Vec<_> = gallery.images.iter().map(|i| i.media_type).collect();
+        assert_eq!(
+            media_types,
+            vec![models::MediaType::Image, models::MediaType::Video]
+        );
+        let tile = get_gallery_image(&db, gallery_id, video.id, &owner)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(tile.media_type, models::MediaType::Video);
+        assert!(can_view_image_file(&db, &owner, &video.path).await.unwrap());
+
+        // Only images can be cropped again
+        assert!(get_image_source(&db, gallery_id, image.id)
+            .await
+            .unwrap()
+            .is_some());
+        assert!(get_image_source(&db, gallery_id, video.id)
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[rocket::async_test]
+    async fn test_chunked_upload() {
+        let db = test_db().await;
+        let owner_id = insert_test_user(&db, "owner@example.com").await;
+        let other_id = insert_test_user(&db, "other@example.com").await;
+        let gallery_id = create_gallery(&db, owner_id, "gallery").await.unwrap();
+
+        let upload = create_upload(&db, owner_id, gallery_id, "holiday.jpg", 100)
+            .await
+            .unwrap();
+        assert_eq!(upload.received, 0);
+        assert_eq!(
+            upload.part_path("./uploads"),
+            format!("./uploads/{}.part", upload.id)
+        );
+
+        assert!(advance_upload(&db, &upload.id, 0, 60).await.unwrap());
+        // The same chunk sent again, or one from the wrong place, doesn't count
+        assert!(!advance_upload(&db, &upload.id, 0, 60).await.unwrap());
+        // Nor does going past the end of the file
+        assert!(!advance_upload(&db, &upload.id, 60, 101).await.unwrap());
+        assert!(advance_upload(&db, &upload.id, 60, 100).await.unwrap());
+
+        let stored = get_upload(&db, &upload.id, owner_id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(stored.received, 100);
+        assert_eq!(stored.file_name, "holiday.jpg");
+        assert!(get_upload(&db, &upload.id, other_id)
+            .await
+            .unwrap()
+            .is_none());
+
+        delete_upload(&db, &upload.id).await.unwrap();
+        assert!(get_upload(&db, &upload.id, owner_id)
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[rocket::async_test]
+    async fn test_upload_is_claimed_once() {
+        let db = test_db().await;
+        let owner_id = insert_test_user(&db, "owner@example.com").await;
+        let gallery_id = create_gallery(&db, owner_id, "gallery").await.unwrap();
+        let upload = create_upload(&db, owner_id, gallery_id, "holiday.jpg", 100)
+            .await
+            .unwrap();
+
+        // Not until it has all arrived
+        assert!(!claim_upload(&db, &upload.id).await.unwrap());
+        assert!(advance_upload(&db, &upload.id, 0, 100).await.unwrap());
+        assert!(claim_upload(&db, &upload.id).await.unwrap());
+        assert!(!claim_upload(&db, &upload.id).await.unwrap());
+
+        let upload = models::Upload {
+            received: 100,
+            ..upload
+        };
+        restore_upload(&db, &upload, owner_id).await.unwrap();
+        let restored = get_upload(&db, &upload.id, owner_id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(restored.received, 100);
+        assert!(claim_upload(&db, &upload.id).await.unwrap());
+    }
+
+    #[rocket::async_test]
+    async fn test_stale_uploads_are_deleted() {
+        let db = test_db().await;
+        let owner_id = insert_test_user(&db, "owner@example.com").await;
+        let gallery_id = create_gallery(&db, owner_id, "gallery").await.unwrap();
+        let stale = create_upload(&db, owner_id, gallery_id, "old.jpg", 10)
+            .await
+            .unwrap();
+        let fresh = create_upload(&db, owner_id, gallery_id, "new.jpg", 10)
+            .await
+            .unwrap();
+        sqlx::query("UPDATE uploads SET time_modified = datetime('now', '-2 days') WHERE id = ?1")
+            .bind(&stale.id)
+            .execute(&db.0)
+            .await
+            .unwrap();
+
+        let deleted = delete_stale_uploads(&db, 60 * 60 * 24).await.unwrap();
+
+        assert_eq!(deleted, vec![stale]);
+        assert!(get_upload(&db, &fresh.id, owner_id)
+            .await
+            .unwrap()
+            .is_some());
+    }
+
+    #[rocket::async_test]
+    async fn test_deleted_image_files_are_not_served() {
+        let db = test_db().await;
+        let owner = test_user(
+            insert_test_user(&db, "owner@example.com").await,
+            models::Role::Writer,
+        );
+        let reader = test_user(
+            insert_test_user(&db, "reader@example.com").await,
+            models::Role::Reader,
+        );
+        let gallery_id = create_gallery(&db, owner.id, "gallery").await.unwrap();
+        let image = create_image(&db, owner.id, gallery_id, "photo.jpg", "", None)
+            .await
+            .unwrap();
+
+        assert!(can_view_image_file(&db, &reader, &image.path)
+            .await
+            .unwrap());
+
+        delete_image(&db, image.id, owner.id).await.unwrap();
+
+        assert!(!can_view_image_file(&db, &reader, &image.path)
+            .await
+            .unwrap());
+        // Still shown in the trash to whoever can restore it
+        assert!(can_view_image_file(&db, &owner, &image.path).await.unwrap());
+    }
+
+    #[rocket::async_test]
+    async fn test_files_of_deleted_gallery_are_not_served() {
+        let db = test_db().await;
+        let owner = test_user(
+            insert_test_user(&db, "owner@example.com").await,
+            models::Role::Writer,
+        );
+        let reader = test_user(
+            insert_test_user(&db, "reader@example.com").await,
+            models::Role::Reader,
+        );
+        let gallery_id = create_gallery(&db, owner.id, "gallery").await.unwrap();
+        let image = create_image(&db, owner.id, gallery_id, "photo.jpg", "", None)
+            .await
+            .unwrap();
+
+        delete_gallery(&db, gallery_id, owner.id).await.unwrap();
+
+        assert!(!can_view_image_file(&db, &reader, &image.path)
+            .await
+            .unwrap());
+        assert!(can_view_image_file(&db, &owner, &image.path).await.unwrap());
+    }
+
+    #[rocket::async_test]
+    async fn test_unknown_files_are_not_served() {
+        let db = test_db().await;
+        let owner = test_user(
+            insert_test_user(&db, "owner@example.com").await,
+            models::Role::Writer,
+        );
+
+        assert!(!can_view_image_file(&db, &owner, "./img/not-an-image")
+            .await
+            .unwrap());
+    }
+
+    #[rocket::async_test]
+    async fn test_gallery_edit_permissions() {
+        let db = test_db().await;
+        let owner = test_user(
+            insert_test_user(&db, "owner@example.com").await,
+            models::Role::Writer,
+        );
+        let writer = test_user(
+            insert_test_user(&db, "writer@example.com").await,
+            models::Role::Writer,
+        );
+        let reader = test_user(
+            insert_test_user(&db, "reader@example.com").await,
+            models::Role::Reader,
+        );
+        let admin = test_user(
+            insert_test_user(&db, "admin@example.com").await,
+            models::Role::Admin,
+        );
+        sqlx::query("UPDATE users SET role = 'writer' WHERE email = 'writer@example.com'")
+            .execute(&db.0)
+            .await
+            .unwrap();
+        let gallery_id = create_gallery(&db, owner.id, "gallery").await.unwrap();
+
+        assert!(can_edit_gallery(&db, &owner, gallery_id).await.unwrap());
+        assert!(can_edit_gallery(&db, &admin, gallery_id).await.unwrap());
+        assert!(!can_edit_gallery(&db, &writer, gallery_id).await.unwrap());
+        assert!(!can_edit_gallery(&db, &reader, gallery_id).await.unwrap());
+
+        assert!(add_gallery_editor(&db, gallery_id, "writer@example.com")
+            .await
+            .unwrap());
+        // Readers can't be made editors
+        assert!(!add_gallery_editor(&db, gallery_id, "reader@example.com")
+            .await
+            .unwrap());
+
+        assert!(can_edit_gallery(&db, &writer, gallery_id).await.unwrap());
+        assert!(!can_manage_gallery(&db, &writer, gallery_id).await.unwrap());
+        assert!(can_manage_gallery(&db, &owner, gallery_id).await.unwrap());
+
+        remove_gallery_editor(&db, gallery_id, writer.id)
+            .await
+            .unwrap();
+
+        assert!(!can_edit_gallery(&db, &writer, gallery_id).await.unwrap());
+    }
+
+    #[rocket::async_test]
+    async fn test_admin_sees_private_galleries() {
+        let db = test_db().await;
+        let owner = test_user(
+            insert_test_user(&db, "owner@example.com").await,
+            models::Role::Writer,
+        );
+        let admin = test_user(
+            insert_test_user(&db, "admin@example.com").await,
+            models::Role::Admin,
+        );
+        let private = create_gallery(&db, owner.id, "private").await.unwrap();
+        update_gallery_visibility(&db, private, models::Visibility::Private)
+            .await
+            .unwrap();
+
+        let gallery = get_gallery(&db, private, &admin).await.unwrap();
+
+        assert!(gallery.can_edit);
+    }
+
+    #[rocket::async_test]
+    async fn test_get_image_gallery_id() {
+        let db = test_db().await;
+        let owner_id = insert_test_user(&db, "owner@example.com").await;
+        let gallery_id = create_gallery(&db, owner_id, "gallery").await.unwrap();
+        let image = create_image(&db, owner_id, gallery_id, "photo.jpg", "", None)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            get_image_gallery_id(&db, image.id).await.unwrap(),
+            Some(gallery_id)
+        );
+        assert_eq!(get_image_gallery_id(&db, image.id + 1).await.unwrap(), None);
+    }
+
+    #[rocket::async_test]
+    async fn test_processing_images_are_listed_until_they_fail() {
+        let db = test_db().await;
+        let owner_id = insert_test_user(&db, "owner@example.com").await;
+        let owner = test_user(owner_id, models::Role::Writer);
+        let gallery_id = create_gallery(&db, owner_id, "gallery").await.unwrap();
+        let image = create_image(&db, owner_id, gallery_id, "photo.jpg", "", None)
+            .await
+            .unwrap();
+
+        let gallery = get_gallery(&db, gallery_id, &owner).await.unwrap();
+        assert_eq!(gallery.images.len(), 1);
+        assert_eq!(gallery.images[0].status, models::ImageStatus::Processing);
+
+        set_image_status(&db, image.id, models::ImageStatus::Ready)
+            .await
+            .unwrap();
+        let tile = get_gallery_image(&db, gallery_id, image.id, &owner)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(tile.status, models::ImageStatus::Ready);
+
+        set_image_status(&db, image.id, models::ImageStatus::Failed)
+            .await
+            .unwrap();
+        let gallery = get_gallery(&db, gallery_id, &owner).await.unwrap();
+        assert!(gallery.images.is_empty());
+    }
+
+    #[rocket::async_test]
+    async fn test_deleted_image_stays_deleted_after_processing() {
+        let db = test_db().await;
+        let owner_id = insert_test_user(&db, "owner@example.com").await;
+        let owner = test_user(owner_id, models::Role::Writer);
+        let gallery_id = create_gallery(&db, owner_id, "gallery").await.unwrap();
+        let image = create_image(&db, owner_id, gallery_id, "photo.jpg", "", None)
+            .await
+            .unwrap();
+
+        delete_image(&db, image.id, owner_id).await.unwrap();
+        set_image_status(&db, image.id, models::ImageStatus::Ready)
+            .await
+            .unwrap();
+
+        assert!(get_gallery_image(&db, gallery_id, image.id, &owner)
+            .await
+            .unwrap()
+            .is_none());
+
+        // It comes back from the trash processed
+        assert!(restore_image(&db, image.id).await.unwrap());
+        let restored = get_gallery_image(&db, gallery_id, image.id, &owner)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(restored.status, models::ImageStatus::Ready);
+    }
+
+    #[rocket::async_test]
+    async fn test_trash_lists_and_restores() {
+        let db = test_db().await;
+        let owner_id = insert_test_user(&db, "owner@example.com").await;
+        let owner = test_user(owner_id, models::Role::Writer);
+        let other = test_user(
+            insert_test_user(&db, "other@example.com").await,
+            models::Role::Writer,
+        );
+        let gallery_id = create_gallery(&db, owner_id, "gallery").await.unwrap();
+        let family_id = create_gallery(&db, owner_id, "family").await.unwrap();
+        update_gallery_visibility(&db, family_id, models::Visibility::Family)
+            .await
+            .unwrap();
+        let image = create_image(&db, owner_id, gallery_id, "photo.jpg", "caption", None)
+            .await
+            .unwrap();
+        set_image_status(&db, image.id, models::ImageStatus::Ready)
+            .await
+            .unwrap();
+        let in_family = create_image(&db, owner_id, family_id, "photo.jpg", "", None)
+            .await
+            .unwrap();
+
+        delete_image(&db, image.id, owner_id).await.unwrap();
+        delete_image(&db, in_family.id, owner_id).await.unwrap();
+        delete_gallery(&db, family_id, owner_id).await.unwrap();
+
+        let images = get_trashed_images(&db, &owner).await.unwrap();
+        // The image in the deleted gallery comes back with it
+        assert_eq!(images.len(), 1);
+        assert_eq!(images[0].id, image.id);
+        assert_eq!(images[0].deleted_by.as_deref(), Some("owner@example.com"));
+        assert!(images[0].ready);
+        let galleries = get_trashed_galleries(&db, &owner).await.unwrap();
+        assert_eq!(galleries.len(), 1);
+        assert_eq!(galleries[0].id, family_id);
+        assert_eq!(galleries[0].image_count, 0);
+
+        // Writers only see what they could edit
+        assert!(get_trashed_images(&db, &other).await.unwrap().is_empty());
+        assert!(get_trashed_galleries(&db, &other).await.unwrap().is_empty());
+        assert!(!can_restore_gallery(&db, &other, family_id).await.unwrap());
+        assert!(can_restore_gallery(&db, &owner, family_id).await.unwrap());
+        assert!(!can_restore_gallery(&db, &owner, gallery_id).await.unwrap());
+
+        restore_gallery(&db, family_id).await.unwrap();
+        assert!(restore_image(&db, image.id).await.unwrap());
+        let gallery = get_gallery(&db, family_id, &owner).await.unwrap();
+        assert_eq!(gallery.visibility, models::Visibility::Family);
+        // Deleted on its own, so it stays in the trash
+        assert_eq!(
+            get_trashed_images(&db, &owner).await.unwrap()[0].id,
+            in_family.id
+        );
+        assert!(get_trashed_galleries(&db, &owner).await.unwrap().is_empty());
+        let restored = get_gallery_image(&db, gallery_id, image.id, &owner)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(restored.status, models::ImageStatus::Ready);
+        assert_eq!(restored.caption, "caption");
+    }
+
+    #[rocket::async_test]
+    async fn test_restore_refuses_a_photo_uploaded_again() {
+        let db = test_db().await;
+        let owner_id = insert_test_user(&db, "owner@example.com").await;
+        let gallery_id = create_gallery(&db, owner_id, "gallery").await.unwrap();
+        let create = || {
+            let media_type = models::MediaType::Image;
+            create_media(
+                &db,
+                owner_id,
+                gallery_id,
+                "photo.jpg",
+                "",
+                None,
+                media_type,
+                "abc",
+            )
+        };
+
+        let deleted = create().await.unwrap().unwrap();
+        delete_image(&db, deleted.id, owner_id).await.unwrap();
+        let again = create().await.unwrap().unwrap();
+
+        assert!(!restore_image(&db, deleted.id).await.unwrap());
+        let trashed = get_trashed_images(&db, &test_user(owner_id, models::Role::Writer))
+            .await
+            .unwrap();
+        assert_eq!(trashed[0].id, deleted.id);
+
+        // Once the new upload is deleted in turn, the old one can come back
+        delete_image(&db, again.id, owner_id).await.unwrap();
+        assert!(restore_image(&db, deleted.id).await.unwrap());
+    }
+
+    fn process_image_job(image_id: i64) -> models::Job {
+        models::Job::ProcessImage {
+            image_id,
+            path: format!("./img/{}", image_id),
+        }
+    }
+
+    #[rocket::async_test]
+    async fn test_jobs_are_claimed_once_in_order() {
+        let db = test_db().await;
+        let first = enqueue_job(&db, &process_image_job(1)).await.unwrap();
+        let second = enqueue_job(&db, &process_image_job(2)).await.unwrap();
+
+        let claimed = claim_next_job(&db).await.unwrap().unwrap();
+        assert_eq!(claimed.id, first);
+        assert_eq!(claimed.attempts, 1);
+        assert_eq!(claimed.job, process_image_job(1));
+
+        let claimed = claim_next_job(&db).await.unwrap().unwrap();
+        assert_eq!(claimed.id, second);
+
+        assert!(claim_next_job(&db).await.unwrap().is_none());
+
+        complete_job(&db, first).await.unwrap();
+        let remaining: i64 = sqlx::query("SELECT COUNT(*) FROM jobs")
+            .fetch_one(&db.0)
+            .await
+            .unwrap()
+            .get(0);
+        assert_eq!(remaining, 1);
+    }
+
+    #[rocket::async_test]
+    async fn test_failed_job_is_retried_until_max_attempts() {
+        let db = test_db().await;
+        let job_id = enqueue_job(&db, &process_image_job(1)).await.unwrap();
+
+        claim_next_job(&db).await.unwrap().unwrap();
+        assert!(fail_job(&db, job_id, "boom", 2).await.unwrap());
+
+        let claimed = claim_next_job(&db).await.unwrap().unwrap();
+        assert_eq!(claimed.attempts, 2);
+        assert!(!fail_job(&db, job_id, "boom", 2).await.unwrap());
+
+        assert!(claim_next_job(&db).await.unwrap().is_none());
+        let row = sqlx::query("SELECT status, last_error FROM jobs WHERE id = ?1")
+            .bind(job_id)
+            .fetch_one(&db.0)
+            .await
+            .unwrap();
+        let status: String = row.get(0);
+        let last_error: String = row.get(1);
+        assert_eq!(status, "failed");
+        assert_eq!(last_error, "boom");
+    }
+
+    #[rocket::async_test]
+    async fn test_requeue_interrupted_jobs() {
+        let db = test_db().await;
+        let job_id = enqueue_job(&db, &process_image_job(1)).await.unwrap();
+        claim_next_job(&db).await.unwrap().unwrap();
+
+        assert_eq!(requeue_interrupted_jobs(&db).await.unwrap(), 1);
+
+        let claimed = claim_next_job(&db).await.unwrap().unwrap();
+        assert_eq!(claimed.id, job_id);
+    }
+
+    #[rocket::async_test]
+    async fn test_image_metadata_round_trip() {
+        let db = test_db().await;
+        let owner_id = insert_test_user(&db, "owner@example.com").await;
+        let gallery_id = create_gallery(&db, owner_id, "gallery").await.unwrap();
+        let image = create_image(&db, owner_id, gallery_id, "photo.jpg", "", None)
+            .await
+            .unwrap();
+        let metadata = models::ImageMetadata {
+            capture_time: Some("2023-05-01 12:34:56".to_string()),
+            camera_make: Some("Apple".to_string()),
+            camera_model: Some("iPhone 12".to_string()),
+            orientation: Some(6),
+            latitude: Some(51.5),
+            longitude: Some(-0.13),
+        };
+
+        assert!(get_image_metadata(&db, image.id).await.unwrap().is_none());
+
+        save_image_metadata(&db, image.id, &metadata).await.unwrap();
+        // Reading the same original twice replaces the first read
+        save_image_metadata(&db, image.id, &metadata).await.unwrap();
+
+        assert_eq!(
+            get_image_metadata(&db, image.id).await.unwrap(),
+            Some(metadata)
+        );
+    }
+
+    #[rocket::async_test]
+    async fn test_exact_duplicates_are_refused_in_the_same_gallery() {
+        let db = test_db().await;
+        let owner_id = insert_test_user(&db, "owner@example.com").await;
+        let gallery_id = create_gallery(&db, owner_id, "gallery").await.unwrap();
+        let other_gallery_id = create_gallery(&db, owner_id, "other").await.unwrap();
+        let create = |gallery_id, content_hash| {
+            let db = &db;
+            async move {
+                let media_type = models::MediaType::Image;
+                create_media(
+                    db,
+                    owner_id,
+                    gallery_id,
+                    "photo.jpg",
+                    "",
+                    None,
+                    media_type,
+                    content_hash,
+                )
+                .await
+                .unwrap()
+            }
+        };
+
+        let image = create(gallery_id, "abc").await.unwrap();
+        assert_eq!(image.original_path, Some(blobs::blob_path("abc", None)));
+        assert!(create(gallery_id, "abc").await.is_none());
+        assert!(create(gallery_id, "abd").await.is_some());
+        assert!(create(other_gallery_id, "abc").await.is_some());
+
+        let (count,): (i64,) = sqlx::query_as(
+            "SELECT COUNT(*) FROM original_images WHERE gallery_id = ?1 AND content_hash = 'abc'",
+        )
+        .bind(gallery_id)
+        .fetch_one(&db.0)
+        .await
+        .unwrap();
+        assert_eq!(count, 1);
+
+        // A deleted photo can be uploaded again
+        delete_image(&db, image.id, owner_id).await.unwrap();
+        assert!(create(gallery_id, "abc").await.is_some());
+    }
+
+    #[rocket::async_test]
+    async fn test_near_duplicates_are_flagged_and_clustered() {
+        let db = test_db().await;
+        let owner_id = insert_test_user(&db, "owner@example.com").await;
+        let owner = test_user(owner_id, models::Role::Writer);
+        let gallery_id = create_gallery(&db, owner_id, "gallery").await.unwrap();
+        let other_gallery_id = create_gallery(&db, owner_id, "other").await.unwrap();
+
+        let mut images = vec![];
+        for (gallery_id, hash) in [
+            (gallery_id, 0b1111),
+            (gallery_id, 0b0111),
+            (gallery_id, u64::MAX),
+            (other_gallery_id, 0b1110),
+        ] {
+            let image = create_image(&db, owner_id, gallery_id, "photo.jpg", "", None)
+                .await
+                .unwrap();
+            set_image_status(&db, image.id, models::ImageStatus::Ready)
+                .await
+                .unwrap();
+            let similar = save_perceptual_hash(&db, image.id, hash, 2).await.unwrap();
+            images.push((image.id, similar));
+        }
+
+        // Only earlier images in the same gallery count
+        assert_eq!(images[0].1, None);
+        assert_eq!(images[1].1, Some(images[0].0));
+        assert_eq!(images[2].1, None);
+        assert_eq!(images[3].1, None);
+        let gallery = get_gallery(&db, gallery_id, &owner).await.unwrap();
+        let flags: Vec<_> = gallery.images.iter().map(|i| i.near_duplicate).collect();
+        assert_eq!(flags, vec![false, true, false]);
+
+        let clusters = get_near_duplicates(&db, 2).await.unwrap();
+        let ids: Vec<Vec<i64>> = clusters
+            .iter()
+            .map(|cluster| cluster.iter().map(|image| image.id).collect())
+            .collect();
+        assert_eq!(ids, vec![vec![images[0].0, images[1].0, images[3].0]]);
+        assert_eq!(clusters[0][2].gallery_name, "other");
+    }
+}
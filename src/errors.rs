@@ -1,6 +1,4 @@
 use argon2::password_hash;
-use image;
-use reqwest;
 use rocket::http::ContentType;
 use rocket::http::Status;
 use rocket::response::{self, Responder, Response};
@@ -8,7 +6,6 @@ use rocket::Request;
 use rocket_db_pools::sqlx;
 use std::fmt;
 use std::io;
-use tera;
 
 pub struct AppError {
     pub code: u16,
@@ -90,6 +87,24 @@ impl From<image::ImageError> for AppError {
     }
 }
 
+impl From<img_parts::Error> for AppError {
+    fn from(error: img_parts::Error) -> Self {
+        AppError {
+            code: 500,
+            message: error.to_string(),
+        }
+    }
+}
+
+impl From<serde_json::Error> for AppError {
+    fn from(error: serde_json::Error) -> Self {
+        AppError {
+            code: 500,
+            message: error.to_string(),
+        }
+    }
+}
+
 impl From<String> for AppError {
     fn from(error: String) -> Self {
         AppError {
@@ -211,6 +226,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(dead_code, clippy::assertions_on_constants)]
     fn test_from_reqwest_error() {
         // Create a reqwest URL parse error
         let url_result = reqwest::Url::parse("not_a_valid_url");
@@ -327,7 +343,6 @@ mod tests {
     #[test]
     fn test_concurrent_error_creation() {
         use std::thread;
-        use std::sync::Arc;
         
         let handles: Vec<_> = (0..10).map(|i| {
             thread::spawn(move || {
@@ -0,0 +1,750 @@
+use crate::blobs;
+use crate::config::AppConfig;
+use crate::constants;
+use crate::db::queries;
+use crate::db::queries::Db;
+use crate::errors;
+use crate::images::{self, RenditionFormat};
+use crate::models::models::{Job, LegacyOriginal, MediaType, StoredImage};
+use crate::storage::{self, Scratch, SharedStorage, Storage};
+use crate::video;
+use log::error;
+use rocket::tokio;
+use rocket::tokio::io;
+use rocket::{Build, Rocket};
+use rocket_db_pools::Database;
+use std::collections::HashSet;
+use std::process::ExitCode;
+use std::time::UNIX_EPOCH;
+
+// Checks that the stored files and the images in the database agree. Run as
+// `john_rocket fsck`, against the same database and storage the server uses,
+// ideally while it is stopped.
+
+pub static USAGE: &str = "\
+Usage: john_rocket fsck [--regenerate] [--delete-orphans] [--purge-deleted DAYS] [--verify]
+                        [--relink-originals]
+
+Reports image files that are missing, files no image owns, missing renditions and
+originals saved before they had their own paths.
+
+  --regenerate          Make any missing renditions again
+  --delete-orphans      Delete files no image or original owns, keeping
+                        those that could be legacy originals not yet relinked
+  --purge-deleted DAYS  Forget images and galleries deleted more than DAYS days
+                        ago, deleting the files no other image uses
+  --verify              Read every file stored under its hash, reporting those
+                        whose bytes don't match it
+  --relink-originals    Point originals saved before they had their own paths at
+                        the only unowned file written when they were uploaded";
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Options {
+    pub regenerate: bool,
+    pub delete_orphans: bool,
+    pub purge_deleted_after: Option<i64>,
+    pub verify: bool,
+    pub relink_originals: bool,
+}
+
+pub fn parse_args(args: &[String]) -> Result<Options, String> {
+    let mut options = Options::default();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--regenerate" => options.regenerate = true,
+            "--delete-orphans" => options.delete_orphans = true,
+            "--verify" => options.verify = true,
+            "--relink-originals" => options.relink_originals = true,
+            "--purge-deleted" => {
+                let days = args
+                    .next()
+                    .and_then(|days| days.parse().ok())
+                    .filter(|days: &i64| *days >= 0)
+                    .ok_or("--purge-deleted needs a number of days")?;
+                options.purge_deleted_after = Some(days);
+            }
+            _ => return Err(format!("Unknown option: {}", arg)),
+        }
+    }
+    Ok(options)
+}
+
+/// What the check found, and what it did about it
+#[derive(Debug, Default)]
+pub struct Report {
+    /// Files of images in use, or their originals, that aren't there
+    pub missing_files: Vec<String>,
+    /// Images in use without all their renditions
+    pub missing_renditions: Vec<String>,
+    /// Images whose renditions were made again
+    pub regenerated: Vec<String>,
+    /// Files that belong to nothing in the database
+    pub orphaned_files: Vec<String>,
+    pub deleted_orphans: Vec<String>,
+    /// Files no image uses once those deleted long enough ago are forgotten
+    pub purged_files: Vec<String>,
+    /// Files whose bytes don't match the hash they are named after
+    pub corrupt_files: Vec<String>,
+    /// Images whose originals were saved before they had their own paths
+    pub legacy_originals: Vec<String>,
+    /// Files found to be those originals, which they now point at
+    pub relinked_originals: Vec<String>,
+}
+
+impl Report {
+    /// Whether anything is still wrong
+    pub fn has_problems(&self) -> bool {
+        !self.missing_files.is_empty()
+            || self.missing_renditions.len() > self.regenerated.len()
+            || self.orphaned_files.len() > self.deleted_orphans.len()
+            || !self.corrupt_files.is_empty()
+            || self.legacy_originals.len() > self.relinked_originals.len()
+    }
+
+    fn print(&self) {
+        let sections = [
+            ("Missing files", &self.missing_files),
+            ("Missing renditions", &self.missing_renditions),
+            ("Regenerated renditions", &self.regenerated),
+            ("Orphaned files", &self.orphaned_files),
+            ("Deleted orphaned files", &self.deleted_orphans),
+            ("Purged files", &self.purged_files),
+            ("Corrupt files", &self.corrupt_files),
+            ("Legacy originals", &self.legacy_originals),
+            ("Relinked originals", &self.relinked_originals),
+        ];
+        for (title, paths) in sections {
+            println!("{}: {}", title, paths.len());
+            for path in paths {
+                println!("  {}", path);
+            }
+        }
+    }
+}
+
+// The part of a key shared by an image and all its renditions: the hash or UUID
+fn stem(key: &str) -> &str {
+    key.split('.').next().unwrap_or(key)
+}
+
+fn rendition_keys(path: &str, sizes: &[u32]) -> Vec<String> {
+    sizes
+        .iter()
+        .flat_map(|size| {
+            RenditionFormat::ALL
+                .iter()
+                .map(move |format| images::rendition_path(storage::key(path), *size, *format))
+        })
+        .collect()
+}
+
+async fn regenerate(
+    image: &StoredImage,
+    storage: &dyn Storage,
+    config: &AppConfig,
+) -> Result<(), errors::AppError> {
+    let mut scratch = Scratch::new().await?;
+    let path = scratch.fetch(storage, &image.path).await?;
+    let (media_type, config) = (image.media_type, config.clone());
+    tokio::task::spawn_blocking(move || match media_type {
+        MediaType::Image => images::create_renditions(&path, &config.rendition_sizes),
+        MediaType::Video => {
+            let poster = video::poster_frame(&config.ffmpeg_path, &path)?;
+            images::save_renditions(&poster, &path, &config.rendition_sizes)
+        }
+    })
+    .await
+    .map_err(|e| e.to_string())??;
+    scratch.store(storage).await?;
+    Ok(())
+}
+
+// Originals saved before they had their own paths were named by a bare UUID
+fn is_legacy_key(key: &str) -> bool {
+    uuid::Uuid::parse_str(key).is_ok()
+}
+
+// The file each legacy original was saved to, out of the `unowned` ones: the only
+// one written around when it was uploaded, when no other original was uploaded
+// around then. Any other guess could swap two people's photos.
+async fn find_legacy_files(
+    storage: &dyn Storage,
+    legacy: &[LegacyOriginal],
+    unowned: &[&String],
+) -> Result<Vec<Option<String>>, errors::AppError> {
+    let mut written = vec![];
+    for key in unowned {
+        let time = storage
+            .head(key)
+            .await?
+            .and_then(|info| info.last_modified)
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok());
+        if let Some(time) = time {
+            written.push((key.to_string(), time.as_secs() as i64));
+        }
+    }
+
+    let near = |original: &LegacyOriginal, time: i64| {
+        (time - original.time_created).abs() <= constants::LEGACY_ORIGINAL_WINDOW
+    };
+    Ok(legacy
+        .iter()
+        .map(|original| {
+            let mut found = written.iter().filter(|(_, time)| near(original, *time));
+            match (found.next(), found.next()) {
+                (Some((key, time)), None)
+                    if legacy.iter().filter(|other| near(other, *time)).count() == 1 =>
+                {
+                    Some(key.clone())
+                }
+                _ => None,
+            }
+        })
+        .collect())
+}
+
+// Point legacy originals at the files found for them, returning those files.
+// Their metadata and perceptual hashes are read again from the real original.
+async fn relink(
+    db: &Db,
+    legacy: &[LegacyOriginal],
+    found: Vec<Option<String>>,
+) -> Result<Vec<String>, errors::AppError> {
+    let mut relinked = vec![];
+    for (original, key) in legacy.iter().zip(found) {
+        let key = match key {
+            Some(key) => key,
+            None => continue,
+        };
+        let original_path = format!("{}/{}", constants::IMG_PATH, key);
+        if !queries::relink_original(db, original.id, &original_path).await? {
+            continue;
+        }
+        for job in [
+            Job::ReadMetadata {
+                image_id: original.image_id,
+                original_path: original_path.clone(),
+            },
+            Job::HashImage {
+                image_id: original.image_id,
+                original_path,
+            },
+        ] {
+            queries::enqueue_job(db, &job).await?;
+        }
+        relinked.push(key);
+    }
+    Ok(relinked)
+}
+
+// Every stem named by an image or original
+fn known_stems(stored: &[StoredImage]) -> HashSet<&str> {
+    stored
+        .iter()
+        .flat_map(|image| [&image.path, &image.original_path])
+        .map(|path| stem(storage::key(path)))
+        .collect()
+}
+
+// Whether the stored file `key` has the bytes its hash says
+async fn verify(storage: &dyn Storage, key: &str) -> Result<bool, errors::AppError> {
+    let mut reader = match storage.stream(key, None).await? {
+        Some(reader) => blobs::verified(key, reader),
+        None => return Ok(true),
+    };
+    match io::copy(&mut reader, &mut io::sink()).await {
+        Ok(_) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::InvalidData => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Compare the database with the files in `storage`, fixing what `options` allow
+pub async fn check(
+    db: &Db,
+    config: &AppConfig,
+    storage: &dyn Storage,
+    options: &Options,
+) -> Result<Report, errors::AppError> {
+    let mut report = Report::default();
+    let mut keys = storage.list().await?;
+
+    // Images share files, which go once no image uses them
+    if let Some(days) = options.purge_deleted_after {
+        queries::purge_deleted_images(db, days).await?;
+        queries::purge_deleted_galleries(db, days).await?;
+        report.purged_files = blobs::delete_unreferenced(db, storage).await?;
+        keys.retain(|key| !report.purged_files.contains(key));
+    }
+    let mut stored = queries::get_stored_images(db).await?;
+
+    // Legacy originals are stored with their image's path, and their own file is
+    // named by nothing
+    let legacy = queries::get_legacy_originals(db).await?;
+    if !legacy.is_empty() {
+        let known = known_stems(&stored);
+        let unowned: Vec<&String> = keys
+            .iter()
+            .filter(|key| !known.contains(stem(key)) && is_legacy_key(key))
+            .collect();
+        let found = find_legacy_files(storage, &legacy, &unowned).await?;
+        report.legacy_originals = legacy
+            .iter()
+            .map(|original| original.path.clone())
+            .collect();
+        if options.relink_originals {
+            report.relinked_originals = relink(db, &legacy, found).await?;
+            stored = queries::get_stored_images(db).await?;
+        }
+    }
+
+    let mut checked = HashSet::new();
+    for image in stored.iter().filter(|image| image.needs_files) {
+        for path in [&image.path, &image.original_path] {
+            if checked.insert(path) && !storage.exists(storage::key(path)).await? {
+                report.missing_files.push(path.clone());
+            }
+        }
+    }
+
+    let mut checked = HashSet::new();
+    for image in stored.iter().filter(|image| image.needs_renditions) {
+        if !checked.insert(&image.path) {
+            continue;
+        }
+        // Nothing can be made without the image itself
+        let mut has_all = true;
+        for key in rendition_keys(&image.path, &config.rendition_sizes) {
+            has_all = has_all && storage.exists(&key).await?;
+        }
+        if has_all || !storage.exists(storage::key(&image.path)).await? {
+            continue;
+        }
+        report.missing_renditions.push(image.path.clone());
+
+        if options.regenerate {
+            match regenerate(image, storage, config).await {
+                Ok(()) => report.regenerated.push(image.path.clone()),
+                Err(e) => error!(
+                    "Couldn't regenerate renditions of {}: {}",
+                    image.path, e.message
+                ),
+            }
+        }
+    }
+
+    // Every file named after something in the database belongs to it
+    let known = known_stems(&stored);
+
+    if options.verify {
+        for key in keys.iter().filter(|key| blobs::hash_of(key).is_some()) {
+            if !verify(storage, key).await? {
+                report.corrupt_files.push(key.clone());
+            }
+        }
+    }
+
+    // Until every legacy original is found, any file named like one could be the
+    // only copy of someone's upload
+    let unlinked = report.legacy_originals.len() > report.relinked_originals.len();
+    for key in keys {
+        if known.contains(stem(&key)) {
+            continue;
+        }
+        report.orphaned_files.push(key.clone());
+        if options.delete_orphans && !(unlinked && is_legacy_key(&key)) {
+            storage.delete(&key).await?;
+            report.deleted_orphans.push(key);
+        }
+    }
+
+    Ok(report)
+}
+
+/// Run the check from the command line, with the server's configuration
+pub async fn main(rocket: Rocket<Build>, args: &[String]) -> ExitCode {
+    let options = match parse_args(args) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    // Igniting runs the migrations and opens the database, but starts none of
+    // the workers
+    let rocket = match rocket.ignite().await {
+        Ok(rocket) => rocket,
+        Err(e) => {
+            eprintln!("Couldn't start: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    let storage = rocket.state::<SharedStorage>();
+    let (db, config, storage) = match (Db::fetch(&rocket), rocket.state::<AppConfig>(), storage) {
+        (Some(db), Some(config), Some(storage)) => (db, config, storage),
+        _ => {
+            eprintln!("Database, config or storage not available");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    match check(db, config, storage.as_ref(), &options).await {
+        Ok(report) => {
+            report.print();
+            if report.has_problems() {
+                ExitCode::FAILURE
+            } else {
+                ExitCode::SUCCESS
+            }
+        }
+        Err(e) => {
+            eprintln!("Check failed: {}", e.message);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::queries::tests::{insert_test_user, test_db};
+    use crate::storage::LocalStorage;
+    use image::{DynamicImage, RgbImage};
+    use rocket::figment::Figment;
+    use rocket_db_pools::sqlx;
+    use std::path::{Path, PathBuf};
+
+    fn test_config() -> AppConfig {
+        let mut config: AppConfig = Figment::new().extract().unwrap();
+        config.rendition_sizes = vec![50];
+        config
+    }
+
+    // Where a path stored in the database is inside `dir`
+    fn in_dir(dir: &Path, path: &str) -> PathBuf {
+        dir.join(storage::key(path))
+    }
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    // An image in a new gallery, with its file and original in `dir`
+    async fn stored_image(db: &Db, dir: &Path) -> (i64, String, String) {
+        let user_id = insert_test_user(db, "owner@example.com").await;
+        let gallery_id = queries::create_gallery(db, user_id, "gallery")
+            .await
+            .unwrap();
+        let image = queries::tests::create_image(db, user_id, gallery_id, "photo.jpg", "", None)
+            .await
+            .unwrap();
+        queries::set_image_status(db, image.id, crate::models::models::ImageStatus::Ready)
+            .await
+            .unwrap();
+
+        let pixels = DynamicImage::ImageRgb8(RgbImage::new(100, 80));
+        pixels
+            .save_with_format(in_dir(dir, &image.path), image::ImageFormat::Jpeg)
+            .unwrap();
+        std::fs::write(
+            in_dir(dir, image.original_path.as_ref().unwrap()),
+            b"original",
+        )
+        .unwrap();
+        (image.id, image.path, image.original_path.unwrap())
+    }
+
+    fn file_name(path: &str) -> String {
+        Path::new(path)
+            .file_name()
+            .unwrap()
+            .to_string_lossy()
+            .to_string()
+    }
+
+    fn file_names(dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn test_parse_args() {
+        assert_eq!(parse_args(&[]), Ok(Options::default()));
+        assert_eq!(
+            parse_args(&args(&["--regenerate", "--purge-deleted", "30"])),
+            Ok(Options {
+                regenerate: true,
+                delete_orphans: false,
+                purge_deleted_after: Some(30),
+                verify: false,
+                relink_originals: false,
+            })
+        );
+        assert_eq!(
+            parse_args(&args(&["--verify"])),
+            Ok(Options {
+                verify: true,
+                ..Options::default()
+            })
+        );
+        assert!(parse_args(&args(&["--purge-deleted"])).is_err());
+        assert!(parse_args(&args(&["--purge-deleted", "-1"])).is_err());
+        assert!(parse_args(&args(&["--force"])).is_err());
+    }
+
+    #[rocket::async_test]
+    async fn test_check_reports_then_repairs() {
+        let db = test_db().await;
+        let config = test_config();
+        let dir = std::env::temp_dir().join(format!("jv-fsck-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir(&dir).unwrap();
+        let storage = LocalStorage::new(&dir);
+
+        let (_, path, original_path) = stored_image(&db, &dir).await;
+        let (deleted_id, deleted_path, _) = stored_image(&db, &dir).await;
+        queries::delete_image(&db, deleted_id, 1).await.unwrap();
+        std::fs::write(dir.join("leftover.50.jpg"), b"").unwrap();
+        std::fs::write(dir.join(".gitignore"), b"").unwrap();
+
+        let report = check(&db, &config, &storage, &Options::default())
+            .await
+            .unwrap();
+        assert!(report.missing_files.is_empty());
+        assert_eq!(report.missing_renditions, vec![path.clone()]);
+        assert_eq!(report.orphaned_files.len(), 1);
+        assert!(report.orphaned_files[0].ends_with("leftover.50.jpg"));
+        assert!(report.has_problems());
+
+        // Just deleted, so it is kept for now
+        let options = Options {
+            regenerate: true,
+            delete_orphans: true,
+            purge_deleted_after: Some(1),
+            verify: true,
+            relink_originals: true,
+        };
+        let report = check(&db, &config, &storage, &options).await.unwrap();
+        assert_eq!(report.regenerated, vec![path.clone()]);
+        assert!(report.purged_files.is_empty());
+        assert!(!report.has_problems());
+        assert!(!dir.join("leftover.50.jpg").exists());
+        assert!(in_dir(&dir, &deleted_path).exists());
+
+        sqlx::query("UPDATE modified_images SET time_deleted = datetime('now', '-2 days')")
+            .execute(&db.0)
+            .await
+            .unwrap();
+        let report = check(&db, &config, &storage, &options).await.unwrap();
+        // The deleted image and its original
+        assert_eq!(report.purged_files.len(), 2);
+        assert!(!in_dir(&dir, &deleted_path).exists());
+        assert!(!report.has_problems());
+
+        let name = file_name(&path);
+        let mut expected = vec![
+            ".gitignore".to_string(),
+            file_name(&original_path),
+            format!("{}.50.avif", name),
+            format!("{}.50.jpg", name),
+            format!("{}.50.webp", name),
+            name,
+        ];
+        expected.sort();
+        assert_eq!(file_names(&dir), expected);
+
+        // Files that go missing are reported
+        std::fs::remove_file(in_dir(&dir, &path)).unwrap();
+        let report = check(&db, &config, &storage, &Options::default())
+            .await
+            .unwrap();
+        assert_eq!(report.missing_files, vec![path]);
+        assert!(report.has_problems());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // A legacy image in a new gallery, with its file and the original it was
+    // saved to in `dir`. Returns the image's path and the original's key.
+    async fn legacy_image(db: &Db, dir: &Path) -> (String, String) {
+        let user_id = insert_test_user(db, "owner@example.com").await;
+        let gallery_id = queries::create_gallery(db, user_id, "gallery")
+            .await
+            .unwrap();
+        let (_, path) = queries::tests::create_legacy_image(db, user_id, gallery_id).await;
+        std::fs::write(in_dir(dir, &path), b"image").unwrap();
+        for size in [50] {
+            for format in RenditionFormat::ALL {
+                let key = images::rendition_path(storage::key(&path), size, format);
+                std::fs::write(dir.join(key), b"").unwrap();
+            }
+        }
+        let original = uuid::Uuid::new_v4().to_string();
+        std::fs::write(dir.join(&original), b"original").unwrap();
+        (path, original)
+    }
+
+    #[rocket::async_test]
+    async fn test_legacy_originals_are_relinked() {
+        let db = test_db().await;
+        let config = test_config();
+        let dir = std::env::temp_dir().join(format!("jv-fsck-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir(&dir).unwrap();
+        let storage = LocalStorage::new(&dir);
+
+        // Uploaded at the same time, so neither file can be told apart
+        let (path, original) = legacy_image(&db, &dir).await;
+        let (other_path, other_original) = legacy_image(&db, &dir).await;
+        let options = Options {
+            relink_originals: true,
+            ..Options::default()
+        };
+        let report = check(&db, &config, &storage, &options).await.unwrap();
+        assert_eq!(
+            report.legacy_originals,
+            vec![path.clone(), other_path.clone()]
+        );
+        assert!(report.relinked_originals.is_empty());
+        assert_eq!(report.orphaned_files.len(), 2);
+        assert!(report.has_problems());
+
+        sqlx::query(
+            "UPDATE original_images SET time_created = datetime('now', '-1 day') WHERE path = ?1",
+        )
+        .bind(&other_path)
+        .execute(&db.0)
+        .await
+        .unwrap();
+        let a_day_ago = std::time::SystemTime::now() - std::time::Duration::from_secs(60 * 60 * 24);
+        std::fs::File::options()
+            .write(true)
+            .open(dir.join(&other_original))
+            .unwrap()
+            .set_modified(a_day_ago)
+            .unwrap();
+        let report = check(&db, &config, &storage, &Options::default())
+            .await
+            .unwrap();
+        assert_eq!(report.legacy_originals.len(), 2);
+        assert!(report.relinked_originals.is_empty());
+        let report = check(&db, &config, &storage, &options).await.unwrap();
+        assert_eq!(
+            report.relinked_originals,
+            vec![original.clone(), other_original]
+        );
+        assert!(report.orphaned_files.is_empty());
+        assert!(!report.has_problems());
+
+        let (original_path,): (String,) =
+            sqlx::query_as("SELECT path FROM original_images WHERE id = 1")
+                .fetch_one(&db.0)
+                .await
+                .unwrap();
+        assert_eq!(storage::key(&original_path), original);
+        let (jobs,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM jobs")
+            .fetch_one(&db.0)
+            .await
+            .unwrap();
+        assert_eq!(jobs, 4);
+
+        let report = check(&db, &config, &storage, &options).await.unwrap();
+        assert!(report.legacy_originals.is_empty());
+        assert!(!report.has_problems());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[rocket::async_test]
+    async fn test_legacy_originals_are_never_deleted() {
+        let db = test_db().await;
+        let config = test_config();
+        let dir = std::env::temp_dir().join(format!("jv-fsck-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir(&dir).unwrap();
+        let storage = LocalStorage::new(&dir);
+
+        let (_, original) = legacy_image(&db, &dir).await;
+        let (_, other_original) = legacy_image(&db, &dir).await;
+        let leftover = format!("{}.50.jpg", uuid::Uuid::new_v4());
+        std::fs::write(dir.join(&leftover), b"").unwrap();
+
+        let options = Options {
+            delete_orphans: true,
+            relink_originals: true,
+            ..Options::default()
+        };
+        let report = check(&db, &config, &storage, &options).await.unwrap();
+        assert!(report.relinked_originals.is_empty());
+        assert_eq!(report.orphaned_files.len(), 3);
+        assert_eq!(report.deleted_orphans, vec![leftover]);
+        assert!(dir.join(&original).exists());
+        assert!(dir.join(&other_original).exists());
+        assert!(report.has_problems());
+
+        // Once they are all found, what is left over is nobody's
+        sqlx::query("UPDATE original_images SET is_legacy = FALSE")
+            .execute(&db.0)
+            .await
+            .unwrap();
+        let report = check(&db, &config, &storage, &options).await.unwrap();
+        assert_eq!(report.deleted_orphans.len(), 2);
+        assert!(!dir.join(&original).exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[rocket::async_test]
+    async fn test_shared_files_are_kept_and_verified() {
+        let db = test_db().await;
+        let config = test_config();
+        let dir = std::env::temp_dir().join(format!("jv-fsck-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir(&dir).unwrap();
+        let storage = LocalStorage::new(&dir);
+
+        // The same original uploaded to two galleries
+        let hash = crate::hashing::content_hash(&b"original"[..])
+            .await
+            .unwrap();
+        let mut original_path = String::new();
+        let mut image_ids = vec![];
+        for _ in 0..2 {
+            let (image_id, _, old_path) = stored_image(&db, &dir).await;
+            std::fs::remove_file(in_dir(&dir, &old_path)).unwrap();
+            original_path = queries::tests::save_content_hash(&db, image_id, &hash)
+                .await
+                .unwrap();
+            image_ids.push(image_id);
+        }
+        std::fs::write(in_dir(&dir, &original_path), b"original").unwrap();
+        queries::delete_image(&db, image_ids[1], 1).await.unwrap();
+        sqlx::query("UPDATE modified_images SET time_deleted = datetime('now', '-2 days')")
+            .execute(&db.0)
+            .await
+            .unwrap();
+
+        let options = Options {
+            regenerate: true,
+            purge_deleted_after: Some(1),
+            verify: true,
+            ..Options::default()
+        };
+        let report = check(&db, &config, &storage, &options).await.unwrap();
+        // Only the deleted image's own file goes
+        assert_eq!(report.purged_files.len(), 1);
+        assert!(in_dir(&dir, &original_path).exists());
+        assert!(report.missing_files.is_empty());
+        assert!(report.corrupt_files.is_empty());
+        assert!(!report.has_problems());
+
+        std::fs::write(in_dir(&dir, &original_path), b"originaL").unwrap();
+        let report = check(&db, &config, &storage, &options).await.unwrap();
+        assert_eq!(report.corrupt_files, vec![storage::key(&original_path)]);
+        assert!(report.has_problems());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,144 @@
+use image::imageops::FilterType;
+use image::DynamicImage;
+use rocket::tokio::io::{AsyncRead, AsyncReadExt};
+use sha2::{Digest, Sha256};
+use std::io;
+
+/// SHA-256 of everything `reader` gives, as lowercase hex
+///
+/// Only identical files have the same hash, so this finds exact duplicates.
+pub async fn content_hash<R: AsyncRead + Unpin>(mut reader: R) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        let read = reader.read(&mut buffer).await?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+
+    Ok(hex(&hasher.finalize()))
+}
+
+/// Bytes as lowercase hex, as hashes are written
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// A difference hash of what the image looks like
+///
+/// The image is shrunk to 9x8 greys, and each bit says whether a pixel is
+/// brighter than the one to its right. Resizing, re-encoding or small edits
+/// barely change it, so similar photos have hashes a few bits apart.
+pub fn dhash(image: &DynamicImage) -> u64 {
+    let small = image.resize_exact(9, 8, FilterType::Triangle).to_luma8();
+
+    let mut hash = 0;
+    for y in 0..8 {
+        for x in 0..8 {
+            let brighter = small.get_pixel(x, y)[0] > small.get_pixel(x + 1, y)[0];
+            hash = (hash << 1) | brighter as u64;
+        }
+    }
+    hash
+}
+
+/// How many bits two perceptual hashes differ by
+pub fn distance(a: u64, b: u64) -> u32 {
+    (a ^ b).count_ones()
+}
+
+/// Group hashes that are at most `max_distance` apart, directly or through
+/// other hashes in the group, returning the indexes of each group
+///
+/// Hashes with nothing near them are left out.
+pub fn clusters(hashes: &[u64], max_distance: u32) -> Vec<Vec<usize>> {
+    // Each hash starts in its own group, and groups are merged as pairs are found
+    let mut group: Vec<usize> = (0..hashes.len()).collect();
+    fn root(group: &mut [usize], mut i: usize) -> usize {
+        while group[i] != i {
+            group[i] = group[group[i]];
+            i = group[i];
+        }
+        i
+    }
+
+    for i in 0..hashes.len() {
+        for j in i + 1..hashes.len() {
+            if distance(hashes[i], hashes[j]) <= max_distance {
+                let (a, b) = (root(&mut group, i), root(&mut group, j));
+                group[b] = a;
+            }
+        }
+    }
+
+    let mut clusters: Vec<Vec<usize>> = vec![];
+    let mut cluster_of_root: Vec<Option<usize>> = vec![None; hashes.len()];
+    for i in 0..hashes.len() {
+        let r = root(&mut group, i);
+        match cluster_of_root[r] {
+            Some(cluster) => clusters[cluster].push(i),
+            None => {
+                cluster_of_root[r] = Some(clusters.len());
+                clusters.push(vec![i]);
+            }
+        }
+    }
+    clusters.retain(|cluster| cluster.len() > 1);
+    clusters
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgb, RgbImage};
+
+    // Light on the left, dark on the right, with a bar whose place varies
+    fn gradient(width: u32, height: u32, bar: u32) -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_fn(width, height, |x, y| {
+            let shade = 255 - (x * 255 / width) as u8;
+            if (y * 8 / height) == bar {
+                Rgb([0, 0, 0])
+            } else {
+                Rgb([shade, shade, shade])
+            }
+        }))
+    }
+
+    #[rocket::async_test]
+    async fn test_content_hash() {
+        let hash = content_hash(&b"abc"[..]).await.unwrap();
+        assert_eq!(
+            hash,
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_ne!(content_hash(&b"abd"[..]).await.unwrap(), hash);
+    }
+
+    #[test]
+    fn test_dhash_ignores_size() {
+        let photo = gradient(900, 600, 3);
+        let thumbnail = photo.resize(300, 200, FilterType::Triangle);
+
+        assert!(distance(dhash(&photo), dhash(&thumbnail)) <= 2);
+        assert!(distance(dhash(&photo), dhash(&photo.fliph())) > 20);
+    }
+
+    #[test]
+    fn test_distance() {
+        assert_eq!(distance(0b1010, 0b1010), 0);
+        assert_eq!(distance(0b1010, 0b0101), 4);
+        assert_eq!(distance(0, u64::MAX), 64);
+    }
+
+    #[test]
+    fn test_clusters() {
+        let hashes = [0b0000, 0b1111_0000, 0b0001, 0b1111_0001, 0b0011, u64::MAX];
+
+        assert_eq!(clusters(&hashes, 1), vec![vec![0, 2, 4], vec![1, 3]]);
+        // Further apart than any pair
+        assert!(clusters(&hashes, 0).is_empty());
+        assert_eq!(clusters(&[], 10), Vec::<Vec<usize>>::new());
+    }
+}
@@ -0,0 +1,153 @@
+use crate::blobs;
+use crate::config::AppConfig;
+use crate::constants;
+use crate::db::queries;
+use crate::db::queries::Db;
+use crate::errors;
+use crate::storage::{SharedStorage, Storage};
+use log::{error, info};
+use rocket::fairing::AdHoc;
+use rocket::tokio;
+use rocket_db_pools::Database;
+use std::time::Duration;
+
+/// Fairing that periodically removes expired rows from the `sessions` table
+///
+/// Expired sessions are already rejected by the `Session` request guard, so this
+/// only keeps the table from growing forever. The purge interval is taken from
+/// `AppConfig::session_purge_interval`.
+pub fn session_purge() -> AdHoc {
+    AdHoc::on_liftoff("Purge expired sessions", |rocket| {
+        Box::pin(async move {
+            let db = match Db::fetch(rocket) {
+                Some(db) => db.clone(),
+                None => {
+                    error!("Database not available, expired sessions will not be purged");
+                    return;
+                }
+            };
+            let interval = match rocket.state::<AppConfig>() {
+                Some(config) => config.session_purge_interval,
+                None => {
+                    error!("Config not available, expired sessions will not be purged");
+                    return;
+                }
+            };
+
+            tokio::spawn(async move {
+                let mut ticker = tokio::time::interval(Duration::from_secs(interval));
+                loop {
+                    ticker.tick().await;
+                    match queries::delete_expired_sessions(&db).await {
+                        Ok(0) => (),
+                        Ok(n) => info!("Purged {} expired sessions", n),
+                        Err(e) => error!("Failed to purge expired sessions: {}", e),
+                    }
+                }
+            });
+        })
+    })
+}
+
+/// Fairing that periodically forgets chunked uploads nobody has sent a chunk to
+/// in `AppConfig::upload_ttl` seconds, and removes what they had received
+pub fn upload_purge() -> AdHoc {
+    AdHoc::on_liftoff("Purge abandoned uploads", |rocket| {
+        Box::pin(async move {
+            let db = match Db::fetch(rocket) {
+                Some(db) => db.clone(),
+                None => {
+                    error!("Database not available, abandoned uploads will not be purged");
+                    return;
+                }
+            };
+            let (ttl, upload_path) = match rocket.state::<AppConfig>() {
+                Some(config) => (config.upload_ttl, config.upload_path.clone()),
+                None => {
+                    error!("Config not available, abandoned uploads will not be purged");
+                    return;
+                }
+            };
+
+            tokio::spawn(async move {
+                let mut ticker =
+                    tokio::time::interval(Duration::from_secs(constants::UPLOAD_PURGE_INTERVAL));
+                loop {
+                    ticker.tick().await;
+                    let uploads = match queries::delete_stale_uploads(&db, ttl).await {
+                        Ok(uploads) => uploads,
+                        Err(e) => {
+                            error!("Failed to purge abandoned uploads: {}", e);
+                            continue;
+                        }
+                    };
+                    for upload in &uploads {
+                        let path = upload.part_path(&upload_path);
+                        if let Err(e) = tokio::fs::remove_file(&path).await {
+                            error!("Failed to remove {}: {}", path, e);
+                        }
+                    }
+                    if !uploads.is_empty() {
+                        info!("Purged {} abandoned uploads", uploads.len());
+                    }
+                }
+            });
+        })
+    })
+}
+
+/// Fairing that periodically deletes for good what has been in the trash for
+/// `AppConfig::trash_retention_days`, and the stored files only it used
+pub fn trash_purge() -> AdHoc {
+    AdHoc::on_liftoff("Purge the trash", |rocket| {
+        Box::pin(async move {
+            let db = match Db::fetch(rocket) {
+                Some(db) => db.clone(),
+                None => {
+                    error!("Database not available, the trash will not be purged");
+                    return;
+                }
+            };
+            let days = match rocket.state::<AppConfig>() {
+                Some(config) => config.trash_retention_days,
+                None => {
+                    error!("Config not available, the trash will not be purged");
+                    return;
+                }
+            };
+            let storage = match rocket.state::<SharedStorage>() {
+                Some(storage) => storage.clone(),
+                None => {
+                    error!("Storage not available, the trash will not be purged");
+                    return;
+                }
+            };
+
+            tokio::spawn(async move {
+                let mut ticker =
+                    tokio::time::interval(Duration::from_secs(constants::TRASH_PURGE_INTERVAL));
+                loop {
+                    ticker.tick().await;
+                    if let Err(e) = purge_trash(&db, storage.as_ref(), days).await {
+                        error!("Failed to purge the trash: {}", e.message);
+                    }
+                }
+            });
+        })
+    })
+}
+
+async fn purge_trash(db: &Db, storage: &dyn Storage, days: i64) -> Result<(), errors::AppError> {
+    let images = queries::purge_deleted_images(db, days).await?;
+    let galleries = queries::purge_deleted_galleries(db, days).await?;
+    let files = blobs::delete_unreferenced(db, storage).await?;
+    if images > 0 || galleries > 0 || !files.is_empty() {
+        info!(
+            "Purged {} images, {} galleries and {} files from the trash",
+            images,
+            galleries,
+            files.len()
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,443 @@
+use crate::constants;
+use crate::errors;
+use crate::hashing;
+use crate::models::models::{Crop, Rotation};
+use image::codecs::avif::AvifEncoder;
+use image::codecs::jpeg::JpegEncoder;
+use image::imageops::FilterType;
+use image::{DynamicImage, ImageDecoder, ImageReader};
+use log::info;
+use rocket::http::{Accept, MediaType, Status};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+/// File formats renditions are saved in
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RenditionFormat {
+    Avif,
+    Webp,
+    Jpeg,
+}
+
+impl RenditionFormat {
+    /// Every format, best first
+    pub const ALL: [RenditionFormat; 3] = [
+        RenditionFormat::Avif,
+        RenditionFormat::Webp,
+        RenditionFormat::Jpeg,
+    ];
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            RenditionFormat::Avif => "avif",
+            RenditionFormat::Webp => "webp",
+            RenditionFormat::Jpeg => "jpg",
+        }
+    }
+
+    fn media_type(&self) -> MediaType {
+        match self {
+            RenditionFormat::Avif => MediaType::AVIF,
+            RenditionFormat::Webp => MediaType::WEBP,
+            RenditionFormat::Jpeg => MediaType::JPEG,
+        }
+    }
+
+    fn save(&self, image: &DynamicImage, path: &str) -> Result<(), errors::AppError> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        match self {
+            // The default speed is far too slow for a photo gallery
+            RenditionFormat::Avif => {
+                image.write_with_encoder(AvifEncoder::new_with_speed_quality(
+                    writer,
+                    constants::AVIF_SPEED,
+                    constants::AVIF_QUALITY,
+                ))?
+            }
+            // The image crate can only write lossless WebP, which is several
+            // times the size of the JPEG for a photo
+            RenditionFormat::Webp => {
+                let encoder = webp::Encoder::from_image(image).map_err(|e| e.to_string())?;
+                writer.write_all(&encoder.encode(constants::WEBP_QUALITY))?;
+                writer.flush()?;
+            }
+            RenditionFormat::Jpeg => image.write_with_encoder(JpegEncoder::new(writer))?,
+        }
+        Ok(())
+    }
+}
+
+/// The formats a client accepts, in the order we should try them
+///
+/// AVIF and WebP are only used when the client asks for them by name, since
+/// `image/*` is sent by clients that can't show them. JPEG is always last, as
+/// every client can show it.
+pub fn negotiate_formats(accept: Option<&Accept>) -> Vec<RenditionFormat> {
+    let mut formats: Vec<(RenditionFormat, f32)> = RenditionFormat::ALL
+        .iter()
+        .filter(|format| **format != RenditionFormat::Jpeg)
+        .filter_map(|format| {
+            let weight = accept?
+                .iter()
+                .filter(|accepted| *accepted.media_type() == format.media_type())
+                .map(|accepted| accepted.weight_or(1.0))
+                .fold(0.0, f32::max);
+            (weight > 0.0).then_some((*format, weight))
+        })
+        .collect();
+
+    // Stable, so equally weighted formats keep their order in `ALL`
+    formats.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+    formats
+        .into_iter()
+        .map(|(format, _)| format)
+        .chain([RenditionFormat::Jpeg])
+        .collect()
+}
+
+/// Path of the single thumbnail made for images uploaded before renditions
+pub fn thumbnail_path(path: &str) -> String {
+    format!("{}.{}", path, constants::THUMBNAIL_EXT)
+}
+
+/// Path of the rendition of an image that is `width` pixels wide
+pub fn rendition_path(path: &str, width: u32, format: RenditionFormat) -> String {
+    format!("{}.{}.{}", path, width, format.extension())
+}
+
+/// The rendition to serve when `width` pixels are wanted: the smallest one that
+/// is at least that wide, or the largest one if none are
+pub fn pick_rendition(sizes: &[u32], width: u32) -> Option<u32> {
+    let mut sizes = sizes.to_vec();
+    sizes.sort_unstable();
+    sizes
+        .iter()
+        .find(|size| **size >= width)
+        .or(sizes.last())
+        .copied()
+}
+
+// Decode an image turned the way its EXIF orientation says
+fn open_oriented(path: &str) -> Result<DynamicImage, errors::AppError> {
+    let mut decoder = ImageReader::open(path)?
+        .with_guessed_format()?
+        .into_decoder()?;
+    let orientation = decoder.orientation()?;
+    let mut image = DynamicImage::from_decoder(decoder)?;
+    image.apply_orientation(orientation);
+    Ok(image)
+}
+
+/// The perceptual hash of the original at `original_path`, turned the right way up
+///
+/// Decoding large photos takes a while, so call this from a blocking task.
+pub fn perceptual_hash(original_path: &str) -> Result<u64, errors::AppError> {
+    Ok(hashing::dhash(&open_oriented(original_path)?))
+}
+
+/// The original at `original_path` as JPEG, for choosing a new crop in the browser
+///
+/// It is already turned the way its EXIF says, so the browser shows it the same
+/// way `crop_image` sees it, and has no metadata, so it can't leak the GPS.
+pub fn editable_original(original_path: &str) -> Result<Vec<u8>, errors::AppError> {
+    let image = DynamicImage::ImageRgb8(open_oriented(original_path)?.to_rgb8());
+    let mut jpeg = Vec::new();
+    image.write_with_encoder(JpegEncoder::new_with_quality(
+        &mut jpeg,
+        constants::CROP_QUALITY,
+    ))?;
+    Ok(jpeg)
+}
+
+/// Cut `crop` out of the original at `original_path` and save it to `path` as JPEG
+///
+/// The original is first turned the way its EXIF orientation says, since that is
+/// how the browser showed it while the crop was chosen. Only pixels are saved, so
+/// none of the original's metadata comes across.
+pub fn crop_image(original_path: &str, crop: &Crop, path: &str) -> Result<(), errors::AppError> {
+    info!("Cropping {} to {:?}", original_path, crop);
+
+    let image = open_oriented(original_path)?;
+
+    let image = match crop.rotation {
+        Rotation::None => image,
+        Rotation::Quarter => image.rotate90(),
+        Rotation::Half => image.rotate180(),
+        Rotation::ThreeQuarters => image.rotate270(),
+    };
+
+    // The browser rounds its crop, so it can hang over the edge by a pixel
+    let x = crop.x.min(image.width());
+    let y = crop.y.min(image.height());
+    let width = crop.width.min(image.width() - x);
+    let height = crop.height.min(image.height() - y);
+    if width == 0 || height == 0 {
+        return Err(errors::AppError {
+            code: Status::BadRequest.code,
+            message: format!("Crop {:?} is outside the image", crop),
+        });
+    }
+
+    let cropped = DynamicImage::ImageRgb8(image.crop_imm(x, y, width, height).to_rgb8());
+    let writer = BufWriter::new(File::create(path)?);
+    cropped.write_with_encoder(JpegEncoder::new_with_quality(
+        writer,
+        constants::CROP_QUALITY,
+    ))?;
+
+    Ok(())
+}
+
+/// Decode the image at `path` and save a rendition next to it for each size,
+/// in every format
+///
+/// Images are never scaled up, so small images get renditions at their own size.
+/// Decoding large photos takes a while, so call this from a blocking task.
+pub fn create_renditions(path: &str, sizes: &[u32]) -> Result<(), errors::AppError> {
+    let image = ImageReader::open(path)?.with_guessed_format()?.decode()?;
+    save_renditions(&image, path, sizes)
+}
+
+/// Save renditions of `image` next to `path`, e.g. a video's poster frame
+pub fn save_renditions(
+    image: &DynamicImage,
+    path: &str,
+    sizes: &[u32],
+) -> Result<(), errors::AppError> {
+    // JPEG has no alpha channel, and photos don't need one in the other formats
+    let image = DynamicImage::ImageRgb8(image.to_rgb8());
+
+    for size in sizes {
+        info!("Creating {}px rendition for: {}", size, path);
+
+        let rendition = if image.width() > *size {
+            image.resize(*size, u32::MAX, FilterType::CatmullRom)
+        } else {
+            image.clone()
+        };
+
+        for format in RenditionFormat::ALL {
+            let rendition_path = rendition_path(path, *size, format);
+
+            info!("Saving rendition to: {}", &rendition_path);
+
+            format.save(&rendition, &rendition_path)?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{ImageFormat, Rgb, RgbImage, RgbaImage};
+    use std::str::FromStr;
+
+    fn negotiate(accept: &str) -> Vec<RenditionFormat> {
+        negotiate_formats(Some(&Accept::from_str(accept).unwrap()))
+    }
+
+    #[test]
+    fn test_negotiate_formats() {
+        use RenditionFormat::*;
+
+        // What browsers send for images
+        assert_eq!(
+            negotiate("image/avif,image/webp,image/apng,image/svg+xml,image/*,*/*;q=0.8"),
+            vec![Avif, Webp, Jpeg]
+        );
+        assert_eq!(negotiate("image/webp,*/*"), vec![Webp, Jpeg]);
+        assert_eq!(negotiate("image/*"), vec![Jpeg]);
+        assert_eq!(
+            negotiate("image/avif;q=0.5,image/webp"),
+            vec![Webp, Avif, Jpeg]
+        );
+        assert_eq!(negotiate("image/avif;q=0,image/webp"), vec![Webp, Jpeg]);
+        assert_eq!(negotiate_formats(None), vec![Jpeg]);
+    }
+
+    #[test]
+    fn test_webp_clients_are_served_lossy_webp() {
+        let path = temp_path();
+        let mut image = RgbImage::new(600, 400);
+        for (x, y, pixel) in image.enumerate_pixels_mut() {
+            *pixel = Rgb([(x % 256) as u8, (y % 256) as u8, ((x + y) % 256) as u8]);
+        }
+        image.save_with_format(&path, ImageFormat::Png).unwrap();
+
+        create_renditions(&path, &[300]).unwrap();
+
+        // A browser that takes WebP but not AVIF
+        let served = negotiate("image/webp,*/*")[0];
+        assert_eq!(served, RenditionFormat::Webp);
+        let webp = std::fs::read(rendition_path(&path, 300, served)).unwrap();
+        assert_eq!(&webp[..4], b"RIFF");
+        assert_eq!(&webp[8..16], b"WEBPVP8 ");
+        let image = open(&rendition_path(&path, 300, served));
+        assert_eq!((image.width(), image.height()), (300, 200));
+
+        for format in RenditionFormat::ALL {
+            std::fs::remove_file(rendition_path(&path, 300, format)).unwrap();
+        }
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_pick_rendition() {
+        let sizes = [600, 150, 1200, 300];
+
+        assert_eq!(pick_rendition(&sizes, 100), Some(150));
+        assert_eq!(pick_rendition(&sizes, 300), Some(300));
+        assert_eq!(pick_rendition(&sizes, 301), Some(600));
+        assert_eq!(pick_rendition(&sizes, 4000), Some(1200));
+        assert_eq!(pick_rendition(&[], 300), None);
+    }
+
+    #[test]
+    fn test_create_renditions() {
+        // Uploaded files have no extension, so the format has to be guessed
+        let path = std::env::temp_dir().join(format!("jv-test-{}", uuid::Uuid::new_v4()));
+        let path = path.to_str().unwrap();
+        RgbaImage::new(900, 600)
+            .save_with_format(path, ImageFormat::Png)
+            .unwrap();
+
+        create_renditions(path, &[300, 1200]).unwrap();
+
+        for format in RenditionFormat::ALL {
+            let small = rendition_path(path, 300, format);
+            let large = rendition_path(path, 1200, format);
+            // The image crate can write AVIF but can't read it back
+            if format != RenditionFormat::Avif {
+                let image = image::open(&small).unwrap();
+                assert_eq!((image.width(), image.height()), (300, 200));
+                let image = image::open(&large).unwrap();
+                assert_eq!((image.width(), image.height()), (900, 600));
+            }
+            assert!(std::fs::metadata(&small).unwrap().len() > 0);
+            std::fs::remove_file(small).unwrap();
+            std::fs::remove_file(large).unwrap();
+        }
+        std::fs::remove_file(path).unwrap();
+    }
+
+    fn temp_path() -> String {
+        let path = std::env::temp_dir().join(format!("jv-test-{}", uuid::Uuid::new_v4()));
+        path.to_str().unwrap().to_string()
+    }
+
+    // Our files have no extension, so `image::open` can't tell their format
+    fn open(path: &str) -> DynamicImage {
+        ImageReader::open(path)
+            .unwrap()
+            .with_guessed_format()
+            .unwrap()
+            .decode()
+            .unwrap()
+    }
+
+    fn crop(x: u32, y: u32, width: u32, height: u32, rotation: Rotation) -> Crop {
+        Crop {
+            x,
+            y,
+            width,
+            height,
+            rotation,
+        }
+    }
+
+    #[test]
+    fn test_crop_image() {
+        let original = temp_path();
+        let path = temp_path();
+        // Red on the left half, blue on the right
+        let mut image = RgbImage::new(200, 100);
+        for (x, _, pixel) in image.enumerate_pixels_mut() {
+            *pixel = if x < 100 {
+                Rgb([255, 0, 0])
+            } else {
+                Rgb([0, 0, 255])
+            };
+        }
+        image.save_with_format(&original, ImageFormat::Png).unwrap();
+
+        crop_image(&original, &crop(100, 0, 100, 50, Rotation::None), &path).unwrap();
+        let cropped = open(&path).to_rgb8();
+        assert_eq!(cropped.dimensions(), (100, 50));
+        assert!(cropped.get_pixel(50, 25)[2] > 200);
+
+        // Turned clockwise the left half ends up on top
+        crop_image(&original, &crop(0, 50, 100, 100, Rotation::Quarter), &path).unwrap();
+        let cropped = open(&path).to_rgb8();
+        assert_eq!(cropped.dimensions(), (100, 100));
+        assert!(cropped.get_pixel(50, 25)[0] > 200);
+        assert!(cropped.get_pixel(50, 75)[2] > 200);
+
+        // A crop hanging over the edge is cut back to the image
+        crop_image(&original, &crop(150, 50, 60, 60, Rotation::None), &path).unwrap();
+        let cropped = open(&path).to_rgb8();
+        assert_eq!(cropped.dimensions(), (50, 50));
+
+        // Batch uploads aren't cropped
+        crop_image(&original, &Crop::whole(), &path).unwrap();
+        assert_eq!(open(&path).to_rgb8().dimensions(), (200, 100));
+
+        assert!(crop_image(&original, &crop(200, 0, 10, 10, Rotation::None), &path).is_err());
+
+        std::fs::remove_file(original).unwrap();
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_crop_image_follows_exif_orientation() {
+        let original = temp_path();
+        let path = temp_path();
+        // 64x48, with EXIF saying it must be turned a quarter clockwise
+        std::fs::write(&original, crate::metadata::tests::jpeg_with_exif()).unwrap();
+
+        crop_image(&original, &crop(0, 0, 48, 64, Rotation::None), &path).unwrap();
+
+        let cropped = open(&path);
+        assert_eq!((cropped.width(), cropped.height()), (48, 64));
+        assert_eq!(
+            crate::metadata::read_metadata(&path).unwrap(),
+            crate::models::models::ImageMetadata::default()
+        );
+
+        std::fs::remove_file(original).unwrap();
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_editable_original() {
+        let original = temp_path();
+        std::fs::write(&original, crate::metadata::tests::jpeg_with_exif()).unwrap();
+
+        let jpeg = editable_original(&original).unwrap();
+
+        let path = temp_path();
+        std::fs::write(&path, jpeg).unwrap();
+        let image = open(&path);
+        assert_eq!((image.width(), image.height()), (48, 64));
+        assert_eq!(
+            crate::metadata::read_metadata(&path).unwrap(),
+            crate::models::models::ImageMetadata::default()
+        );
+
+        std::fs::remove_file(original).unwrap();
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_create_renditions_of_broken_file_fails() {
+        let path = std::env::temp_dir().join(format!("jv-test-{}", uuid::Uuid::new_v4()));
+        let path = path.to_str().unwrap();
+        std::fs::write(path, b"not an image").unwrap();
+
+        assert!(create_renditions(path, &[300]).is_err());
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,244 @@
+use crate::blobs;
+use crate::config::AppConfig;
+use crate::constants;
+use crate::db::queries;
+use crate::db::queries::Db;
+use crate::errors;
+use crate::images;
+use crate::metadata;
+use crate::models::models::{ImageStatus, Job, QueuedJob};
+use crate::storage::{Scratch, SharedStorage, Storage};
+use crate::video;
+use log::{error, info, warn};
+use rocket::fairing::AdHoc;
+use rocket::tokio;
+use rocket_db_pools::Database;
+use std::time::Duration;
+
+impl Job {
+    // The tools need local files, so each job works on copies in a scratch
+    // directory and stores what it made
+    async fn run(
+        &self,
+        db: &Db,
+        config: &AppConfig,
+        storage: &dyn Storage,
+    ) -> Result<(), errors::AppError> {
+        let mut scratch = Scratch::new().await?;
+        match self {
+            Job::ProcessImage { image_id, path } => {
+                let path = scratch.fetch(storage, path).await?;
+                let sizes = config.rendition_sizes.clone();
+                tokio::task::spawn_blocking(move || images::create_renditions(&path, &sizes))
+                    .await
+                    .map_err(|e| e.to_string())??;
+                scratch.store(storage).await?;
+                queries::set_image_status(db, *image_id, ImageStatus::Ready).await?;
+                Ok(())
+            }
+            Job::CropImage {
+                image_id,
+                original_path,
+                path,
+                crop,
+            } => {
+                let original_path = scratch.fetch(storage, original_path).await?;
+                let (path, crop) = (scratch.path(path), *crop);
+                let cropped = path.clone();
+                tokio::task::spawn_blocking(move || {
+                    images::crop_image(&original_path, &crop, &cropped)?;
+                    // Only pixels are saved, but served files must never carry GPS
+                    metadata::strip_metadata(&cropped)
+                })
+                .await
+                .map_err(|e| e.to_string())??;
+
+                // The same crop of the same original is stored once
+                let (path, stored_path) = blobs::address(&path, None).await?;
+                let sizes = config.rendition_sizes.clone();
+                tokio::task::spawn_blocking(move || images::create_renditions(&path, &sizes))
+                    .await
+                    .map_err(|e| e.to_string())??;
+                // Used before it is stored, so it can't be collected as unused meanwhile
+                queries::set_image_path(db, *image_id, &stored_path).await?;
+                scratch.store(storage).await?;
+                queries::set_image_status(db, *image_id, ImageStatus::Ready).await?;
+                Ok(())
+            }
+            Job::ProcessVideo {
+                image_id,
+                original_path,
+                path,
+            } => {
+                let original_path = scratch.fetch(storage, original_path).await?;
+                let path = scratch.path(path);
+                let ffmpeg = config.ffmpeg_path.clone();
+                let transcoded = path.clone();
+                tokio::task::spawn_blocking(move || {
+                    video::transcode(&ffmpeg, &original_path, &transcoded)
+                })
+                .await
+                .map_err(|e| e.to_string())??;
+
+                let (path, stored_path) = blobs::address(&path, Some(constants::VIDEO_EXT)).await?;
+                let ffmpeg = config.ffmpeg_path.clone();
+                let sizes = config.rendition_sizes.clone();
+                tokio::task::spawn_blocking(move || {
+                    let poster = video::poster_frame(&ffmpeg, &path)?;
+                    images::save_renditions(&poster, &path, &sizes)
+                })
+                .await
+                .map_err(|e| e.to_string())??;
+                // Used before it is stored, so it can't be collected as unused meanwhile
+                queries::set_image_path(db, *image_id, &stored_path).await?;
+                scratch.store(storage).await?;
+                queries::set_image_status(db, *image_id, ImageStatus::Ready).await?;
+                Ok(())
+            }
+            Job::HashImage {
+                image_id,
+                original_path,
+            } => {
+                let original_path = scratch.fetch(storage, original_path).await?;
+                let perceptual_hash =
+                    tokio::task::spawn_blocking(move || images::perceptual_hash(&original_path))
+                        .await
+                        .map_err(|e| e.to_string())??;
+                let similar = queries::save_perceptual_hash(
+                    db,
+                    *image_id,
+                    perceptual_hash,
+                    config.near_duplicate_distance,
+                )
+                .await?;
+                if let Some(similar) = similar {
+                    info!("Image {} looks like image {}", image_id, similar);
+                }
+                Ok(())
+            }
+            Job::ReadMetadata {
+                image_id,
+                original_path,
+            } => {
+                let original_path = scratch.fetch(storage, original_path).await?;
+                let image_metadata =
+                    tokio::task::spawn_blocking(move || metadata::read_metadata(&original_path))
+                        .await
+                        .map_err(|e| e.to_string())??;
+                queries::save_image_metadata(db, *image_id, &image_metadata).await?;
+                Ok(())
+            }
+        }
+    }
+
+    /// Called once the job has failed for the last time
+    async fn give_up(&self, db: &Db) -> Result<(), errors::AppError> {
+        match self {
+            Job::ProcessImage { image_id, .. }
+            | Job::CropImage { image_id, .. }
+            | Job::ProcessVideo { image_id, .. } => {
+                queries::set_image_status(db, *image_id, ImageStatus::Failed).await?;
+                Ok(())
+            }
+            // The image is fine without metadata or a hash
+            Job::ReadMetadata { .. } | Job::HashImage { .. } => Ok(()),
+        }
+    }
+}
+
+async fn handle(
+    db: &Db,
+    queued: QueuedJob,
+    config: &AppConfig,
+    storage: &dyn Storage,
+) -> Result<(), errors::AppError> {
+    info!(
+        "Running job {} (attempt {}): {:?}",
+        queued.id, queued.attempts, queued.job
+    );
+
+    match queued.job.run(db, config, storage).await {
+        Ok(()) => queries::complete_job(db, queued.id).await?,
+        Err(e) => {
+            let retrying =
+                queries::fail_job(db, queued.id, &e.message, config.job_max_attempts).await?;
+            if retrying {
+                warn!("Job {} failed, will retry: {}", queued.id, e.message);
+            } else {
+                error!("Job {} failed for good: {}", queued.id, e.message);
+                queued.job.give_up(db).await?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+async fn work(db: Db, worker: usize, config: AppConfig, storage: SharedStorage) {
+    let poll_interval = config.job_poll_interval;
+    info!("Job worker {} started", worker);
+    loop {
+        let queued = match queries::claim_next_job(&db).await {
+            Ok(Some(queued)) => queued,
+            Ok(None) => {
+                tokio::time::sleep(Duration::from_secs(poll_interval)).await;
+                continue;
+            }
+            Err(e) => {
+                error!("Job worker {} couldn't claim a job: {:?}", worker, e);
+                tokio::time::sleep(Duration::from_secs(poll_interval)).await;
+                continue;
+            }
+        };
+
+        if let Err(e) = handle(&db, queued, &config, storage.as_ref()).await {
+            error!(
+                "Job worker {} couldn't record a job result: {:?}",
+                worker, e
+            );
+        }
+    }
+}
+
+/// Fairing that starts the workers for the `jobs` table
+///
+/// Requests queue slow work with `queries::enqueue_job` and return straight away.
+/// The number of workers, how often idle workers look for jobs, how often a job
+/// is retried and what the jobs produce come from `AppConfig`.
+pub fn worker_pool() -> AdHoc {
+    AdHoc::on_liftoff("Job workers", |rocket| {
+        Box::pin(async move {
+            let db = match Db::fetch(rocket) {
+                Some(db) => db.clone(),
+                None => {
+                    error!("Database not available, jobs will not be processed");
+                    return;
+                }
+            };
+            let config = match rocket.state::<AppConfig>() {
+                Some(config) => config.clone(),
+                None => {
+                    error!("Config not available, jobs will not be processed");
+                    return;
+                }
+            };
+            let storage = match rocket.state::<SharedStorage>() {
+                Some(storage) => storage.clone(),
+                None => {
+                    error!("Storage not available, jobs will not be processed");
+                    return;
+                }
+            };
+
+            match queries::requeue_interrupted_jobs(&db).await {
+                Ok(0) => (),
+                Ok(n) => info!("Requeued {} interrupted jobs", n),
+                Err(e) => error!("Failed to requeue interrupted jobs: {}", e),
+            }
+
+            for worker in 0..config.job_workers {
+                tokio::spawn(work(db.clone(), worker, config.clone(), storage.clone()));
+            }
+        })
+    })
+}
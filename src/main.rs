@@ -1,17 +1,31 @@
 mod auth {
     pub mod pw_utils;
 }
+mod blobs;
+mod caching;
 mod catchers;
+mod config;
 mod constants;
 mod db {
+    pub mod migrations;
     pub mod queries;
 }
 mod errors;
+mod fsck;
+mod hashing;
+mod housekeeping;
+mod images;
+mod jobs;
+mod metadata;
 mod middleware;
 mod models {
+    #[allow(clippy::module_inception)]
     pub mod models;
 }
+mod promote;
+mod ranges;
 mod routes {
+    pub mod admin;
     pub mod css;
     pub mod galleries;
     pub mod img;
@@ -19,19 +33,28 @@ mod routes {
     pub mod js;
     pub mod login;
     pub mod logout;
+    pub mod password;
     pub mod signup;
+    pub mod trash;
+    pub mod uploads;
 }
+mod storage;
 mod tera_utils;
+mod video;
 
+use config::AppConfig;
+use db::migrations;
 use db::queries;
 use db::queries::Db;
-use env_logger;
 use log::error;
+use rocket::data::{Limits, ToByteUnit};
 use rocket::fairing::{self, AdHoc};
+use rocket::figment::providers::{Env, Format, Toml};
+use rocket::figment::Figment;
 use rocket::fs::{relative, FileServer};
-use rocket::{catchers, Build, Rocket};
-use rocket::{launch, routes};
+use rocket::{catchers, routes, Build, Rocket};
 use rocket_db_pools::Database;
+use routes::admin;
 use routes::css;
 use routes::galleries;
 use routes::img;
@@ -39,14 +62,18 @@ use routes::index;
 use routes::js;
 use routes::login;
 use routes::logout;
+use routes::password;
 use routes::signup;
+use routes::trash;
+use routes::uploads;
+use std::process::ExitCode;
 
 async fn create_tables(rocket: Rocket<Build>) -> fairing::Result {
     match Db::fetch(&rocket) {
-        Some(db) => match queries::create_tables(&db).await {
+        Some(db) => match migrations::run(db).await {
             Ok(_) => Ok(rocket),
             Err(e) => {
-                error!("Failed to initialize SQLx database: {}", e);
+                error!("Failed to migrate SQLx database: {}", e);
                 Err(rocket)
             }
         },
@@ -58,27 +85,64 @@ fn stage() -> AdHoc {
     AdHoc::on_ignite("SQLx Stage", |rocket| async {
         rocket
             .attach(Db::init())
+            .attach(storage::init())
             .attach(AdHoc::try_on_ignite("SQLx create tables", create_tables))
+            .attach(housekeeping::session_purge())
+            .attach(housekeeping::upload_purge())
+            .attach(housekeeping::trash_purge())
+            .attach(jobs::worker_pool())
             .mount(
                 "/",
                 routes![
                     galleries::post,
                     galleries::post_img,
+                    galleries::post_batch,
                     galleries::get,
                     galleries::get_gallery,
                     galleries::delete_gallery,
                     galleries::update_gallery,
                     galleries::get_upload_form,
                     galleries::get_gallery_item,
+                    galleries::get_image_item,
+                    galleries::get_image_editor,
+                    galleries::get_original,
+                    galleries::edit_image,
+                    galleries::get_settings,
+                    galleries::update_visibility,
+                    galleries::add_member,
+                    galleries::remove_member,
+                    galleries::add_editor,
+                    galleries::remove_editor,
+                    admin::get_users,
+                    admin::get_duplicates,
+                    admin::update_role,
+                    admin::approve,
+                    admin::disable,
+                    admin::enable,
+                    admin::logout,
                     img::delete,
                     img::get,
+                    img::get_rendition,
                     img::update_caption,
+                    trash::get,
+                    trash::restore_gallery,
+                    trash::restore_image,
+                    uploads::create,
+                    uploads::status,
+                    uploads::put_chunk,
+                    uploads::finish,
+                    uploads::delete,
                     login::post,
                     login::get,
                     logout::get,
+                    password::get_forgot,
+                    password::post_forgot,
+                    password::get_reset,
+                    password::post_reset,
                     signup::post,
                     signup::get,
                     signup::verify,
+                    signup::resend,
                     index::get,
                     css::get,
                     js::get,
@@ -87,11 +151,99 @@ fn stage() -> AdHoc {
     })
 }
 
-#[launch]
-fn rocket() -> _ {
-    env_logger::init();
-    rocket::build()
+// Rocket's configuration, with room in upload forms for the files upload.js
+// sends whole. Limits set in Rocket.toml or ROCKET_LIMITS still win.
+fn figment() -> Figment {
+    let limits = Limits::default()
+        .limit("file", constants::UPLOAD_FORM_LIMIT.bytes())
+        .limit("data-form", constants::UPLOAD_FORM_LIMIT.bytes());
+    rocket::Config::figment()
+        .merge(("limits", limits))
+        .merge(Toml::file(Env::var_or("ROCKET_CONFIG", "Rocket.toml")).nested())
+        .merge(Env::prefixed("ROCKET_").ignore(&["PROFILE"]).global())
+}
+
+fn rocket() -> Rocket<Build> {
+    rocket::custom(figment())
+        .attach(AdHoc::config::<AppConfig>())
         .attach(stage())
         .register("/", catchers![catchers::not_authorized, catchers::forbidden])
         .mount("/", FileServer::from(relative!("static")))
 }
+
+// Serves the app, or runs a maintenance command named by the first argument
+#[rocket::main]
+async fn main() -> ExitCode {
+    env_logger::init();
+    let args: Vec<String> = std::env::args().skip(1).collect();
+
+    match args.first().map(String::as_str) {
+        None => match rocket().launch().await {
+            Ok(_) => ExitCode::SUCCESS,
+            Err(e) => {
+                error!("Failed to launch: {}", e);
+                ExitCode::FAILURE
+            }
+        },
+        Some("fsck") => fsck::main(rocket(), &args[1..]).await,
+        Some("admin") => promote::main(rocket(), &args[1..]).await,
+        Some(command) => {
+            eprintln!(
+                "Unknown command: {}\n\n{}\n\n{}",
+                command,
+                fsck::USAGE,
+                promote::USAGE
+            );
+            ExitCode::FAILURE
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::db::queries::tests::insert_test_user;
+    use crate::models::models::Role;
+    use rocket::http::Cookie;
+    use rocket::local::asynchronous::Client;
+    use std::path::PathBuf;
+
+    /// The app with a database and stored files of its own in a new directory,
+    /// and no job workers, so uploads stay as they were saved
+    pub(crate) async fn test_client() -> (Client, PathBuf) {
+        let dir = std::env::temp_dir().join(format!("jv-app-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir(&dir).unwrap();
+        let db_url = format!("sqlite://{}?mode=rwc", dir.join("db.sqlite").display());
+        let figment = figment()
+            .merge(("databases.db.url", db_url))
+            .merge(("storage.backend", "local"))
+            .merge(("storage.path", dir.join("img")))
+            .merge(("upload_path", dir.join("uploads")))
+            .merge(("job_workers", 0));
+        std::fs::create_dir(dir.join("img")).unwrap();
+
+        let rocket = rocket::custom(figment)
+            .attach(AdHoc::config::<AppConfig>())
+            .attach(stage());
+        (Client::tracked(rocket).await.unwrap(), dir)
+    }
+
+    /// A new writer and the session cookie to send as them
+    pub(crate) async fn log_in_writer(client: &Client, email: &str) -> (i64, Cookie<'static>) {
+        let db = Db::fetch(client.rocket()).unwrap();
+        let user_id = insert_test_user(db, email).await;
+        queries::set_user_role(db, user_id, &Role::Writer)
+            .await
+            .unwrap();
+        let token = queries::create_user_session(db, email, 3600).await.unwrap();
+        (user_id, Cookie::new("s_id", token))
+    }
+
+    #[test]
+    fn test_upload_forms_fit_whole_files() {
+        let config: rocket::Config = figment().extract().unwrap();
+        let limit = constants::UPLOAD_FORM_LIMIT.bytes();
+        assert_eq!(config.limits.get("file"), Some(limit));
+        assert_eq!(config.limits.get("data-form"), Some(limit));
+    }
+}
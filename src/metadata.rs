@@ -0,0 +1,283 @@
+use crate::errors;
+use crate::models::models::ImageMetadata;
+use exif::{Exif, In, Reader, Tag, Value};
+use image::ImageFormat;
+use img_parts::webp::CHUNK_XMP;
+use img_parts::{DynImage, ImageEXIF};
+use log::info;
+use std::fs::File;
+use std::io::{BufReader, Cursor};
+
+// JPEG segments that can carry metadata: APP1 (EXIF and XMP), APP3 to APP13
+// (maker notes, IPTC, ...), APP15 and comments. APP0 (JFIF), APP2 (ICC colour
+// profile) and APP14 (Adobe colour transform) are needed to show the image right.
+fn is_jpeg_metadata(marker: u8) -> bool {
+    matches!(marker, 0xE1 | 0xE3..=0xED | 0xEF | 0xFE)
+}
+
+// PNG chunks that can carry metadata
+const PNG_METADATA_CHUNKS: [[u8; 4]; 5] = [*b"eXIf", *b"tEXt", *b"iTXt", *b"zTXt", *b"tIME"];
+
+fn ascii(exif: &Exif, tag: Tag) -> Option<String> {
+    match &exif.get_field(tag, In::PRIMARY)?.value {
+        Value::Ascii(values) => {
+            let value = String::from_utf8_lossy(values.first()?);
+            let value = value.trim_matches(char::from(0)).trim();
+            (!value.is_empty()).then(|| value.to_string())
+        }
+        _ => None,
+    }
+}
+
+fn capture_time(exif: &Exif) -> Option<String> {
+    [Tag::DateTimeOriginal, Tag::DateTime]
+        .iter()
+        .find_map(|tag| match &exif.get_field(*tag, In::PRIMARY)?.value {
+            Value::Ascii(values) => {
+                let time = exif::DateTime::from_ascii(values.first()?).ok()?;
+                Some(format!(
+                    "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+                    time.year, time.month, time.day, time.hour, time.minute, time.second
+                ))
+            }
+            _ => None,
+        })
+}
+
+// GPS coordinates are stored as degrees, minutes and seconds, with the
+// hemisphere in a separate "ref" tag
+fn coordinate(exif: &Exif, tag: Tag, ref_tag: Tag, negative_ref: &str) -> Option<f64> {
+    let parts = match &exif.get_field(tag, In::PRIMARY)?.value {
+        Value::Rational(parts) if parts.len() >= 3 => parts,
+        _ => return None,
+    };
+    if parts.iter().any(|part| part.denom == 0) {
+        return None;
+    }
+
+    let degrees = parts[0].to_f64() + parts[1].to_f64() / 60.0 + parts[2].to_f64() / 3600.0;
+
+    match ascii(exif, ref_tag) {
+        Some(hemisphere) if hemisphere.eq_ignore_ascii_case(negative_ref) => Some(-degrees),
+        _ => Some(degrees),
+    }
+}
+
+/// Read what we keep of the EXIF in the file at `path`
+///
+/// Files without EXIF, or in formats that can't carry it, give empty metadata.
+pub fn read_metadata(path: &str) -> Result<ImageMetadata, errors::AppError> {
+    let file = File::open(path)?;
+
+    let exif = match Reader::new().read_from_container(&mut BufReader::new(file)) {
+        Ok(exif) => exif,
+        Err(e) => {
+            info!("No EXIF read from {}: {}", path, e);
+            return Ok(ImageMetadata::default());
+        }
+    };
+
+    Ok(ImageMetadata {
+        capture_time: capture_time(&exif),
+        camera_make: ascii(&exif, Tag::Make),
+        camera_model: ascii(&exif, Tag::Model),
+        orientation: exif
+            .get_field(Tag::Orientation, In::PRIMARY)
+            .and_then(|field| field.value.get_uint(0)),
+        latitude: coordinate(&exif, Tag::GPSLatitude, Tag::GPSLatitudeRef, "S"),
+        longitude: coordinate(&exif, Tag::GPSLongitude, Tag::GPSLongitudeRef, "W"),
+    })
+}
+
+/// Remove EXIF (GPS, camera serial numbers, ...) and other metadata from the
+/// image at `path`, in place
+///
+/// JPEG, PNG and WebP keep their pixels untouched. Anything else is decoded and
+/// saved again as PNG, which never copies metadata across.
+pub fn strip_metadata(path: &str) -> Result<(), errors::AppError> {
+    let bytes = std::fs::read(path)?;
+
+    let stripped = match DynImage::from_bytes(bytes.clone().into())? {
+        Some(DynImage::Jpeg(mut jpeg)) => {
+            jpeg.segments_mut()
+                .retain(|segment| !is_jpeg_metadata(segment.marker()));
+            jpeg.encoder().bytes().to_vec()
+        }
+        Some(DynImage::Png(mut png)) => {
+            png.chunks_mut()
+                .retain(|chunk| !PNG_METADATA_CHUNKS.contains(&chunk.kind()));
+            png.encoder().bytes().to_vec()
+        }
+        Some(DynImage::WebP(mut webp)) => {
+            webp.set_exif(None);
+            webp.remove_chunks_by_id(CHUNK_XMP);
+            webp.encoder().bytes().to_vec()
+        }
+        None => {
+            info!("Re-encoding {} to strip its metadata", path);
+            let mut png = Cursor::new(Vec::new());
+            image::load_from_memory(&bytes)?.write_to(&mut png, ImageFormat::Png)?;
+            png.into_inner()
+        }
+    };
+
+    std::fs::write(path, stripped)?;
+    Ok(())
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use exif::experimental::Writer;
+    use exif::{Field, Rational};
+    use image::codecs::jpeg::JpegEncoder;
+    use image::RgbImage;
+
+    fn ascii_field(tag: Tag, value: &str) -> Field {
+        Field {
+            tag,
+            ifd_num: In::PRIMARY,
+            value: Value::Ascii(vec![value.as_bytes().to_vec()]),
+        }
+    }
+
+    fn dms_field(tag: Tag, degrees: u32, minutes: u32, seconds: u32) -> Field {
+        Field {
+            tag,
+            ifd_num: In::PRIMARY,
+            value: Value::Rational(vec![
+                Rational::from((degrees, 1)),
+                Rational::from((minutes, 1)),
+                Rational::from((seconds, 1)),
+            ]),
+        }
+    }
+
+    /// A small JPEG with the EXIF a phone would write, including GPS
+    pub fn jpeg_with_exif() -> Vec<u8> {
+        let fields = vec![
+            ascii_field(Tag::Make, "Apple"),
+            ascii_field(Tag::Model, "iPhone 12"),
+            ascii_field(Tag::DateTimeOriginal, "2023:05:01 12:34:56"),
+            ascii_field(Tag::GPSLatitudeRef, "N"),
+            dms_field(Tag::GPSLatitude, 51, 30, 0),
+            ascii_field(Tag::GPSLongitudeRef, "W"),
+            dms_field(Tag::GPSLongitude, 0, 7, 48),
+            Field {
+                tag: Tag::Orientation,
+                ifd_num: In::PRIMARY,
+                value: Value::Short(vec![6]),
+            },
+        ];
+        let mut writer = Writer::new();
+        for field in &fields {
+            writer.push_field(field);
+        }
+        let mut tiff = Cursor::new(Vec::new());
+        writer.write(&mut tiff, false).unwrap();
+        let tiff = tiff.into_inner();
+
+        let mut jpeg = Vec::new();
+        RgbImage::new(64, 48)
+            .write_with_encoder(JpegEncoder::new(&mut jpeg))
+            .unwrap();
+
+        // EXIF lives in an APP1 segment straight after the start of image marker
+        let mut app1 = vec![0xFF, 0xE1];
+        app1.extend_from_slice(&((tiff.len() + 8) as u16).to_be_bytes());
+        app1.extend_from_slice(b"Exif\0\0");
+        app1.extend_from_slice(&tiff);
+
+        let mut with_exif = jpeg[..2].to_vec();
+        with_exif.extend_from_slice(&app1);
+        with_exif.extend_from_slice(&jpeg[2..]);
+        with_exif
+    }
+
+    fn temp_file(bytes: &[u8]) -> String {
+        let path = std::env::temp_dir().join(format!("jv-test-{}", uuid::Uuid::new_v4()));
+        std::fs::write(&path, bytes).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn test_read_metadata() {
+        let path = temp_file(&jpeg_with_exif());
+
+        let metadata = read_metadata(&path).unwrap();
+
+        assert_eq!(
+            metadata.capture_time.as_deref(),
+            Some("2023-05-01 12:34:56")
+        );
+        assert_eq!(metadata.camera().as_deref(), Some("Apple iPhone 12"));
+        assert_eq!(metadata.captured_on().as_deref(), Some("1 May 2023"));
+        assert_eq!(metadata.orientation, Some(6));
+        assert_eq!(metadata.latitude, Some(51.5));
+        assert!((metadata.longitude.unwrap() + 0.13).abs() < 1e-9);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_strip_metadata_removes_gps() {
+        let path = temp_file(&jpeg_with_exif());
+
+        strip_metadata(&path).unwrap();
+
+        assert_eq!(read_metadata(&path).unwrap(), ImageMetadata::default());
+        // Still a good image
+        let image = image::load_from_memory(&std::fs::read(&path).unwrap()).unwrap();
+        assert_eq!((image.width(), image.height()), (64, 48));
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_strip_metadata_of_png() {
+        let mut png = Cursor::new(Vec::new());
+        RgbImage::new(8, 8)
+            .write_to(&mut png, ImageFormat::Png)
+            .unwrap();
+        let mut png = img_parts::png::Png::from_bytes(png.into_inner().into()).unwrap();
+        // Put the EXIF from the JPEG into the PNG
+        let jpeg = img_parts::jpeg::Jpeg::from_bytes(jpeg_with_exif().into()).unwrap();
+        png.set_exif(jpeg.exif());
+        let path = temp_file(&png.encoder().bytes());
+        assert!(read_metadata(&path).unwrap().latitude.is_some());
+
+        strip_metadata(&path).unwrap();
+
+        assert_eq!(read_metadata(&path).unwrap(), ImageMetadata::default());
+        image::load_from_memory(&std::fs::read(&path).unwrap()).unwrap();
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_renditions_have_no_gps() {
+        let path = temp_file(&jpeg_with_exif());
+
+        crate::images::create_renditions(&path, &[32]).unwrap();
+
+        for format in crate::images::RenditionFormat::ALL {
+            let rendition = crate::images::rendition_path(&path, 32, format);
+            assert_eq!(read_metadata(&rendition).unwrap().latitude, None);
+            std::fs::remove_file(rendition).unwrap();
+        }
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_read_metadata_without_exif() {
+        let mut jpeg = Vec::new();
+        RgbImage::new(8, 8)
+            .write_with_encoder(JpegEncoder::new(&mut jpeg))
+            .unwrap();
+        let path = temp_file(&jpeg);
+
+        assert_eq!(read_metadata(&path).unwrap(), ImageMetadata::default());
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -1,7 +1,9 @@
+use crate::config::AppConfig;
+use crate::errors;
 use crate::models::models::{Session, Role};
 use crate::queries;
 use crate::queries::Db;
-use log::debug;
+use log::{debug, warn};
 use rocket::http::Status;
 use rocket::request::{FromRequest, Outcome, Request};
 
@@ -31,8 +33,15 @@ impl<'r> FromRequest<'r> for Session {
             None => return Outcome::Error((Status::InternalServerError, ())),
         };
 
+        let config = match request.rocket().state::<AppConfig>() {
+            Some(config) => config,
+            None => return Outcome::Error((Status::InternalServerError, ())),
+        };
+
         debug!("Getting user id from session token");
 
+        // Expired sessions are filtered out here, so a stale cookie is treated
+        // the same as a missing one
         let user = queries::get_user_from_session_token(&session_token, pool).await;
 
         let user = match user {
@@ -40,12 +49,23 @@ impl<'r> FromRequest<'r> for Session {
             Err(_) => return Outcome::Error((Status::Unauthorized, ())),
         };
 
+        let renewed = queries::renew_user_session(
+            pool,
+            &session_token,
+            config.session_renewal_window,
+            config.session_length,
+        )
+        .await;
+
+        match renewed {
+            Ok(true) => debug!("Renewed session for user: {}", user.email),
+            Ok(false) => (),
+            Err(e) => warn!("Failed to renew session: {}", e),
+        }
+
         debug!("Getting session for user: (user_id: {:?})", user);
 
-        let session = Session {
-            session_token,
-            user,
-        };
+        let session = Session { user };
 
         debug!("Session: {:?}", session);
 
@@ -53,10 +73,12 @@ impl<'r> FromRequest<'r> for Session {
     }
 }
 
-/// Request guard that ensures the user has Writer role authorization
+/// Request guard that ensures the user has Writer (or Admin) role authorization
 /// 
 /// This guard combines authentication (via Session) with authorization,
-/// ensuring that only users with Writer role can access protected routes.
+/// ensuring that only users with Writer or Admin role can access protected routes.
+/// Writers can only change galleries they own or were granted, so routes that
+/// touch a specific gallery must also call `ensure_can_edit_gallery`.
 /// 
 /// Returns:
 /// - Success: WriterSession containing the authenticated session
@@ -67,15 +89,76 @@ pub struct WriterSession {
 }
 
 impl WriterSession {
-    /// Access the underlying session
-    pub fn session(&self) -> &Session {
-        &self.session
-    }
-    
     /// Access the user from the session
     pub fn user(&self) -> &crate::models::models::User {
         &self.session.user
     }
+
+    /// Fails with Forbidden (403) unless the user may change the gallery
+    pub async fn ensure_can_edit_gallery(
+        &self,
+        db: &Db,
+        gallery_id: i64,
+    ) -> Result<(), errors::AppError> {
+        if queries::can_edit_gallery(db, self.user(), gallery_id).await? {
+            Ok(())
+        } else {
+            debug!("{} may not edit gallery {}", self.user().email, gallery_id);
+            Err(forbidden())
+        }
+    }
+
+    /// Fails with Forbidden (403) unless the user may change the gallery the image is in
+    pub async fn ensure_can_edit_image(
+        &self,
+        db: &Db,
+        image_id: i64,
+    ) -> Result<(), errors::AppError> {
+        match queries::get_image_gallery_id(db, image_id).await? {
+            Some(gallery_id) => self.ensure_can_edit_gallery(db, gallery_id).await,
+            None => Err(errors::AppError {
+                code: Status::NotFound.code,
+                message: "Image not found".to_string(),
+            }),
+        }
+    }
+
+    /// Fails with Forbidden (403) unless the gallery is in the trash and the user
+    /// could edit it once restored
+    pub async fn ensure_can_restore_gallery(
+        &self,
+        db: &Db,
+        gallery_id: i64,
+    ) -> Result<(), errors::AppError> {
+        if queries::can_restore_gallery(db, self.user(), gallery_id).await? {
+            Ok(())
+        } else {
+            debug!("{} may not restore gallery {}", self.user().email, gallery_id);
+            Err(forbidden())
+        }
+    }
+
+    /// Fails with Forbidden (403) unless the user may decide who sees or edits the
+    /// gallery
+    pub async fn ensure_can_manage_gallery(
+        &self,
+        db: &Db,
+        gallery_id: i64,
+    ) -> Result<(), errors::AppError> {
+        if queries::can_manage_gallery(db, self.user(), gallery_id).await? {
+            Ok(())
+        } else {
+            debug!("{} may not manage gallery {}", self.user().email, gallery_id);
+            Err(forbidden())
+        }
+    }
+}
+
+fn forbidden() -> errors::AppError {
+    errors::AppError {
+        code: Status::Forbidden.code,
+        message: "You don't have permission to change this gallery".to_string(),
+    }
 }
 
 #[rocket::async_trait]
@@ -100,7 +183,7 @@ impl<'r> FromRequest<'r> for WriterSession {
         
         // Then check if user has Writer role authorization
         match session.user.role {
-            Role::Writer => {
+            Role::Writer | Role::Admin => {
                 debug!("Authorization successful for Writer user: {}", session.user.email);
                 Outcome::Success(WriterSession { session })
             }
@@ -111,3 +194,52 @@ impl<'r> FromRequest<'r> for WriterSession {
         }
     }
 }
+
+/// Request guard that ensures the user has Admin role authorization
+///
+/// Returns:
+/// - Success: AdminSession containing the authenticated session
+/// - Forbidden (403): User is authenticated but is not an Admin
+/// - Unauthorized (401): User is not authenticated
+pub struct AdminSession {
+    pub session: Session,
+}
+
+impl AdminSession {
+    /// Access the user from the session
+    pub fn user(&self) -> &crate::models::models::User {
+        &self.session.user
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for AdminSession {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<AdminSession, ()> {
+        debug!("Checking AdminSession authorization");
+
+        let session = match Session::from_request(request).await {
+            Outcome::Success(session) => session,
+            Outcome::Error(e) => {
+                debug!("Authentication failed: {:?}", e);
+                return Outcome::Error(e);
+            }
+            Outcome::Forward(status) => {
+                debug!("Authentication forwarded");
+                return Outcome::Forward(status);
+            }
+        };
+
+        match session.user.role {
+            Role::Admin => {
+                debug!("Authorization successful for Admin user: {}", session.user.email);
+                Outcome::Success(AdminSession { session })
+            }
+            Role::Reader | Role::Writer => {
+                debug!("Authorization denied for non-Admin user: {}", session.user.email);
+                Outcome::Error((Status::Forbidden, ()))
+            }
+        }
+    }
+}
@@ -3,8 +3,9 @@ use chrono::DateTime;
 use chrono_humanize::{Accuracy, Tense};
 use log::warn;
 use rocket::fs::TempFile;
+use rocket::http::ContentType;
 use rocket::serde::{Deserialize, Serialize};
-use rocket::FromForm;
+use rocket::{FromForm, FromFormField};
 
 #[derive(FromForm, Clone)]
 pub struct UserSignup {
@@ -18,11 +19,163 @@ pub struct UserLogin<'f> {
     pub password: &'f str,
 }
 
+#[derive(FromForm)]
+pub struct ResendVerification<'f> {
+    pub email: &'f str,
+}
+
+#[derive(FromForm)]
+pub struct PasswordForgot<'f> {
+    pub email: &'f str,
+}
+
+#[derive(FromForm)]
+pub struct PasswordReset<'f> {
+    pub password: &'f str,
+    pub password_repeat: &'f str,
+}
+
 #[derive(FromForm)]
 pub struct ImgUpload<'f> {
     pub file: TempFile<'f>,
-    pub modified_file: TempFile<'f>,
+    /// Videos are sent without one
+    pub crop: Option<Crop>,
+    pub caption: &'f str,
+}
+
+/// Clockwise turn applied to an original before it is cropped
+#[derive(FromFormField, Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(crate = "rocket::serde")]
+pub enum Rotation {
+    #[field(value = "0")]
+    None,
+    #[field(value = "90")]
+    Quarter,
+    #[field(value = "180")]
+    Half,
+    #[field(value = "270")]
+    ThreeQuarters,
+}
+
+impl Rotation {
+    pub fn degrees(&self) -> i64 {
+        match self {
+            Rotation::None => 0,
+            Rotation::Quarter => 90,
+            Rotation::Half => 180,
+            Rotation::ThreeQuarters => 270,
+        }
+    }
+
+    pub fn from_degrees(degrees: i64) -> Option<Rotation> {
+        match degrees {
+            0 => Some(Rotation::None),
+            90 => Some(Rotation::Quarter),
+            180 => Some(Rotation::Half),
+            270 => Some(Rotation::ThreeQuarters),
+            _ => None,
+        }
+    }
+}
+
+/// The part of an original an image shows, in pixels of the original once it
+/// has been turned the way its EXIF says and then rotated
+#[derive(FromForm, Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(crate = "rocket::serde")]
+pub struct Crop {
+    pub x: u32,
+    pub y: u32,
+    #[field(validate = range(1..))]
+    pub width: u32,
+    #[field(validate = range(1..))]
+    pub height: u32,
+    #[field(default = Rotation::None)]
+    pub rotation: Rotation,
+}
+
+impl Crop {
+    /// The whole image, whatever its size, as crops are cut back to fit
+    pub fn whole() -> Crop {
+        Crop {
+            x: 0,
+            y: 0,
+            width: u32::MAX,
+            height: u32::MAX,
+            rotation: Rotation::None,
+        }
+    }
+}
+
+#[derive(FromForm)]
+pub struct BatchUpload<'f> {
+    pub files: Vec<TempFile<'f>>,
+}
+
+/// What happened to one file of a batch upload
+#[derive(Serialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct BatchUploadResult {
+    pub name: String,
+    pub image: Option<Image>,
+    pub error: Option<String>,
+}
+
+#[derive(FromForm)]
+pub struct UploadCreate<'f> {
+    pub file_name: &'f str,
+    #[field(validate = range(1..))]
+    pub length: i64,
+}
+
+#[derive(FromForm)]
+pub struct UploadFinish<'f> {
     pub caption: &'f str,
+    pub crop: Option<Crop>,
+}
+
+/// A chunked upload that hasn't been finished yet
+#[derive(Debug, PartialEq)]
+pub struct Upload {
+    pub id: String,
+    pub gallery_id: i64,
+    pub file_name: String,
+    /// Size of the whole file, in bytes
+    pub length: i64,
+    /// How many bytes have arrived, always from the start of the file
+    pub received: i64,
+}
+
+impl Upload {
+    /// Where the bytes received so far are kept, in `AppConfig::upload_path`
+    pub fn part_path(&self, upload_path: &str) -> String {
+        format!("{}/{}.part", upload_path, self.id)
+    }
+}
+
+#[derive(FromForm)]
+pub struct ImageEdit {
+    pub crop: Crop,
+}
+
+/// The original an image was cut from, and how
+#[derive(Debug)]
+pub struct ImageSource {
+    pub original_path: String,
+    /// Saved before originals had their own paths, so `original_path` is the
+    /// image itself until `fsck --relink-originals` finds the original
+    pub is_legacy: bool,
+    /// None for images cropped in the browser before crops were stored
+    pub crop: Option<Crop>,
+}
+
+/// One of the versions of an image made from the same original
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct ImageVersion {
+    pub id: i64,
+    pub path: String,
+    pub is_current: bool,
+    pub time_created: String,
 }
 
 #[derive(FromForm)]
@@ -40,11 +193,82 @@ pub struct CreateGallery<'f> {
     pub name: Option<&'f str>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(FromForm)]
+pub struct VisibilityUpdate {
+    pub visibility: Visibility,
+}
+
+#[derive(FromForm)]
+pub struct GalleryUserAdd<'f> {
+    pub email: &'f str,
+}
+
+#[derive(FromForm)]
+pub struct RoleUpdate {
+    pub role: Role,
+}
+
+/// Who can see a gallery, stored in `galleries.status`
+///
+/// The owner of a gallery can always see it. Beyond that:
+/// - `Public`: every logged in user
+/// - `Family`: users with the Writer role
+/// - `Shared`: users listed in `gallery_members`
+/// - `Private`: nobody else, useful for staging a gallery before publishing it
+#[derive(Serialize, Deserialize, FromFormField, Debug, Clone, Copy, PartialEq)]
+#[serde(crate = "rocket::serde")]
+pub enum Visibility {
+    Public,
+    Family,
+    Shared,
+    Private,
+}
+
+impl Visibility {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Visibility::Public => "public",
+            Visibility::Family => "family",
+            Visibility::Shared => "shared",
+            Visibility::Private => "private",
+        }
+    }
+
+    pub fn from_status(status: &str) -> Visibility {
+        match status {
+            "public" => Visibility::Public,
+            "family" => Visibility::Family,
+            "shared" => Visibility::Shared,
+            // Anything we don't recognise stays hidden
+            _ => Visibility::Private,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, FromFormField, Debug, Clone, Copy, PartialEq)]
 #[serde(crate = "rocket::serde")]
 pub enum Role {
     Reader,
     Writer,
+    Admin,
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Reader => "reader",
+            Role::Writer => "writer",
+            Role::Admin => "admin",
+        }
+    }
+
+    pub fn from_db(role: &str) -> Role {
+        match role {
+            "writer" => Role::Writer,
+            "admin" => Role::Admin,
+            _ => Role::Reader,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -57,10 +281,30 @@ pub struct User {
 
 #[derive(Debug)]
 pub struct Session {
-    pub session_token: String,
     pub user: User,
 }
 
+/// What we found when looking up a verification token
+#[derive(Debug, PartialEq)]
+pub enum TokenStatus {
+    Valid(i64),
+    Expired,
+    Used,
+    NotFound,
+}
+
+impl TokenStatus {
+    /// Short description of why a token can't be used, for the "link expired" page
+    pub fn reason(&self) -> &'static str {
+        match self {
+            TokenStatus::Valid(_) => "valid",
+            TokenStatus::Expired => "expired",
+            TokenStatus::Used => "used",
+            TokenStatus::NotFound => "not_found",
+        }
+    }
+}
+
 pub struct SaltedPassword {
     pub password_hash: String,
     pub salt: SaltString,
@@ -78,6 +322,118 @@ pub struct Image {
     pub path: String,
     pub original_path: Option<String>,
     pub caption: String,
+    pub status: ImageStatus,
+    pub media_type: MediaType,
+    /// An earlier image in the gallery looks like this one
+    pub near_duplicate: bool,
+}
+
+/// What an upload is, stored in `modified_images.media_type`
+///
+/// Videos live alongside images and share their rows, but are transcoded instead
+/// of cropped and get renditions of a poster frame.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(crate = "rocket::serde")]
+pub enum MediaType {
+    Image,
+    Video,
+}
+
+impl MediaType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MediaType::Image => "image",
+            MediaType::Video => "video",
+        }
+    }
+
+    pub fn from_media_type(media_type: &str) -> MediaType {
+        match media_type {
+            "video" => MediaType::Video,
+            _ => MediaType::Image,
+        }
+    }
+
+    /// Videos are told apart by the top level of their content type
+    pub fn from_content_type(content_type: Option<&ContentType>) -> MediaType {
+        match content_type {
+            Some(content_type) if content_type.top() == "video" => MediaType::Video,
+            _ => MediaType::Image,
+        }
+    }
+}
+
+/// Where an image is in processing, stored in `modified_images.status`
+///
+/// Deleted images, and versions replaced by a later edit ("superseded"), also
+/// live in that column but are never loaded as an `Image`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(crate = "rocket::serde")]
+pub enum ImageStatus {
+    /// Waiting for a worker to make the renditions
+    Processing,
+    /// Processing gave up after too many attempts
+    Failed,
+    Ready,
+}
+
+impl ImageStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ImageStatus::Processing => "processing",
+            ImageStatus::Failed => "failed",
+            // Stored as 'public' since before images were processed in the background
+            ImageStatus::Ready => "public",
+        }
+    }
+
+    pub fn from_status(status: &str) -> ImageStatus {
+        match status {
+            "processing" => ImageStatus::Processing,
+            "failed" => ImageStatus::Failed,
+            _ => ImageStatus::Ready,
+        }
+    }
+}
+
+/// Work for the background workers, stored as JSON in `jobs.payload`
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(crate = "rocket::serde", tag = "kind")]
+pub enum Job {
+    /// Make the renditions for an image uploaded already cropped
+    ProcessImage { image_id: i64, path: String },
+    /// Cut an image out of its original, then make its renditions
+    CropImage {
+        image_id: i64,
+        original_path: String,
+        path: String,
+        crop: Crop,
+    },
+    /// Transcode a video for the web from its original, then make renditions of
+    /// its poster frame
+    ProcessVideo {
+        image_id: i64,
+        original_path: String,
+        path: String,
+    },
+    /// Work out the perceptual hash of an original, to find near duplicates
+    HashImage {
+        image_id: i64,
+        original_path: String,
+    },
+    /// Read the EXIF from the original an image was made from
+    ReadMetadata {
+        image_id: i64,
+        original_path: String,
+    },
+}
+
+/// A job a worker has claimed from the queue
+#[derive(Debug)]
+pub struct QueuedJob {
+    pub id: i64,
+    pub attempts: i64,
+    pub job: Job,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -85,53 +441,183 @@ pub struct Image {
 pub struct GalleryTile {
     pub id: i64,
     pub name: String,
+    pub visibility: Visibility,
     pub example_image_path: Option<String>,
     pub image_count: i64,
     pub time_created: String,
     pub time_created_human: String,
     pub created_by: String,
+    pub can_edit: bool,
 }
 
 impl GalleryTile {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         id: i64,
         name: String,
+        visibility: Visibility,
         example_image_path: Option<String>,
         image_count: i64,
         time_created: String,
         created_by: String,
+        can_edit: bool,
     ) -> GalleryTile {
-        let time_created_chrono =
-            chrono::NaiveDateTime::parse_from_str(&time_created, "%Y-%m-%d %H:%M:%S");
-        let human_time = match time_created_chrono {
-            Ok(time) => chrono_humanize::HumanTime::from(
-                DateTime::<chrono::Utc>::from_naive_utc_and_offset(time, chrono::Utc),
-            ),
-            Err(err) => {
-                warn!(
-                    "Error parsing time_created: {}, due to {}",
-                    time_created, err
-                );
-                chrono_humanize::HumanTime::from(chrono::Utc::now())
-            }
-        };
         GalleryTile {
             id,
             name,
+            visibility,
             example_image_path,
             image_count,
+            time_created_human: human_time(&time_created),
             time_created,
-            time_created_human: human_time.to_text_en(Accuracy::Rough, Tense::Past),
             created_by,
+            can_edit,
         }
     }
 }
 
+/// A database timestamp as how long ago it was, e.g. "3 days ago"
+pub fn human_time(time: &str) -> String {
+    let parsed = chrono::NaiveDateTime::parse_from_str(time, "%Y-%m-%d %H:%M:%S");
+    let human_time = match parsed {
+        Ok(time) => chrono_humanize::HumanTime::from(
+            DateTime::<chrono::Utc>::from_naive_utc_and_offset(time, chrono::Utc),
+        ),
+        Err(err) => {
+            warn!("Error parsing time: {}, due to {}", time, err);
+            chrono_humanize::HumanTime::from(chrono::Utc::now())
+        }
+    };
+    human_time.to_text_en(Accuracy::Rough, Tense::Past)
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(crate = "rocket::serde")]
 pub struct GalleryContents {
     pub id: i64,
     pub name: String,
+    pub visibility: Visibility,
+    pub can_edit: bool,
     pub images: Vec<Image>,
     pub time_created: String,
 }
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct GalleryUser {
+    pub user_id: i64,
+    pub email: String,
+}
+
+/// A row in the admin console's list of users
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct UserSummary {
+    pub id: i64,
+    pub email: String,
+    pub role: Role,
+    pub is_verified: bool,
+    pub is_disabled: bool,
+    pub time_created: String,
+    pub session_count: i64,
+}
+
+/// An image as the storage check sees it
+#[derive(Debug, Clone)]
+pub struct StoredImage {
+    pub path: String,
+    pub original_path: String,
+    pub media_type: MediaType,
+    /// Shown, or kept as an earlier version, so its file and original must exist
+    pub needs_files: bool,
+    /// Shown now, so it must have every rendition
+    pub needs_renditions: bool,
+}
+
+/// An original saved before its own path was stored, for the storage check to find
+#[derive(Debug, Clone)]
+pub struct LegacyOriginal {
+    pub id: i64,
+    /// The newest image made from it
+    pub image_id: i64,
+    /// The path stored for it, which is that of the first image made from it
+    pub path: String,
+    /// When it was uploaded, in seconds since the epoch
+    pub time_created: i64,
+}
+
+/// An image that looks like others, for the admin's duplicates page
+#[derive(Serialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct DuplicateImage {
+    pub id: i64,
+    pub path: String,
+    pub original_filename: String,
+    pub gallery_id: i64,
+    pub gallery_name: String,
+}
+
+/// A deleted gallery, for the trash page
+#[derive(Serialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct TrashedGallery {
+    pub id: i64,
+    pub name: String,
+    pub image_count: i64,
+    /// Email of whoever deleted it, unknown for galleries deleted before the trash
+    pub deleted_by: Option<String>,
+    pub time_deleted_human: String,
+}
+
+/// A deleted image in a gallery that is still there, for the trash page
+#[derive(Serialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct TrashedImage {
+    pub id: i64,
+    pub path: String,
+    pub caption: String,
+    pub original_filename: String,
+    pub media_type: MediaType,
+    /// Whether it had been processed, so has renditions to show
+    pub ready: bool,
+    pub gallery_id: i64,
+    pub gallery_name: String,
+    /// Email of whoever deleted it, unknown for images deleted before the trash
+    pub deleted_by: Option<String>,
+    pub time_deleted_human: String,
+}
+
+/// What we could read from an original's EXIF, stored in `image_metadata`
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
+#[serde(crate = "rocket::serde")]
+pub struct ImageMetadata {
+    /// When the photo was taken, as `YYYY-MM-DD HH:MM:SS` in the camera's local time
+    pub capture_time: Option<String>,
+    pub camera_make: Option<String>,
+    pub camera_model: Option<String>,
+    pub orientation: Option<u32>,
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+}
+
+impl ImageMetadata {
+    /// The camera as people would name it, e.g. "Apple iPhone 12"
+    pub fn camera(&self) -> Option<String> {
+        match (&self.camera_make, &self.camera_model) {
+            // Most models already start with the make, e.g. "Canon EOS 80D"
+            (Some(make), Some(model)) if model.starts_with(make.as_str()) => Some(model.clone()),
+            (Some(make), Some(model)) => Some(format!("{} {}", make, model)),
+            (None, Some(model)) => Some(model.clone()),
+            (Some(make), None) => Some(make.clone()),
+            (None, None) => None,
+        }
+    }
+
+    /// The capture date for people to read, e.g. "1 May 2023"
+    pub fn captured_on(&self) -> Option<String> {
+        let capture_time = self.capture_time.as_ref()?;
+        let capture_time =
+            chrono::NaiveDateTime::parse_from_str(capture_time, "%Y-%m-%d %H:%M:%S").ok()?;
+        Some(capture_time.format("%-d %B %Y").to_string())
+    }
+}
@@ -0,0 +1,88 @@
+use crate::db::queries;
+use crate::db::queries::Db;
+use rocket::{Build, Rocket};
+use rocket_db_pools::Database;
+use std::process::ExitCode;
+
+// Makes a user an admin, which is how a new install gets its first one. Run as
+// `john_rocket admin EMAIL` once that user has signed up; everyone after them
+// can be approved from the admin console.
+
+pub static USAGE: &str = "\
+Usage: john_rocket admin EMAIL
+
+Makes the user who signed up with EMAIL an admin, approving and enabling them
+if they weren't already.";
+
+pub fn parse_args(args: &[String]) -> Result<&str, String> {
+    match args {
+        [email] if !email.starts_with("--") => Ok(email),
+        [] => Err("Missing the email of the user to make an admin".to_string()),
+        _ => Err(format!("Unexpected arguments: {}", args.join(" "))),
+    }
+}
+
+pub async fn main(rocket: Rocket<Build>, args: &[String]) -> ExitCode {
+    let email = match parse_args(args) {
+        Ok(email) => email,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    // Igniting runs the migrations and opens the database, but starts none of
+    // the workers
+    let rocket = match rocket.ignite().await {
+        Ok(rocket) => rocket,
+        Err(e) => {
+            eprintln!("Couldn't start: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    let db = match Db::fetch(&rocket) {
+        Some(db) => db,
+        None => {
+            eprintln!("Database not available");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    match queries::make_admin(db, email).await {
+        Ok(true) => {
+            println!("{} is now an admin", email);
+            ExitCode::SUCCESS
+        }
+        Ok(false) => {
+            eprintln!(
+                "No user with the email {}, they need to sign up first",
+                email
+            );
+            ExitCode::FAILURE
+        }
+        Err(e) => {
+            eprintln!("Couldn't make {} an admin: {}", email, e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_args() {
+        assert_eq!(
+            parse_args(&args(&["admin@example.com"])),
+            Ok("admin@example.com")
+        );
+        assert!(parse_args(&args(&[])).is_err());
+        assert!(parse_args(&args(&["--verify"])).is_err());
+        assert!(parse_args(&args(&["a@example.com", "b@example.com"])).is_err());
+    }
+}
@@ -0,0 +1,131 @@
+use crate::constants;
+use crate::errors;
+use crate::storage::{Storage, StoredFile, StoredInfo};
+use rocket::http::{Header, Status};
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::tokio;
+use rocket::Responder;
+
+/// The `Range` header of a request, if it has one
+pub struct RangeRequest<'r>(pub Option<&'r str>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for RangeRequest<'r> {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, ()> {
+        Outcome::Success(RangeRequest(request.headers().get_one("Range")))
+    }
+}
+
+/// The bytes asked for by a `Range` header, as an inclusive start and end
+///
+/// Only single ranges are supported, which is all browsers ask for when playing
+/// video. No more than `max_length` bytes are given, clients ask again for the
+/// rest. None if the header can't be satisfied for a file of `file_length` bytes.
+pub fn parse_range(header: &str, file_length: u64, max_length: u64) -> Option<(u64, u64)> {
+    let spec = header.trim().strip_prefix("bytes=")?;
+    if spec.contains(',') || file_length == 0 {
+        return None;
+    }
+    let (start, end) = spec.split_once('-')?;
+    let (start, end) = match (start.trim(), end.trim()) {
+        // The last `end` bytes
+        ("", end) => {
+            let suffix: u64 = end.parse().ok()?;
+            if suffix == 0 {
+                return None;
+            }
+            (file_length.saturating_sub(suffix), file_length - 1)
+        }
+        (start, "") => (start.parse().ok()?, file_length - 1),
+        (start, end) => (start.parse().ok()?, end.parse().ok()?),
+    };
+    if start > end || start >= file_length {
+        return None;
+    }
+
+    let end = end.min(file_length - 1).min(start + max_length.max(1) - 1);
+    Some((start, end))
+}
+
+/// Part of a stored file, in answer to a `Range` request
+#[derive(Responder)]
+pub struct PartialFile {
+    inner: (Status, StoredFile),
+    content_range: Header<'static>,
+    accept_ranges: Header<'static>,
+}
+
+impl PartialFile {
+    /// Read the part of the file stored as `key`, which `info` describes, that
+    /// `range` asks for
+    ///
+    /// Ranges that can't be satisfied get a 416 with the file's length, as the
+    /// client must ask again.
+    pub async fn open(
+        storage: &dyn Storage,
+        key: &str,
+        info: &StoredInfo,
+        range: &str,
+    ) -> Result<Option<PartialFile>, errors::AppError> {
+        let (start, end) = match parse_range(range, info.length, constants::MAX_RANGE_LENGTH) {
+            Some(range) => range,
+            None => {
+                let empty = StoredFile::new(key, Box::pin(tokio::io::empty()), 0);
+                return Ok(Some(PartialFile {
+                    inner: (Status::RangeNotSatisfiable, empty),
+                    content_range: Header::new("Content-Range", format!("bytes */{}", info.length)),
+                    accept_ranges: Header::new("Accept-Ranges", "bytes"),
+                }));
+            }
+        };
+
+        let body = match storage.stream(key, Some((start, end))).await? {
+            Some(body) => body,
+            None => return Ok(None),
+        };
+        Ok(Some(PartialFile {
+            inner: (
+                Status::PartialContent,
+                StoredFile::new(key, body, end - start + 1),
+            ),
+            content_range: Header::new(
+                "Content-Range",
+                format!("bytes {}-{}/{}", start, end, info.length),
+            ),
+            accept_ranges: Header::new("Accept-Ranges", "bytes"),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(parse_range("bytes=0-99", 1000, 500), Some((0, 99)));
+        assert_eq!(parse_range("bytes=0-1", 1000, 500), Some((0, 1)));
+        // Open ranges and big ranges are cut down to the most we send at once
+        assert_eq!(parse_range("bytes=0-", 1000, 500), Some((0, 499)));
+        assert_eq!(parse_range("bytes=900-", 1000, 500), Some((900, 999)));
+        assert_eq!(parse_range("bytes=100-5000", 1000, 500), Some((100, 599)));
+        // The end of the file
+        assert_eq!(parse_range("bytes=-100", 1000, 500), Some((900, 999)));
+        assert_eq!(parse_range("bytes=-5000", 1000, 500), Some((0, 499)));
+        // Past the end of the file
+        assert_eq!(parse_range("bytes=995-2000", 1000, 500), Some((995, 999)));
+        assert_eq!(parse_range("bytes=1000-", 1000, 500), None);
+    }
+
+    #[test]
+    fn test_parse_bad_range() {
+        assert_eq!(parse_range("bytes=5-1", 1000, 500), None);
+        assert_eq!(parse_range("bytes=-0", 1000, 500), None);
+        assert_eq!(parse_range("bytes=0-1,5-9", 1000, 500), None);
+        assert_eq!(parse_range("items=0-1", 1000, 500), None);
+        assert_eq!(parse_range("bytes=a-b", 1000, 500), None);
+        assert_eq!(parse_range("bytes=0-", 0, 500), None);
+    }
+}
@@ -0,0 +1,194 @@
+use crate::config::AppConfig;
+use crate::db::queries;
+use crate::db::queries::Db;
+use crate::errors;
+use crate::middleware::AdminSession;
+use crate::models::models;
+use crate::routes::signup;
+use crate::tera_utils;
+
+use log::info;
+use rocket::form::Form;
+use rocket::http::Status;
+use rocket::response::content;
+use rocket::{get, post, put, State};
+
+#[get("/admin/users")]
+pub async fn get_users(
+    db: &Db,
+    admin_session: AdminSession,
+) -> Result<content::RawHtml<String>, errors::AppError> {
+    let accounts = queries::get_users(db).await?;
+
+    let mut context = tera::Context::new();
+    context.insert("user", admin_session.user());
+    context.insert("accounts", &accounts);
+
+    let users = tera_utils::render_template_with_logging("admin_users.html", &context)?;
+    Ok(content::RawHtml(users))
+}
+
+// Photos that look alike, across every gallery, so copies can be tidied up
+#[get("/admin/duplicates")]
+pub async fn get_duplicates(
+    db: &Db,
+    admin_session: AdminSession,
+    config: &State<AppConfig>,
+) -> Result<content::RawHtml<String>, errors::AppError> {
+    let clusters = queries::get_near_duplicates(db, config.near_duplicate_distance).await?;
+
+    let mut context = tera::Context::new();
+    context.insert("user", admin_session.user());
+    context.insert("clusters", &clusters);
+
+    let duplicates = tera_utils::render_template_with_logging("admin_duplicates.html", &context)?;
+    Ok(content::RawHtml(duplicates))
+}
+
+async fn render_user_row(
+    db: &Db,
+    admin_session: &AdminSession,
+    user_id: i64,
+    error: Option<&str>,
+) -> Result<content::RawHtml<String>, errors::AppError> {
+    let account = match queries::get_user_summary(db, user_id).await? {
+        Some(account) => account,
+        None => {
+            return Err(errors::AppError {
+                code: Status::NotFound.code,
+                message: "User not found".to_string(),
+            })
+        }
+    };
+
+    let mut context = tera::Context::new();
+    context.insert("is_self", &(account.id == admin_session.user().id));
+    context.insert("account", &account);
+    context.insert("error", &error);
+
+    let row = tera_utils::render_template_with_logging("admin_user_row.html", &context)?;
+    Ok(content::RawHtml(row))
+}
+
+#[put("/admin/users/<user_id>/role", data = "<update>")]
+pub async fn update_role(
+    update: Form<models::RoleUpdate>,
+    db: &Db,
+    admin_session: AdminSession,
+    user_id: i64,
+) -> Result<content::RawHtml<String>, errors::AppError> {
+    // Stop the last admin from locking everyone out
+    if user_id == admin_session.user().id {
+        return render_user_row(
+            db,
+            &admin_session,
+            user_id,
+            Some("You can't change your own role"),
+        )
+        .await;
+    }
+
+    info!("Setting role of user {} to {:?}", user_id, update.role);
+    queries::set_user_role(db, user_id, &update.role).await?;
+    render_user_row(db, &admin_session, user_id, None).await
+}
+
+#[post("/admin/users/<user_id>/approve")]
+pub async fn approve(
+    db: &Db,
+    admin_session: AdminSession,
+    user_id: i64,
+) -> Result<content::RawHtml<String>, errors::AppError> {
+    info!("Approving user {}", user_id);
+    let email = match queries::verify_user(db, user_id).await? {
+        Some(email) => email,
+        None => {
+            // Either there's no such user or they were approved already, only
+            // send the welcome email the first time
+            let (code, message) = match queries::get_user_summary(db, user_id).await? {
+                Some(_) => (Status::Conflict.code, "User is already verified"),
+                None => (Status::NotFound.code, "User not found"),
+            };
+            return Err(errors::AppError {
+                code,
+                message: message.to_string(),
+            });
+        }
+    };
+    signup::send_welcome_email(&email).await?;
+    render_user_row(db, &admin_session, user_id, None).await
+}
+
+#[post("/admin/users/<user_id>/disable")]
+pub async fn disable(
+    db: &Db,
+    admin_session: AdminSession,
+    user_id: i64,
+) -> Result<content::RawHtml<String>, errors::AppError> {
+    if user_id == admin_session.user().id {
+        return render_user_row(
+            db,
+            &admin_session,
+            user_id,
+            Some("You can't disable yourself"),
+        )
+        .await;
+    }
+
+    info!("Disabling user {}", user_id);
+    queries::set_user_disabled(db, user_id, true).await?;
+    render_user_row(db, &admin_session, user_id, None).await
+}
+
+#[post("/admin/users/<user_id>/enable")]
+pub async fn enable(
+    db: &Db,
+    admin_session: AdminSession,
+    user_id: i64,
+) -> Result<content::RawHtml<String>, errors::AppError> {
+    info!("Enabling user {}", user_id);
+    queries::set_user_disabled(db, user_id, false).await?;
+    render_user_row(db, &admin_session, user_id, None).await
+}
+
+#[post("/admin/users/<user_id>/logout")]
+pub async fn logout(
+    db: &Db,
+    admin_session: AdminSession,
+    user_id: i64,
+) -> Result<content::RawHtml<String>, errors::AppError> {
+    let ended = queries::delete_user_sessions(db, user_id).await?;
+    info!("Logged user {} out of {} sessions", user_id, ended);
+    render_user_row(db, &admin_session, user_id, None).await
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::db::queries::{self, Db};
+    use crate::models::models::Role;
+    use crate::tests::{log_in_writer, test_client};
+    use rocket::http::Status;
+    use rocket_db_pools::Database;
+
+    #[rocket::async_test]
+    async fn test_approve_only_unverified_users() {
+        let (client, dir) = test_client().await;
+        let (admin_id, admin) = log_in_writer(&client, "admin@example.com").await;
+        let db = Db::fetch(client.rocket()).unwrap();
+        queries::set_user_role(db, admin_id, &Role::Admin)
+            .await
+            .unwrap();
+        let verified = queries::tests::insert_test_user(db, "john@example.com").await;
+
+        let approve = |user_id: i64| {
+            client
+                .post(format!("/admin/users/{}/approve", user_id))
+                .private_cookie(admin.clone())
+                .dispatch()
+        };
+        assert_eq!(approve(verified).await.status(), Status::Conflict);
+        assert_eq!(approve(verified + 1).await.status(), Status::NotFound);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,16 +1,23 @@
+use crate::caching::{CachePolicy, Cached};
 use crate::constants;
 use crate::errors;
 use crate::tera_utils;
 use rocket::get;
 use rocket::response::content;
 
+// Stylesheets aren't versioned in their URL, so clients check them on every
+// use and usually get a 304
 #[get("/css/<style_name>")]
-pub async fn get(style_name: &str) -> Result<content::RawCss<String>, errors::AppError> {
+pub async fn get(style_name: &str) -> Result<Cached<content::RawCss<String>>, errors::AppError> {
     let mut context = tera::Context::new();
     for (name, color) in constants::COLORS.iter() {
         context.insert(*name, color);
     }
     let path = format!("css/{}", style_name);
     let style = tera_utils::render_template_with_logging(&path, &context)?;
-    Ok(content::RawCss(style))
+    Ok(Cached::content(
+        content::RawCss(style.clone()),
+        &style,
+        CachePolicy::Revalidate,
+    ))
 }
@@ -1,17 +1,23 @@
-use crate::constants;
+use crate::blobs;
+use crate::config::AppConfig;
 use crate::db::queries;
 use crate::db::queries::Db;
 use crate::errors;
+use crate::hashing;
+use crate::images;
 use crate::middleware::WriterSession;
 use crate::models::models;
+use crate::storage::{self, Scratch, SharedStorage, Storage};
 use crate::tera_utils;
-use image::ImageReader;
 
-use log::info;
+use log::{info, warn};
 use rocket::form::Form;
-use rocket::http::Status;
+use rocket::fs::TempFile;
+use rocket::http::{ContentType, Status};
 use rocket::response::content;
-use rocket::{delete, get, post, put};
+use rocket::tokio;
+use rocket::{delete, get, post, put, State};
+use std::path::Path;
 
 #[post("/galleries", data = "<create_gallery>")]
 pub async fn post(
@@ -19,22 +25,19 @@ pub async fn post(
     writer_session: WriterSession,
     db: &Db,
 ) -> Result<content::RawHtml<String>, errors::AppError> {
-    let gallery_name = create_gallery.name;
-
-    let gallery_name = match gallery_name {
-        Some(name) => name,
-        None => "Untitled",
-    };
+    let gallery_name = create_gallery.name.unwrap_or("Untitled");
 
     let gallery_id = queries::create_gallery(db, writer_session.user().id, gallery_name).await?;
 
     let gallery = models::GalleryTile::new(
         gallery_id,
         gallery_name.to_string(),
+        models::Visibility::Public,
         None,
         0,
         chrono::Utc::now().to_string(),
         writer_session.user().email.to_string(),
+        true,
     );
 
     let mut context = tera::Context::new();
@@ -45,17 +48,53 @@ pub async fn post(
     Ok(content::RawHtml(new_gallery))
 }
 
-#[post("/galleries/<gallery_id>", data = "<img_upload>")]
-pub async fn post_img(
-    mut img_upload: Form<models::ImgUpload<'_>>,
-    writer_session: WriterSession,
-    gallery_id: i64,
-    db: &Db,
-) -> Result<content::RawHtml<String>, errors::AppError> {
-    let original_path = img_upload.file.name();
+/// Where the bytes of an uploaded original are before they are stored
+pub enum UploadedFile<'a, 'f> {
+    /// Sent in one go, in a multipart form
+    Form(&'a mut TempFile<'f>),
+    /// Sent in chunks, and now complete, put together at `path`
+    Chunked {
+        upload: &'a models::Upload,
+        path: &'a str,
+    },
+}
+
+/// What came of saving an upload
+pub enum Saved {
+    Image(models::Image),
+    /// The gallery already has an image with the same bytes, so nothing was kept
+    Duplicate(errors::AppError),
+}
+
+impl Saved {
+    /// The saved image, with a duplicate as an error
+    pub fn into_image(self) -> Result<models::Image, errors::AppError> {
+        match self {
+            Saved::Image(image) => Ok(image),
+            Saved::Duplicate(e) => Err(e),
+        }
+    }
+}
 
-    let original_path = match original_path {
-        Some(path) => path,
+// Store an uploaded original and queue the jobs that make the image shown from
+// it. Without a crop the whole original is shown. Videos can't be cropped, they
+// are transcoded instead.
+#[allow(clippy::too_many_arguments)]
+pub async fn save_upload(
+    db: &Db,
+    storage: &dyn Storage,
+    user_id: i64,
+    gallery_id: i64,
+    file: UploadedFile<'_, '_>,
+    caption: &str,
+    crop: Option<models::Crop>,
+) -> Result<Saved, errors::AppError> {
+    let original_filename = match &file {
+        UploadedFile::Form(file) => file.name().map(str::to_string),
+        UploadedFile::Chunked { upload, .. } => Some(upload.file_name.clone()),
+    };
+    let original_filename = match original_filename {
+        Some(name) => name,
         None => {
             return Err(errors::AppError {
                 message: "No file uploaded".to_string(),
@@ -64,63 +103,380 @@ pub async fn post_img(
         }
     };
 
-    let image = queries::create_image(
+    // The same photo is often sent twice, only the first is kept
+    let content_hash = match &file {
+        UploadedFile::Form(file) => hashing::content_hash(file.open().await?).await?,
+        UploadedFile::Chunked { path, .. } => {
+            hashing::content_hash(tokio::fs::File::open(path).await?).await?
+        }
+    };
+
+    // Chunks come without a content type, so go by the file name
+    let media_type = match &file {
+        UploadedFile::Form(file) => models::MediaType::from_content_type(file.content_type()),
+        UploadedFile::Chunked { .. } => models::MediaType::from_content_type(
+            std::path::Path::new(&original_filename)
+                .extension()
+                .and_then(|extension| ContentType::from_extension(&extension.to_string_lossy()))
+                .as_ref(),
+        ),
+    };
+
+    // Originals are stored under their hash, one file however many galleries have it
+    let image = queries::create_media(
         db,
-        writer_session.user().id,
+        user_id,
         gallery_id,
-        original_path,
-        img_upload.caption,
+        &original_filename,
+        caption,
+        crop.as_ref(),
+        media_type,
+        &content_hash,
     )
     .await?;
+    let image = match image {
+        Some(image) => image,
+        None => {
+            info!("{} is already in gallery {}", original_filename, gallery_id);
+            return Ok(Saved::Duplicate(errors::AppError {
+                message: format!("{} is already in this gallery", original_filename),
+                code: Status::Conflict.code,
+            }));
+        }
+    };
+    let original_path = blobs::blob_path(&content_hash, None);
 
     // Save the file (persist to should be more performant... but this should be good enough)
-    // TODO: These can be done in parallel
-    match image.original_path {
-        Some(original_path) => {
-            img_upload.file.copy_to(&original_path).await?;
-        }
-        None => {
-            return Err(errors::AppError {
-                message: "No original path found".to_string(),
-                code: Status::InternalServerError.code,
-            })
+    let stored = async {
+        match file {
+            UploadedFile::Form(file) => {
+                let scratch = Scratch::new().await?;
+                let local = scratch.path(&original_path);
+                file.copy_to(&local).await?;
+                storage
+                    .put(storage::key(&original_path), Path::new(&local))
+                    .await
+            }
+            UploadedFile::Chunked { path, .. } => {
+                storage
+                    .put(storage::key(&original_path), Path::new(path))
+                    .await
+            }
         }
+    };
+    if let Err(e) = stored.await {
+        // Nothing could make the image without its original, so don't leave it
+        // processing forever
+        warn!("Couldn't store {}, forgetting it: {}", original_filename, e);
+        queries::forget_image(db, image.id).await?;
+        return Err(e);
     }
-    img_upload.modified_file.copy_to(&image.path).await?;
 
-    let mut thumbnail = ImageReader::open(&image.path)?
-        .with_guessed_format()?
-        .decode()?;
+    if media_type == models::MediaType::Video {
+        queries::enqueue_job(
+            db,
+            &models::Job::ProcessVideo {
+                image_id: image.id,
+                original_path,
+                path: image.path.clone(),
+            },
+        )
+        .await?;
+        return Ok(Saved::Image(image));
+    }
 
-    info!("Creating thumbnail for: {}", &image.path);
+    // The served image is cut from the original by a job worker, as decoding big
+    // photos is slow. Saving only its pixels leaves the original's EXIF behind.
+    queries::enqueue_job(
+        db,
+        &models::Job::CropImage {
+            image_id: image.id,
+            original_path: original_path.clone(),
+            path: image.path.clone(),
+            crop: crop.unwrap_or_else(models::Crop::whole),
+        },
+    )
+    .await?;
+    queries::enqueue_job(
+        db,
+        &models::Job::HashImage {
+            image_id: image.id,
+            original_path: original_path.clone(),
+        },
+    )
+    .await?;
+    queries::enqueue_job(
+        db,
+        &models::Job::ReadMetadata {
+            image_id: image.id,
+            original_path,
+        },
+    )
+    .await?;
 
-    thumbnail = thumbnail.thumbnail(constants::THUMBNAIL_SIZE, constants::THUMBNAIL_SIZE);
+    Ok(Saved::Image(image))
+}
 
-    let thumbnail_path = format!("{}.{}", &image.path, constants::THUMBNAIL_EXT);
+#[post("/galleries/<gallery_id>", data = "<img_upload>")]
+pub async fn post_img(
+    mut img_upload: Form<models::ImgUpload<'_>>,
+    writer_session: WriterSession,
+    gallery_id: i64,
+    db: &Db,
+    config: &State<AppConfig>,
+    storage: &State<SharedStorage>,
+) -> Result<content::RawHtml<String>, errors::AppError> {
+    writer_session
+        .ensure_can_edit_gallery(db, gallery_id)
+        .await?;
 
-    info!("Saving thumbnail to: {}", &thumbnail_path);
+    let img_upload = &mut *img_upload;
+    let image = save_upload(
+        db,
+        storage.as_ref(),
+        writer_session.user().id,
+        gallery_id,
+        UploadedFile::Form(&mut img_upload.file),
+        img_upload.caption,
+        img_upload.crop,
+    )
+    .await?
+    .into_image()?;
 
-    thumbnail.save(&thumbnail_path)?;
+    render_image_item(&image, gallery_id, true, config)
+}
 
+// Many files in one request, each saved on its own so one bad file doesn't
+// lose the rest. Responds with tiles for the gallery and a report of each file.
+#[post("/galleries/<gallery_id>/batch", data = "<batch_upload>")]
+pub async fn post_batch(
+    mut batch_upload: Form<models::BatchUpload<'_>>,
+    writer_session: WriterSession,
+    gallery_id: i64,
+    db: &Db,
+    config: &State<AppConfig>,
+    storage: &State<SharedStorage>,
+) -> Result<content::RawHtml<String>, errors::AppError> {
+    writer_session
+        .ensure_can_edit_gallery(db, gallery_id)
+        .await?;
+
+    let mut results = vec![];
+
+    for file in batch_upload.files.iter_mut() {
+        let name = file.raw_name().map_or_else(
+            || "Unnamed file".to_string(),
+            |name| name.dangerous_unsafe_unsanitized_raw().to_string(),
+        );
+
+        let is_media = file.content_type().is_some_and(|content_type| {
+            content_type.top() == "image" || content_type.top() == "video"
+        });
+
+        let result = if !is_media {
+            Err("Not an image or video".to_string())
+        } else {
+            let file = UploadedFile::Form(file);
+            let user_id = writer_session.user().id;
+            save_upload(db, storage.as_ref(), user_id, gallery_id, file, "", None)
+                .await
+                .and_then(Saved::into_image)
+                .map_err(|e| e.message)
+        };
+
+        match result {
+            Ok(image) => results.push(models::BatchUploadResult {
+                name,
+                image: Some(image),
+                error: None,
+            }),
+            Err(error) => {
+                warn!("Batch upload of {} failed: {}", name, error);
+                results.push(models::BatchUploadResult {
+                    name,
+                    image: None,
+                    error: Some(error),
+                })
+            }
+        }
+    }
+
+    let saved = results.iter().filter(|result| result.error.is_none());
+    info!(
+        "Batch upload to gallery {}: {} of {} files saved",
+        gallery_id,
+        saved.count(),
+        results.len()
+    );
+
+    let mut context = tera::Context::new();
+    context.insert("results", &results);
+    context.insert("gallery_id", &gallery_id);
+    context.insert("rendition_sizes", &config.rendition_sizes);
+
+    let html = tera_utils::render_template_with_logging("batch_upload_results.html", &context)?;
+    Ok(content::RawHtml(html))
+}
+
+pub fn render_image_item(
+    image: &models::Image,
+    gallery_id: i64,
+    can_edit: bool,
+    config: &AppConfig,
+) -> Result<content::RawHtml<String>, errors::AppError> {
     let mut context = tera::Context::new();
 
     context.insert("path", &image.path);
-    context.insert("caption", &img_upload.caption);
+    context.insert("caption", &image.caption);
     context.insert("gallery_id", &gallery_id);
     context.insert("image_id", &image.id);
-    context.insert("user", writer_session.user());
+    context.insert("status", &image.status);
+    context.insert("media_type", &image.media_type);
+    context.insert("near_duplicate", &image.near_duplicate);
+    context.insert("can_edit", &can_edit);
+    context.insert("rendition_sizes", &config.rendition_sizes);
 
     let image_item = tera_utils::render_template_with_logging("image_item.html", &context)?;
 
     Ok(content::RawHtml(image_item))
 }
 
+// Polled by tiles of images that are still processing
+#[get("/galleries/<gallery_id>/images/<image_id>")]
+pub async fn get_image_item(
+    db: &Db,
+    session: models::Session,
+    gallery_id: i64,
+    image_id: i64,
+    config: &State<AppConfig>,
+) -> Result<content::RawHtml<String>, errors::AppError> {
+    let image = queries::get_gallery_image(db, gallery_id, image_id, &session.user)
+        .await?
+        .ok_or_else(|| errors::AppError {
+            message: "Image not found".to_string(),
+            code: Status::NotFound.code,
+        })?;
+    let can_edit = queries::can_edit_gallery(db, &session.user, gallery_id).await?;
+
+    render_image_item(&image, gallery_id, can_edit, config)
+}
+
+// Images whose original hasn't been found yet can't be cropped again, their
+// stored original is only the cropped image
+async fn get_image_source(
+    db: &Db,
+    gallery_id: i64,
+    image_id: i64,
+) -> Result<models::ImageSource, errors::AppError> {
+    let source = queries::get_image_source(db, gallery_id, image_id)
+        .await?
+        .ok_or_else(|| errors::AppError {
+            message: "Image not found".to_string(),
+            code: Status::NotFound.code,
+        })?;
+    if source.is_legacy {
+        return Err(errors::AppError {
+            message: "The original of this image wasn't kept, so it can't be edited".to_string(),
+            code: Status::Conflict.code,
+        });
+    }
+    Ok(source)
+}
+
+#[get("/galleries/<gallery_id>/images/<image_id>/edit")]
+pub async fn get_image_editor(
+    db: &Db,
+    writer_session: WriterSession,
+    gallery_id: i64,
+    image_id: i64,
+) -> Result<content::RawHtml<String>, errors::AppError> {
+    writer_session
+        .ensure_can_edit_gallery(db, gallery_id)
+        .await?;
+    let source = get_image_source(db, gallery_id, image_id).await?;
+    let versions = queries::get_image_versions(db, image_id).await?;
+
+    let mut context = tera::Context::new();
+    context.insert("gallery_id", &gallery_id);
+    context.insert("image_id", &image_id);
+    context.insert("crop", &source.crop);
+    context.insert("rotation", &source.crop.map(|crop| crop.rotation.degrees()));
+    context.insert("versions", &versions);
+
+    let editor = tera_utils::render_template_with_logging("image_editor.html", &context)?;
+    Ok(content::RawHtml(editor))
+}
+
+// The original, for the editor to crop again. Only editors get it, and only
+// without its metadata.
+#[get("/galleries/<gallery_id>/images/<image_id>/original")]
+pub async fn get_original(
+    db: &Db,
+    writer_session: WriterSession,
+    gallery_id: i64,
+    image_id: i64,
+    storage: &State<SharedStorage>,
+) -> Result<(ContentType, Vec<u8>), errors::AppError> {
+    writer_session
+        .ensure_can_edit_gallery(db, gallery_id)
+        .await?;
+    let original_path = get_image_source(db, gallery_id, image_id)
+        .await?
+        .original_path;
+
+    let mut scratch = Scratch::new().await?;
+    let original_path = scratch.fetch(storage.as_ref(), &original_path).await?;
+    let jpeg = tokio::task::spawn_blocking(move || images::editable_original(&original_path))
+        .await
+        .map_err(|e| e.to_string())??;
+    Ok((ContentType::JPEG, jpeg))
+}
+
+// Replace an image with a new crop of its original, keeping the old version
+#[post("/galleries/<gallery_id>/images/<image_id>/edit", data = "<edit>")]
+pub async fn edit_image(
+    edit: Form<models::ImageEdit>,
+    db: &Db,
+    writer_session: WriterSession,
+    gallery_id: i64,
+    image_id: i64,
+    config: &State<AppConfig>,
+) -> Result<content::RawHtml<String>, errors::AppError> {
+    writer_session
+        .ensure_can_edit_gallery(db, gallery_id)
+        .await?;
+    let original_path = get_image_source(db, gallery_id, image_id)
+        .await?
+        .original_path;
+
+    info!("Editing image {} with {:?}", image_id, edit.crop);
+    let image = queries::edit_image(db, writer_session.user().id, image_id, &edit.crop)
+        .await?
+        .ok_or_else(|| errors::AppError {
+            message: "Image not found".to_string(),
+            code: Status::NotFound.code,
+        })?;
+
+    queries::enqueue_job(
+        db,
+        &models::Job::CropImage {
+            image_id: image.id,
+            original_path,
+            path: image.path.clone(),
+            crop: edit.crop,
+        },
+    )
+    .await?;
+
+    render_image_item(&image, gallery_id, true, config)
+}
+
 #[get("/galleries")]
 pub async fn get(
     db: &Db,
     session: models::Session,
 ) -> Result<content::RawHtml<String>, errors::AppError> {
-    let galleries = queries::get_galleries(db).await?;
+    let galleries = queries::get_galleries(db, &session.user).await?;
 
     let mut context = tera::Context::new();
     context.insert("galleries", &galleries);
@@ -135,12 +491,14 @@ pub async fn get_gallery(
     db: &Db,
     session: models::Session,
     gallery_id: i64,
+    config: &State<AppConfig>,
 ) -> Result<content::RawHtml<String>, errors::AppError> {
-    let gallery = queries::get_gallery(db, gallery_id).await?;
+    let gallery = queries::get_gallery(db, gallery_id, &session.user).await?;
 
     let mut context = tera::Context::new();
     context.insert("gallery", &gallery);
     context.insert("user", &session.user);
+    context.insert("rendition_sizes", &config.rendition_sizes);
 
     let gallery_html = tera_utils::render_template_with_logging("gallery.html", &context)?;
     Ok(content::RawHtml(gallery_html))
@@ -152,8 +510,9 @@ pub async fn get_gallery_item(
     session: models::Session,
     gallery_id: i64,
     image_id: i64,
+    config: &State<AppConfig>,
 ) -> Result<content::RawHtml<String>, errors::AppError> {
-    let gallery = queries::get_gallery(db, gallery_id).await?;
+    let gallery = queries::get_gallery(db, gallery_id, &session.user).await?;
 
     let mut context = tera::Context::new();
 
@@ -186,8 +545,20 @@ pub async fn get_gallery_item(
         &gallery.images.get(next_index as usize).map(|i| i.id),
     );
     context.insert("gallery_id", &gallery.id);
+    context.insert("can_edit", &gallery.can_edit);
 
     context.insert("user", &session.user);
+    context.insert("rendition_sizes", &config.rendition_sizes);
+
+    let metadata = queries::get_image_metadata(db, image_id).await?;
+    context.insert(
+        "captured_on",
+        &metadata.as_ref().and_then(|metadata| metadata.captured_on()),
+    );
+    context.insert(
+        "camera",
+        &metadata.as_ref().and_then(|metadata| metadata.camera()),
+    );
 
     let lightbox_html = tera_utils::render_template_with_logging("lightbox.html", &context)?;
     Ok(content::RawHtml(lightbox_html))
@@ -196,10 +567,14 @@ pub async fn get_gallery_item(
 #[delete("/galleries/<gallery_id>")]
 pub async fn delete_gallery(
     db: &Db,
-    _writer_session: WriterSession,
+    writer_session: WriterSession,
     gallery_id: i64,
 ) -> Result<content::RawHtml<String>, errors::AppError> {
-    let gallery = queries::delete_gallery(db, gallery_id).await?;
+    writer_session
+        .ensure_can_edit_gallery(db, gallery_id)
+        .await?;
+    #[allow(clippy::let_unit_value)]
+    let gallery = queries::delete_gallery(db, gallery_id, writer_session.user().id).await?;
     Ok(content::RawHtml(format!("Gallery deleted: {:?}", gallery)))
 }
 
@@ -207,9 +582,13 @@ pub async fn delete_gallery(
 pub async fn update_gallery(
     update: Form<models::GalleryUpdate<'_>>,
     db: &Db,
-    _writer_session: WriterSession,
+    writer_session: WriterSession,
     gallery_id: i64,
 ) -> Result<content::RawHtml<String>, errors::AppError> {
+    writer_session
+        .ensure_can_edit_gallery(db, gallery_id)
+        .await?;
+    #[allow(clippy::let_unit_value)]
     let gallery = queries::update_gallery(db, gallery_id, update.into_inner()).await?;
     Ok(content::RawHtml(format!(
         "Gallery title updated: {:?}",
@@ -219,12 +598,433 @@ pub async fn update_gallery(
 
 #[get("/galleries/<gallery_id>/upload_form")]
 pub async fn get_upload_form(
-    _session: models::Session,
+    db: &Db,
+    writer_session: WriterSession,
     gallery_id: i64,
 ) -> Result<content::RawHtml<String>, errors::AppError> {
+    writer_session
+        .ensure_can_edit_gallery(db, gallery_id)
+        .await?;
+
     let mut context = tera::Context::new();
     context.insert("gallery_id", &gallery_id);
 
     let upload_form = tera_utils::render_template_with_logging("upload_form.html", &context)?;
     Ok(content::RawHtml(upload_form))
 }
+
+async fn render_gallery_settings(
+    db: &Db,
+    writer_session: &WriterSession,
+    gallery_id: i64,
+    error: Option<&str>,
+) -> Result<content::RawHtml<String>, errors::AppError> {
+    let gallery = queries::get_gallery(db, gallery_id, writer_session.user()).await?;
+    let members = queries::get_gallery_members(db, gallery_id).await?;
+    let editors = queries::get_gallery_editors(db, gallery_id).await?;
+    let can_manage = queries::can_manage_gallery(db, writer_session.user(), gallery_id).await?;
+
+    let mut context = tera::Context::new();
+    context.insert("gallery", &gallery);
+    context.insert("members", &members);
+    context.insert("editors", &editors);
+    context.insert("can_manage", &can_manage);
+    context.insert("error", &error);
+
+    let settings = tera_utils::render_template_with_logging("gallery_settings.html", &context)?;
+    Ok(content::RawHtml(settings))
+}
+
+#[get("/galleries/<gallery_id>/settings")]
+pub async fn get_settings(
+    db: &Db,
+    writer_session: WriterSession,
+    gallery_id: i64,
+) -> Result<content::RawHtml<String>, errors::AppError> {
+    writer_session
+        .ensure_can_edit_gallery(db, gallery_id)
+        .await?;
+    render_gallery_settings(db, &writer_session, gallery_id, None).await
+}
+
+#[put("/galleries/<gallery_id>/visibility", data = "<update>")]
+pub async fn update_visibility(
+    update: Form<models::VisibilityUpdate>,
+    db: &Db,
+    writer_session: WriterSession,
+    gallery_id: i64,
+) -> Result<content::RawHtml<String>, errors::AppError> {
+    writer_session
+        .ensure_can_manage_gallery(db, gallery_id)
+        .await?;
+    queries::update_gallery_visibility(db, gallery_id, update.visibility).await?;
+    info!(
+        "Gallery {} visibility set to {}",
+        gallery_id,
+        update.visibility.as_str()
+    );
+    render_gallery_settings(db, &writer_session, gallery_id, None).await
+}
+
+#[post("/galleries/<gallery_id>/members", data = "<member>")]
+pub async fn add_member(
+    member: Form<models::GalleryUserAdd<'_>>,
+    db: &Db,
+    writer_session: WriterSession,
+    gallery_id: i64,
+) -> Result<content::RawHtml<String>, errors::AppError> {
+    writer_session
+        .ensure_can_manage_gallery(db, gallery_id)
+        .await?;
+    // Unknown emails get the same page, so this can't be used to find out who has an account
+    queries::add_gallery_member(db, gallery_id, member.email).await?;
+    render_gallery_settings(db, &writer_session, gallery_id, None).await
+}
+
+#[delete("/galleries/<gallery_id>/members/<user_id>")]
+pub async fn remove_member(
+    db: &Db,
+    writer_session: WriterSession,
+    gallery_id: i64,
+    user_id: i64,
+) -> Result<content::RawHtml<String>, errors::AppError> {
+    writer_session
+        .ensure_can_manage_gallery(db, gallery_id)
+        .await?;
+    queries::remove_gallery_member(db, gallery_id, user_id).await?;
+    render_gallery_settings(db, &writer_session, gallery_id, None).await
+}
+
+#[post("/galleries/<gallery_id>/editors", data = "<editor>")]
+pub async fn add_editor(
+    editor: Form<models::GalleryUserAdd<'_>>,
+    db: &Db,
+    writer_session: WriterSession,
+    gallery_id: i64,
+) -> Result<content::RawHtml<String>, errors::AppError> {
+    writer_session
+        .ensure_can_manage_gallery(db, gallery_id)
+        .await?;
+    let added = queries::add_gallery_editor(db, gallery_id, editor.email).await?;
+    let error = if added {
+        None
+    } else {
+        Some("No writer with that email")
+    };
+    render_gallery_settings(db, &writer_session, gallery_id, error).await
+}
+
+#[delete("/galleries/<gallery_id>/editors/<user_id>")]
+pub async fn remove_editor(
+    db: &Db,
+    writer_session: WriterSession,
+    gallery_id: i64,
+    user_id: i64,
+) -> Result<content::RawHtml<String>, errors::AppError> {
+    writer_session
+        .ensure_can_manage_gallery(db, gallery_id)
+        .await?;
+    queries::remove_gallery_editor(db, gallery_id, user_id).await?;
+    render_gallery_settings(db, &writer_session, gallery_id, None).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{save_upload, UploadedFile};
+    use crate::db::queries::{self, Db};
+    use crate::errors;
+    use crate::models::models;
+    use crate::storage::{self, Storage};
+    use crate::tests::{log_in_writer, test_client};
+    use image::{ImageFormat, RgbImage};
+    use rocket::http::{ContentType, Cookie, Status};
+    use rocket::local::asynchronous::Client;
+    use rocket_db_pools::{sqlx, Database};
+    use std::io::Cursor;
+    use std::path::Path;
+
+    static BOUNDARY: &str = "jv-test-boundary";
+
+    // A multipart form sending each (name, content type, bytes) as `files`
+    fn batch_form(files: &[(&str, &str, &[u8])]) -> Vec<u8> {
+        let mut body = vec![];
+        for (name, content_type, bytes) in files {
+            let disposition = format!("form-data; name=\"files\"; filename=\"{}\"", name);
+            let headers = format!(
+                "--{}\r\nContent-Disposition: {}\r\nContent-Type: {}\r\n\r\n",
+                BOUNDARY, disposition, content_type
+            );
+            body.extend(headers.bytes());
+            body.extend(*bytes);
+            body.extend(b"\r\n");
+        }
+        body.extend(format!("--{}--\r\n", BOUNDARY).bytes());
+        body
+    }
+
+    fn png() -> Vec<u8> {
+        let mut png = Cursor::new(vec![]);
+        RgbImage::new(40, 30)
+            .write_to(&mut png, ImageFormat::Png)
+            .unwrap();
+        png.into_inner()
+    }
+
+    #[rocket::async_test]
+    async fn test_batch_upload_reports_each_file() {
+        let (client, dir) = test_client().await;
+        let (user_id, cookie) = log_in_writer(&client, "writer@example.com").await;
+        let db = Db::fetch(client.rocket()).unwrap();
+        let gallery_id = queries::create_gallery(db, user_id, "batch").await.unwrap();
+        let photo = png();
+
+        let response = client
+            .post(format!("/galleries/{}/batch", gallery_id))
+            .header(ContentType::new("multipart", "form-data").with_params(("boundary", BOUNDARY)))
+            .private_cookie(cookie)
+            .body(batch_form(&[
+                ("photo.png", "image/png", &photo),
+                ("notes.txt", "text/plain", b"notes"),
+                ("again.png", "image/png", &photo),
+            ]))
+            .dispatch()
+            .await;
+
+        assert_eq!(response.status(), Status::Ok);
+        let html = response.into_string().await.unwrap();
+        assert!(html.contains("photo.png: uploaded"));
+        assert!(html.contains("notes.txt: Not an image or video"));
+        assert!(html.contains("again.png: again is already in this gallery"));
+
+        let (saved,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM original_images")
+            .fetch_one(&db.0)
+            .await
+            .unwrap();
+        assert_eq!(saved, 1);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[rocket::async_test]
+    async fn test_same_file_sent_twice_at_once_is_kept_once() {
+        let (client, dir) = test_client().await;
+        let (user_id, cookie) = log_in_writer(&client, "writer@example.com").await;
+        let db = Db::fetch(client.rocket()).unwrap();
+        let gallery_id = queries::create_gallery(db, user_id, "twice").await.unwrap();
+        let body = batch_form(&[("photo.png", "image/png", &png())]);
+
+        let send = || {
+            client
+                .post(format!("/galleries/{}/batch", gallery_id))
+                .header(
+                    ContentType::new("multipart", "form-data").with_params(("boundary", BOUNDARY)),
+                )
+                .private_cookie(cookie.clone())
+                .body(&body)
+                .dispatch()
+        };
+        let (first, second) = rocket::tokio::join!(send(), send());
+        assert_eq!(first.status(), Status::Ok);
+        assert_eq!(second.status(), Status::Ok);
+
+        let (saved,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM original_images")
+            .fetch_one(&db.0)
+            .await
+            .unwrap();
+        assert_eq!(saved, 1);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[rocket::async_test]
+    async fn test_legacy_images_cant_be_edited() {
+        let (client, dir) = test_client().await;
+        let (user_id, cookie) = log_in_writer(&client, "writer@example.com").await;
+        let db = Db::fetch(client.rocket()).unwrap();
+        let gallery_id = queries::create_gallery(db, user_id, "old").await.unwrap();
+        let (image_id, _) = queries::tests::create_legacy_image(db, user_id, gallery_id).await;
+
+        for path in ["edit", "original"] {
+            let response = client
+                .get(format!(
+                    "/galleries/{}/images/{}/{}",
+                    gallery_id, image_id, path
+                ))
+                .private_cookie(cookie.clone())
+                .dispatch()
+                .await;
+            assert_eq!(response.status(), Status::Conflict);
+        }
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    // The statuses of making a gallery public, sharing it and unsharing it
+    async fn change_who_sees(
+        client: &Client,
+        cookie: &Cookie<'static>,
+        gallery_id: i64,
+        member_id: i64,
+    ) -> Vec<Status> {
+        let visibility = client
+            .put(format!("/galleries/{}/visibility", gallery_id))
+            .header(ContentType::Form)
+            .private_cookie(cookie.clone())
+            .body("visibility=public")
+            .dispatch()
+            .await;
+        let add = client
+            .post(format!("/galleries/{}/members", gallery_id))
+            .header(ContentType::Form)
+            .private_cookie(cookie.clone())
+            .body("email=member@example.com")
+            .dispatch()
+            .await;
+        let remove = client
+            .delete(format!("/galleries/{}/members/{}", gallery_id, member_id))
+            .private_cookie(cookie.clone())
+            .dispatch()
+            .await;
+        vec![visibility.status(), add.status(), remove.status()]
+    }
+
+    #[rocket::async_test]
+    async fn test_only_the_owner_decides_who_sees_a_gallery() {
+        let (client, dir) = test_client().await;
+        let (owner_id, owner) = log_in_writer(&client, "owner@example.com").await;
+        let (_, editor) = log_in_writer(&client, "editor@example.com").await;
+        let db = Db::fetch(client.rocket()).unwrap();
+        let member_id = queries::tests::insert_test_user(db, "member@example.com").await;
+        let gallery_id = queries::create_gallery(db, owner_id, "family")
+            .await
+            .unwrap();
+        assert!(
+            queries::add_gallery_editor(db, gallery_id, "editor@example.com")
+                .await
+                .unwrap()
+        );
+
+        assert_eq!(
+            change_who_sees(&client, &editor, gallery_id, member_id).await,
+            vec![Status::Forbidden; 3]
+        );
+        assert_eq!(
+            change_who_sees(&client, &owner, gallery_id, member_id).await,
+            vec![Status::Ok; 3]
+        );
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[rocket::async_test]
+    async fn test_adding_a_member_doesnt_reveal_accounts() {
+        let (client, dir) = test_client().await;
+        let (owner_id, owner) = log_in_writer(&client, "owner@example.com").await;
+        let db = Db::fetch(client.rocket()).unwrap();
+        let gallery_id = queries::create_gallery(db, owner_id, "shared")
+            .await
+            .unwrap();
+        let unverified = queries::tests::insert_test_user(db, "unverified@example.com").await;
+        sqlx::query("UPDATE users SET is_verified = FALSE WHERE id = ?1")
+            .bind(unverified)
+            .execute(&db.0)
+            .await
+            .unwrap();
+
+        let mut bodies = vec![];
+        for email in ["nobody@example.com", "unverified@example.com"] {
+            let response = client
+                .post(format!("/galleries/{}/members", gallery_id))
+                .header(ContentType::Form)
+                .private_cookie(owner.clone())
+                .body(format!("email={}", email))
+                .dispatch()
+                .await;
+            assert_eq!(response.status(), Status::Ok);
+            bodies.push(response.into_string().await.unwrap());
+        }
+        assert_eq!(bodies[0], bodies[1]);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    // Storage that can't keep anything, like a bucket that is down
+    struct BrokenStorage;
+
+    #[rocket::async_trait]
+    impl Storage for BrokenStorage {
+        async fn put(&self, _: &str, _: &Path) -> Result<(), errors::AppError> {
+            Err(errors::AppError {
+                code: 503,
+                message: "storage is down".to_string(),
+            })
+        }
+
+        async fn get(&self, _: &str, _: &Path) -> Result<bool, errors::AppError> {
+            Ok(false)
+        }
+
+        async fn stream(
+            &self,
+            _: &str,
+            _: Option<(u64, u64)>,
+        ) -> Result<Option<storage::Reader>, errors::AppError> {
+            Ok(None)
+        }
+
+        async fn head(&self, _: &str) -> Result<Option<storage::StoredInfo>, errors::AppError> {
+            Ok(None)
+        }
+
+        async fn delete(&self, _: &str) -> Result<(), errors::AppError> {
+            Ok(())
+        }
+
+        async fn list(&self) -> Result<Vec<String>, errors::AppError> {
+            Ok(vec![])
+        }
+    }
+
+    #[rocket::async_test]
+    async fn test_image_is_forgotten_when_its_original_cant_be_stored() {
+        let db = queries::tests::test_db().await;
+        let user_id = queries::tests::insert_test_user(&db, "writer@example.com").await;
+        let gallery_id = queries::create_gallery(&db, user_id, "broken")
+            .await
+            .unwrap();
+        let path = std::env::temp_dir().join(format!("jv-upload-{}", uuid::Uuid::new_v4()));
+        std::fs::write(&path, png()).unwrap();
+        let upload = models::Upload {
+            id: "upload".to_string(),
+            gallery_id,
+            file_name: "photo.png".to_string(),
+            length: 0,
+            received: 0,
+        };
+
+        let saved = save_upload(
+            &db,
+            &BrokenStorage,
+            user_id,
+            gallery_id,
+            UploadedFile::Chunked {
+                upload: &upload,
+                path: path.to_str().unwrap(),
+            },
+            "",
+            None,
+        )
+        .await;
+
+        assert_eq!(saved.err().map(|e| e.code), Some(503));
+        for table in ["original_images", "modified_images", "jobs"] {
+            let (count,): (i64,) = sqlx::query_as(&format!("SELECT COUNT(*) FROM {}", table))
+                .fetch_one(&db.0)
+                .await
+                .unwrap();
+            assert_eq!(count, 0, "{} should be empty", table);
+        }
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
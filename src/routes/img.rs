@@ -1,27 +1,171 @@
+use crate::blobs;
+use crate::caching::{CachePolicy, Cached};
+use crate::config::AppConfig;
+use crate::constants;
 use crate::db::queries;
 use crate::db::queries::Db;
 use crate::errors;
+use crate::images;
 use crate::middleware::WriterSession;
 use crate::models::models;
+use crate::ranges::{PartialFile, RangeRequest};
+use crate::storage::{SharedStorage, Storage, StoredFile};
+use log::debug;
 use rocket::form::Form;
-use rocket::fs::{relative, NamedFile};
+use rocket::http::{Accept, Header};
 use rocket::response::content;
-use rocket::{delete, get, put};
-use std::path::{Path, PathBuf};
+use rocket::{delete, get, put, Responder, State};
+use std::path::PathBuf;
 
+/// A whole file, saying that parts of it can be asked for
+#[derive(Responder)]
+pub struct RangedFile {
+    file: StoredFile,
+    accept_ranges: Header<'static>,
+}
+
+#[derive(Responder)]
+pub enum ServedFile {
+    Whole(RangedFile),
+    Partial(PartialFile),
+}
+
+// Videos are played from Range requests, so the player can seek without
+// downloading the whole file first
 #[get("/img/<path>")]
-pub async fn get(path: PathBuf, _session: models::Session) -> Option<NamedFile> {
-    let path = Path::new(relative!("img")).join(path);
-    NamedFile::open(path).await.ok()
+pub async fn get(
+    path: PathBuf,
+    range: RangeRequest<'_>,
+    db: &Db,
+    session: models::Session,
+    storage: &State<SharedStorage>,
+) -> Result<Option<Cached<ServedFile>>, errors::AppError> {
+    let file_name = match path.to_str() {
+        Some(file_name) => file_name,
+        None => return Ok(None),
+    };
+
+    // Only files we can trace back to an image row are served, thumbnails are
+    // authorized through the image they were made from
+    let thumbnail_suffix = format!(".{}", constants::THUMBNAIL_EXT);
+    let image_name = file_name
+        .strip_suffix(&thumbnail_suffix)
+        .unwrap_or(file_name);
+    let image_path = format!("{}/{}", constants::IMG_PATH, image_name);
+
+    if !queries::can_view_image_file(db, &session.user, &image_path).await? {
+        debug!("Refusing to serve {} to {}", file_name, session.user.email);
+        return Ok(None);
+    }
+
+    let info = match storage.head(file_name).await? {
+        Some(info) => info,
+        None => return Ok(None),
+    };
+
+    // Files are never changed once written under their hash or UUID. Only whole
+    // files can be checked against their hash.
+    let served = match range {
+        RangeRequest(Some(range)) => PartialFile::open(storage.as_ref(), file_name, &info, range)
+            .await?
+            .map(ServedFile::Partial),
+        RangeRequest(None) => storage.stream(file_name, None).await?.map(|body| {
+            ServedFile::Whole(RangedFile {
+                file: StoredFile::new(file_name, blobs::verified(file_name, body), info.length),
+                accept_ranges: Header::new("Accept-Ranges", "bytes"),
+            })
+        }),
+    };
+    Ok(served.map(|served| Cached::file(served, &info, CachePolicy::Immutable)))
+}
+
+/// A rendition in whichever format the client prefers, so caches must key on Accept
+#[derive(Responder)]
+pub struct Rendition {
+    file: StoredFile,
+    vary: Header<'static>,
+}
+
+impl Rendition {
+    // The rendition stored as `key`, if there is one
+    async fn cached(
+        storage: &dyn Storage,
+        key: &str,
+        policy: CachePolicy,
+    ) -> Result<Option<Cached<Rendition>>, errors::AppError> {
+        let info = match storage.head(key).await? {
+            Some(info) => info,
+            None => return Ok(None),
+        };
+        Ok(storage.stream(key, None).await?.map(|body| {
+            let rendition = Rendition {
+                file: StoredFile::new(key, body, info.length),
+                vary: Header::new("Vary", "Accept"),
+            };
+            Cached::file(rendition, &info, policy)
+        }))
+    }
+}
+
+// Serve the rendition that best fits `width`, for `srcset`s, in the best format
+// the client accepts
+#[get("/img/<name>/<width>")]
+pub async fn get_rendition(
+    name: &str,
+    width: u32,
+    accept: Option<&Accept>,
+    db: &Db,
+    session: models::Session,
+    config: &State<AppConfig>,
+    storage: &State<SharedStorage>,
+) -> Result<Option<Cached<Rendition>>, errors::AppError> {
+    let image_path = format!("{}/{}", constants::IMG_PATH, name);
+
+    if !queries::can_view_image_file(db, &session.user, &image_path).await? {
+        debug!(
+            "Refusing to serve renditions of {} to {}",
+            name, session.user.email
+        );
+        return Ok(None);
+    }
+
+    let storage = storage.as_ref();
+
+    if let Some(size) = images::pick_rendition(&config.rendition_sizes, width) {
+        // Fall back through the formats, renditions made before a format was
+        // added won't have it
+        for format in images::negotiate_formats(accept) {
+            let key = images::rendition_path(name, size, format);
+            let rendition = Rendition::cached(storage, &key, CachePolicy::Immutable).await?;
+            if rendition.is_some() {
+                return Ok(rendition);
+            }
+        }
+    }
+
+    // Images uploaded before renditions only have a thumbnail, and images still
+    // processing have nothing yet. A rendition may turn up at this URL later, so
+    // fallbacks are checked again on every use.
+    if width <= constants::THUMBNAIL_SIZE {
+        let key = images::thumbnail_path(name);
+        let thumbnail = Rendition::cached(storage, &key, CachePolicy::Revalidate).await?;
+        if thumbnail.is_some() {
+            return Ok(thumbnail);
+        }
+    }
+
+    Rendition::cached(storage, name, CachePolicy::Revalidate).await
 }
 
 #[delete("/img/<image_id>")]
 pub async fn delete(
     db: &Db,
-    _writer_session: WriterSession,
+    writer_session: WriterSession,
     image_id: i64,
 ) -> Result<content::RawHtml<String>, errors::AppError> {
-    let img_path = queries::delete_image(db, image_id).await?;
+    writer_session.ensure_can_edit_image(db, image_id).await?;
+    #[allow(clippy::let_unit_value)]
+    let img_path = queries::delete_image(db, image_id, writer_session.user().id).await?;
     Ok(content::RawHtml(format!("Image deleted: {:?}", img_path)))
 }
 
@@ -29,9 +173,10 @@ pub async fn delete(
 pub async fn update_caption(
     caption_update: Form<models::CaptionUpdate<'_>>,
     db: &Db,
-    _writer_session: WriterSession,
+    writer_session: WriterSession,
     image_id: i64,
 ) -> Result<content::RawHtml<String>, errors::AppError> {
+    writer_session.ensure_can_edit_image(db, image_id).await?;
     queries::update_image_caption(db, image_id, caption_update.caption).await?;
     Ok(content::RawHtml(caption_update.caption.to_string()))
 }
@@ -1,4 +1,4 @@
-use crate::constants;
+use crate::config::AppConfig;
 use crate::db::queries;
 use crate::db::queries::Db;
 use crate::errors;
@@ -11,6 +11,14 @@ use rocket::http::{Cookie, CookieJar};
 use rocket::post;
 use rocket::response::content;
 use rocket::response::Redirect;
+use rocket::{Responder, State};
+
+#[derive(Responder)]
+#[allow(clippy::large_enum_variant)]
+pub enum LoginResponse {
+    Redirect(Redirect),
+    Page(content::RawHtml<String>),
+}
 
 #[get("/login")]
 pub async fn get() -> Result<content::RawHtml<String>, errors::AppError> {
@@ -23,11 +31,27 @@ pub async fn post(
     user_login: Form<UserLogin<'_>>,
     cookies: &CookieJar<'_>,
     jv_db: &Db,
-) -> Result<Redirect, errors::AppError> {
+    config: &State<AppConfig>,
+) -> Result<LoginResponse, errors::AppError> {
     let is_valid = queries::verify_password(jv_db, user_login.email, user_login.password).await?;
 
+    if is_valid && queries::is_user_disabled(jv_db, user_login.email).await? {
+        return Err(errors::AppError {
+            code: 403,
+            message: "This account has been disabled".to_string(),
+        });
+    }
+
+    if is_valid && !queries::is_user_verified(jv_db, user_login.email).await? {
+        let mut context = tera::Context::new();
+        context.insert("email", user_login.email);
+        let unverified = tera_utils::render_template_with_logging("unverified.html", &context)?;
+        return Ok(LoginResponse::Page(content::RawHtml(unverified)));
+    }
+
     if is_valid {
-        let session_token = queries::create_user_session(jv_db, user_login.email).await?;
+        let session_token =
+            queries::create_user_session(jv_db, user_login.email, config.session_length).await?;
         cookies.add_private(Cookie::new("s_id", session_token));
     } else {
         return Err(errors::AppError {
@@ -35,5 +59,5 @@ pub async fn post(
             message: "Invalid Credentials".to_string(),
         });
     }
-    Ok(Redirect::to("/galleries"))
+    Ok(LoginResponse::Redirect(Redirect::to("/galleries")))
 }
@@ -16,6 +16,7 @@ pub async fn get(cookies: &CookieJar<'_>, jv_db: &Db) -> Result<Redirect, errors
         queries::delete_user_session(jv_db, session_token).await?;
         
         // Remove the session cookie
+        #[allow(deprecated)]
         cookies.remove_private(Cookie::named("s_id"));
     }
     
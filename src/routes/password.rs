@@ -0,0 +1,174 @@
+use crate::config::AppConfig;
+use crate::constants;
+use crate::db::queries;
+use crate::db::queries::Db;
+use crate::errors;
+use crate::models::models::{PasswordForgot, PasswordReset, TokenStatus};
+use crate::routes::signup;
+use crate::tera_utils;
+use log::info;
+use rocket::form::Form;
+use rocket::response::content;
+use rocket::{get, post, State};
+use std::env;
+use std::net::SocketAddr;
+
+#[get("/password/forgot")]
+pub async fn get_forgot() -> Result<content::RawHtml<String>, errors::AppError> {
+    let html = tera_utils::render_template_with_logging("forgot_password.html", &tera::Context::new())?;
+    Ok(content::RawHtml(html))
+}
+
+#[post("/password/forgot", data = "<password_forgot>")]
+pub async fn post_forgot(
+    password_forgot: Form<PasswordForgot<'_>>,
+    db: &Db,
+    config: &State<AppConfig>,
+    remote: Option<SocketAddr>,
+) -> Result<content::RawHtml<String>, errors::AppError> {
+    // The address the connection came from, not one a header claims, as those
+    // can be anything. Requests from unknown addresses share one limit.
+    let address = match remote {
+        Some(remote) => remote.ip().to_string(),
+        None => "unknown".to_string(),
+    };
+    let allowed = queries::claim_password_reset_ip(
+        db,
+        &address,
+        config.password_reset_interval,
+        config.password_reset_ip_limit,
+    )
+    .await?;
+
+    // Always show the same page, so this can't be used to find out who has an
+    // account or when someone was last sent a link
+    let user_id = if allowed {
+        queries::claim_password_reset(db, password_forgot.email, config.password_reset_interval)
+            .await?
+    } else {
+        info!("Too many password resets requested from: {}", address);
+        None
+    };
+
+    match user_id {
+        Some(user_id) => {
+            let token =
+                queries::create_verification_token(db, user_id, constants::PASSWORD_RESET_TOKEN)
+                    .await?;
+            let host = env::var("JV_HOST").expect("JV_HOST must be set");
+            let reset_link = format!("https://{}/password/reset/{}", host, token);
+
+            let mut context = tera::Context::new();
+            context.insert("reset_link", &reset_link);
+            let email_body =
+                tera_utils::render_template_with_logging("password_reset_email.html", &context)?;
+
+            signup::send_email(
+                password_forgot.email,
+                constants::PASSWORD_RESET_SUBJECT,
+                &email_body,
+                constants::PASSWORD_RESET_CATEGORY,
+            )
+            .await?;
+        }
+        None => info!("No password reset sent for: {}", password_forgot.email),
+    }
+
+    let html = tera_utils::render_template_with_logging("password_reset_sent.html", &tera::Context::new())?;
+    Ok(content::RawHtml(html))
+}
+
+#[get("/password/reset/<token>")]
+pub async fn get_reset(
+    token: &str,
+    db: &Db,
+    config: &State<AppConfig>,
+) -> Result<content::RawHtml<String>, errors::AppError> {
+    let token_status = queries::check_verification_token(
+        db,
+        token,
+        constants::PASSWORD_RESET_TOKEN,
+        config.password_reset_ttl,
+    )
+    .await?;
+
+    if !matches!(token_status, TokenStatus::Valid(_)) {
+        let html = tera_utils::render_link_expired(&token_status)?;
+        return Ok(content::RawHtml(html));
+    }
+
+    let mut context = tera::Context::new();
+    context.insert("token", token);
+    let html = tera_utils::render_template_with_logging("reset_password.html", &context)?;
+    Ok(content::RawHtml(html))
+}
+
+#[post("/password/reset/<token>", data = "<password_reset>")]
+pub async fn post_reset(
+    token: &str,
+    password_reset: Form<PasswordReset<'_>>,
+    db: &Db,
+    config: &State<AppConfig>,
+) -> Result<content::RawHtml<String>, errors::AppError> {
+    if password_reset.password != password_reset.password_repeat {
+        let mut context = tera::Context::new();
+        context.insert("token", token);
+        context.insert("error", "Passwords do not match");
+        let html = tera_utils::render_template_with_logging("reset_password.html", &context)?;
+        return Ok(content::RawHtml(html));
+    }
+
+    let token_status = queries::consume_verification_token(
+        db,
+        token,
+        constants::PASSWORD_RESET_TOKEN,
+        config.password_reset_ttl,
+    )
+    .await?;
+
+    let user_id = match token_status {
+        TokenStatus::Valid(user_id) => user_id,
+        _ => {
+            let html = tera_utils::render_link_expired(&token_status)?;
+            return Ok(content::RawHtml(html));
+        }
+    };
+
+    queries::reset_password(db, user_id, password_reset.password).await?;
+    info!("Password reset for user: {}", user_id);
+
+    let html = tera_utils::render_template_with_logging("password_reset_done.html", &tera::Context::new())?;
+    Ok(content::RawHtml(html))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::db::queries::Db;
+    use crate::tests::test_client;
+    use rocket::http::{ContentType, Header, Status};
+    use rocket_db_pools::{sqlx, Database};
+
+    #[rocket::async_test]
+    async fn test_reset_requests_are_limited_by_connection_address() {
+        let (client, dir) = test_client().await;
+
+        let response = client
+            .post("/password/forgot")
+            .header(ContentType::Form)
+            .header(Header::new("X-Real-IP", "192.0.2.1"))
+            .remote("198.51.100.1:4000".parse().unwrap())
+            .body("email=nobody@example.com")
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+
+        let db = Db::fetch(client.rocket()).unwrap();
+        let addresses: Vec<String> = sqlx::query_scalar("SELECT ip FROM password_reset_requests")
+            .fetch_all(&db.0)
+            .await
+            .unwrap();
+        assert_eq!(addresses, vec!["198.51.100.1"]);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
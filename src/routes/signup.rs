@@ -1,19 +1,20 @@
+use crate::config::AppConfig;
 use crate::constants;
 use crate::db::queries;
 use crate::db::queries::Db;
 use crate::errors;
-use crate::models::models::UserSignup;
+use crate::models::models::{ResendVerification, TokenStatus, UserSignup};
 use crate::tera_utils;
 use log::info;
 use reqwest;
 use rocket::form::Form;
 use rocket::response::content;
-use rocket::{get, post};
+use rocket::{get, post, State};
 use rocket_db_pools::Connection;
 use serde_json::json;
 use std::env;
 
-async fn send_email(
+pub async fn send_email(
     to: &str,
     subject: &str,
     body: &str,
@@ -51,24 +52,13 @@ async fn send_email(
     Ok(())
 }
 
-#[get("/signup")]
-pub async fn get() -> Result<content::RawHtml<String>, errors::AppError> {
-    let signup = tera_utils::render_template_with_logging("signup.html", &tera::Context::new())?;
-    Ok(content::RawHtml(signup))
-}
-
-#[post("/signup", data = "<user_signup>")]
-pub async fn post(
-    user_signup: Form<UserSignup>,
-    conn: Connection<Db>,
-) -> Result<content::RawHtml<String>, errors::AppError> {
-    let verification_id =
-        queries::insert_user(conn, &user_signup.email, &user_signup.password).await?;
+// Ask the admin to approve a new user from the admin console
+async fn send_approval_request(new_user_email: &str) -> Result<(), errors::AppError> {
     let host = env::var("JV_HOST").expect("JV_HOST must be set");
-    let verification_link = format!("https://{}/signup/{}", host, verification_id);
+    let admin_link = format!("https://{}/admin/users", host);
     let mut context = tera::Context::new();
-    context.insert("verification_link", &verification_link);
-    context.insert("new_user_email", &user_signup.email);
+    context.insert("admin_link", &admin_link);
+    context.insert("new_user_email", new_user_email);
 
     let email_body = tera_utils::render_template_with_logging("verify_signup.html", &context)?;
     let admin_email = env::var("JV_ADMIN_EMAIL").expect("JV_ADMIN_EMAIL must be set");
@@ -79,19 +69,11 @@ pub async fn post(
         &email_body,
         constants::VERIFY_NEW_USER_CATEGORY,
     )
-    .await?;
-
-    let html = tera_utils::render_template_with_logging("awaiting_verification.html", &tera::Context::new())?;
-    Ok(content::RawHtml(html))
+    .await
 }
 
-#[get("/signup/<verification_id>")]
-pub async fn verify(
-    verification_id: String,
-    db: &Db,
-) -> Result<content::RawHtml<String>, errors::AppError> {
-    // TODO: Add TTL for verification links
-    let email = queries::verify_user(db, &verification_id).await?;
+// Let a newly approved user know they can log in
+pub async fn send_welcome_email(email: &str) -> Result<(), errors::AppError> {
     let host = env::var("JV_HOST").expect("JV_HOST must be set");
     let login_link = format!("https://{}/login", host);
 
@@ -101,13 +83,86 @@ pub async fn verify(
     let email_body = tera_utils::render_template_with_logging("welcome.html", &context)?;
 
     send_email(
-        &email,
+        email,
         constants::WELCOME_SUBJECT,
         &email_body,
         constants::WELCOME_CATEGORY,
     )
+    .await
+}
+
+#[get("/signup")]
+pub async fn get() -> Result<content::RawHtml<String>, errors::AppError> {
+    let signup = tera_utils::render_template_with_logging("signup.html", &tera::Context::new())?;
+    Ok(content::RawHtml(signup))
+}
+
+#[post("/signup", data = "<user_signup>")]
+pub async fn post(
+    user_signup: Form<UserSignup>,
+    conn: Connection<Db>,
+) -> Result<content::RawHtml<String>, errors::AppError> {
+    queries::insert_user(conn, &user_signup.email, &user_signup.password).await?;
+
+    send_approval_request(&user_signup.email).await?;
+
+    let html = tera_utils::render_template_with_logging("awaiting_verification.html", &tera::Context::new())?;
+    Ok(content::RawHtml(html))
+}
+
+#[post("/signup/resend", data = "<resend>")]
+pub async fn resend(
+    resend: Form<ResendVerification<'_>>,
+    db: &Db,
+    config: &State<AppConfig>,
+) -> Result<content::RawHtml<String>, errors::AppError> {
+    // Respond the same way whether or not the email belongs to a pending user,
+    // or the request was resent too recently, so this can't be used to find out
+    // who has an account
+    if !queries::is_user_pending(db, resend.email).await? {
+        info!("No pending user to resend approval request for: {}", resend.email);
+    } else if queries::claim_approval_resend(db, resend.email, config.approval_resend_interval)
+        .await?
+    {
+        info!("Resending approval request for: {}", resend.email);
+        send_approval_request(resend.email).await?;
+    } else {
+        info!("Approval request resent too recently for: {}", resend.email);
+    }
+
+    let html = tera_utils::render_template_with_logging("awaiting_verification.html", &tera::Context::new())?;
+    Ok(content::RawHtml(html))
+}
+
+// Approval links sent before the admin console existed still work until they expire
+#[get("/signup/<verification_id>")]
+pub async fn verify(
+    verification_id: String,
+    db: &Db,
+    config: &State<AppConfig>,
+) -> Result<content::RawHtml<String>, errors::AppError> {
+    let token_status = queries::consume_verification_token(
+        db,
+        &verification_id,
+        constants::SIGNUP_TOKEN,
+        config.verification_ttl,
+    )
     .await?;
 
+    let user_id = match token_status {
+        TokenStatus::Valid(user_id) => user_id,
+        _ => {
+            info!("Verification link rejected: {:?}", token_status);
+            let html = tera_utils::render_link_expired(&token_status)?;
+            return Ok(content::RawHtml(html));
+        }
+    };
+
+    // Someone may have approved them while the link was in their inbox
+    if let Some(email) = queries::verify_user(db, user_id).await? {
+        send_welcome_email(&email).await?;
+    }
+
     let login = "";
     Ok(content::RawHtml(login.to_string()))
 }
@@ -0,0 +1,74 @@
+use crate::config::AppConfig;
+use crate::db::queries;
+use crate::db::queries::Db;
+use crate::errors;
+use crate::middleware::WriterSession;
+use crate::tera_utils;
+
+use log::info;
+use rocket::http::Status;
+use rocket::response::content;
+use rocket::{get, post, State};
+
+// Deleted galleries and images the writer could restore, until they are purged
+#[get("/trash")]
+pub async fn get(
+    db: &Db,
+    writer_session: WriterSession,
+    config: &State<AppConfig>,
+) -> Result<content::RawHtml<String>, errors::AppError> {
+    let galleries = queries::get_trashed_galleries(db, writer_session.user()).await?;
+    let images = queries::get_trashed_images(db, writer_session.user()).await?;
+
+    let mut context = tera::Context::new();
+    context.insert("user", writer_session.user());
+    context.insert("galleries", &galleries);
+    context.insert("images", &images);
+    context.insert("retention_days", &config.trash_retention_days);
+
+    let trash = tera_utils::render_template_with_logging("trash.html", &context)?;
+    Ok(content::RawHtml(trash))
+}
+
+#[post("/trash/galleries/<gallery_id>/restore")]
+pub async fn restore_gallery(
+    db: &Db,
+    writer_session: WriterSession,
+    gallery_id: i64,
+) -> Result<content::RawHtml<String>, errors::AppError> {
+    writer_session
+        .ensure_can_restore_gallery(db, gallery_id)
+        .await?;
+    info!(
+        "{} restored gallery {}",
+        writer_session.user().email,
+        gallery_id
+    );
+    queries::restore_gallery(db, gallery_id).await?;
+    Ok(content::RawHtml(format!(
+        "Gallery restored: {}",
+        gallery_id
+    )))
+}
+
+// Images can only be restored into galleries that aren't deleted themselves
+#[post("/trash/images/<image_id>/restore")]
+pub async fn restore_image(
+    db: &Db,
+    writer_session: WriterSession,
+    image_id: i64,
+) -> Result<content::RawHtml<String>, errors::AppError> {
+    writer_session.ensure_can_edit_image(db, image_id).await?;
+    info!(
+        "{} restored image {}",
+        writer_session.user().email,
+        image_id
+    );
+    if !queries::restore_image(db, image_id).await? {
+        return Err(errors::AppError {
+            code: Status::Conflict.code,
+            message: "The same photo is already in its gallery".to_string(),
+        });
+    }
+    Ok(content::RawHtml(format!("Image restored: {}", image_id)))
+}
@@ -0,0 +1,353 @@
+use crate::config::AppConfig;
+use crate::db::queries;
+use crate::db::queries::Db;
+use crate::errors;
+use crate::middleware::WriterSession;
+use crate::models::models;
+use crate::routes::galleries;
+use crate::storage::SharedStorage;
+
+use log::{info, warn};
+use rocket::data::{Data, ToByteUnit};
+use rocket::form::Form;
+use rocket::http::{Header, Status};
+use rocket::response::content;
+use rocket::tokio::fs::{self, OpenOptions};
+use rocket::tokio::io::{AsyncSeekExt, SeekFrom};
+use rocket::{delete, head, post, put, Responder, State};
+
+// A resumable upload for originals too big to send in one request. The client
+// creates an upload, sends the file in chunks with PUTs, checks with HEAD how
+// much has arrived after a dropped connection, and finishes it to make an image.
+
+/// Where an upload has got to, in the headers
+#[derive(Responder)]
+pub struct UploadStatus {
+    // A bare `Status` would send 409 to a catcher, losing the headers
+    inner: (Status, ()),
+    offset: Header<'static>,
+    length: Header<'static>,
+    location: Header<'static>,
+    cache_control: Header<'static>,
+}
+
+impl UploadStatus {
+    fn new(status: Status, upload: &models::Upload) -> Self {
+        UploadStatus {
+            inner: (status, ()),
+            offset: Header::new("Upload-Offset", upload.received.to_string()),
+            length: Header::new("Upload-Length", upload.length.to_string()),
+            location: Header::new("Location", format!("/uploads/{}", upload.id)),
+            cache_control: Header::new("Cache-Control", "no-store"),
+        }
+    }
+}
+
+fn not_found() -> errors::AppError {
+    errors::AppError {
+        code: Status::NotFound.code,
+        message: "Upload not found".to_string(),
+    }
+}
+
+async fn get_upload(
+    db: &Db,
+    writer_session: &WriterSession,
+    upload_id: &str,
+) -> Result<models::Upload, errors::AppError> {
+    queries::get_upload(db, upload_id, writer_session.user().id)
+        .await?
+        .ok_or_else(not_found)
+}
+
+async fn remove_part(path: &str) {
+    if let Err(e) = fs::remove_file(path).await {
+        warn!("Failed to remove {}: {}", path, e);
+    }
+}
+
+async fn discard(db: &Db, upload: &models::Upload, path: &str) -> Result<(), errors::AppError> {
+    queries::delete_upload(db, &upload.id).await?;
+    remove_part(path).await;
+    Ok(())
+}
+
+#[post("/galleries/<gallery_id>/uploads", data = "<create>")]
+pub async fn create(
+    create: Form<models::UploadCreate<'_>>,
+    db: &Db,
+    writer_session: WriterSession,
+    gallery_id: i64,
+    config: &State<AppConfig>,
+) -> Result<UploadStatus, errors::AppError> {
+    writer_session
+        .ensure_can_edit_gallery(db, gallery_id)
+        .await?;
+
+    if create.length > config.upload_max_length {
+        return Err(errors::AppError {
+            code: Status::PayloadTooLarge.code,
+            message: format!("Files can be at most {} bytes", config.upload_max_length),
+        });
+    }
+
+    let upload = queries::create_upload(
+        db,
+        writer_session.user().id,
+        gallery_id,
+        create.file_name,
+        create.length,
+    )
+    .await?;
+    fs::File::create(upload.part_path(&config.upload_path)).await?;
+
+    info!(
+        "Started upload {} of {} ({} bytes)",
+        upload.id, upload.file_name, upload.length
+    );
+    Ok(UploadStatus::new(Status::Created, &upload))
+}
+
+#[head("/uploads/<upload_id>")]
+pub async fn status(
+    db: &Db,
+    writer_session: WriterSession,
+    upload_id: &str,
+) -> Result<UploadStatus, errors::AppError> {
+    let upload = get_upload(db, &writer_session, upload_id).await?;
+    Ok(UploadStatus::new(Status::Ok, &upload))
+}
+
+// Chunks must be sent in order: one starting anywhere but where the last one
+// ended is refused, with the offset to carry on from
+#[put("/uploads/<upload_id>?<offset>", data = "<chunk>")]
+pub async fn put_chunk(
+    chunk: Data<'_>,
+    db: &Db,
+    writer_session: WriterSession,
+    upload_id: &str,
+    offset: i64,
+    config: &State<AppConfig>,
+) -> Result<UploadStatus, errors::AppError> {
+    let upload = get_upload(db, &writer_session, upload_id).await?;
+
+    if offset != upload.received {
+        warn!(
+            "Chunk for upload {} sent at {}, expected {}",
+            upload.id, offset, upload.received
+        );
+        return Ok(UploadStatus::new(Status::Conflict, &upload));
+    }
+
+    // Write where the chunk belongs rather than appending, so a chunk sent twice
+    // at once lands in the same place
+    let mut file = OpenOptions::new()
+        .write(true)
+        .open(upload.part_path(&config.upload_path))
+        .await?;
+    file.seek(SeekFrom::Start(offset as u64)).await?;
+    let written = chunk
+        .open(config.upload_chunk_limit.bytes())
+        .stream_to(&mut file)
+        .await?;
+    if !written.complete {
+        return Err(errors::AppError {
+            code: Status::PayloadTooLarge.code,
+            message: format!("Chunks can be at most {} bytes", config.upload_chunk_limit),
+        });
+    }
+
+    let received = offset + written.written as i64;
+    if !queries::advance_upload(db, &upload.id, offset, received).await? {
+        // Another request got there first, or the chunk ran past the end
+        let upload = get_upload(db, &writer_session, upload_id).await?;
+        return Ok(UploadStatus::new(Status::Conflict, &upload));
+    }
+
+    Ok(UploadStatus::new(
+        Status::NoContent,
+        &models::Upload { received, ..upload },
+    ))
+}
+
+#[post("/uploads/<upload_id>/finish", data = "<finish>")]
+pub async fn finish(
+    finish: Form<models::UploadFinish<'_>>,
+    db: &Db,
+    writer_session: WriterSession,
+    upload_id: &str,
+    config: &State<AppConfig>,
+    storage: &State<SharedStorage>,
+) -> Result<content::RawHtml<String>, errors::AppError> {
+    let upload = get_upload(db, &writer_session, upload_id).await?;
+    writer_session
+        .ensure_can_edit_gallery(db, upload.gallery_id)
+        .await?;
+
+    if upload.received != upload.length {
+        return Err(errors::AppError {
+            code: Status::Conflict.code,
+            message: format!(
+                "Upload {} has {} of {} bytes",
+                upload.id, upload.received, upload.length
+            ),
+        });
+    }
+
+    // A chunk sent twice can leave bytes past the end
+    let path = upload.part_path(&config.upload_path);
+    OpenOptions::new()
+        .write(true)
+        .open(&path)
+        .await?
+        .set_len(upload.length as u64)
+        .await?;
+
+    // Take the upload first, so finishing it twice at once makes one image
+    if !queries::claim_upload(db, &upload.id).await? {
+        return Err(not_found());
+    }
+
+    let image = match galleries::save_upload(
+        db,
+        storage.as_ref(),
+        writer_session.user().id,
+        upload.gallery_id,
+        galleries::UploadedFile::Chunked {
+            upload: &upload,
+            path: &path,
+        },
+        finish.caption,
+        finish.crop,
+    )
+    .await
+    {
+        Ok(galleries::Saved::Image(image)) => image,
+        // A duplicate will never be taken, so there's no point keeping it
+        Ok(galleries::Saved::Duplicate(e)) => {
+            remove_part(&path).await;
+            return Err(e);
+        }
+        // Anything else can be tried again
+        Err(e) => {
+            queries::restore_upload(db, &upload, writer_session.user().id).await?;
+            return Err(e);
+        }
+    };
+
+    info!("Finished upload {} as image {}", upload.id, image.id);
+    galleries::render_image_item(&image, upload.gallery_id, true, config)
+}
+
+#[delete("/uploads/<upload_id>")]
+pub async fn delete(
+    db: &Db,
+    writer_session: WriterSession,
+    upload_id: &str,
+    config: &State<AppConfig>,
+) -> Result<Status, errors::AppError> {
+    let upload = get_upload(db, &writer_session, upload_id).await?;
+    discard(db, &upload, &upload.part_path(&config.upload_path)).await?;
+    Ok(Status::NoContent)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::db::queries::Db;
+    use crate::storage;
+    use crate::tests::{log_in_writer, test_client};
+    use image::{ImageFormat, RgbImage};
+    use rocket::http::{ContentType, Status};
+    use rocket_db_pools::{sqlx, Database};
+    use std::io::Cursor;
+
+    #[rocket::async_test]
+    async fn test_chunks_and_finish() {
+        let (client, dir) = test_client().await;
+        let (user_id, cookie) = log_in_writer(&client, "writer@example.com").await;
+        let db = Db::fetch(client.rocket()).unwrap();
+        let gallery_id = crate::db::queries::create_gallery(db, user_id, "chunks")
+            .await
+            .unwrap();
+
+        let mut photo = Cursor::new(vec![]);
+        RgbImage::new(40, 30)
+            .write_to(&mut photo, ImageFormat::Png)
+            .unwrap();
+        let photo = photo.into_inner();
+
+        let response = client
+            .post(format!("/galleries/{}/uploads", gallery_id))
+            .header(ContentType::Form)
+            .private_cookie(cookie.clone())
+            .body(format!("file_name=photo.png&length={}", photo.len()))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Created);
+        let location = response.headers().get_one("Location").unwrap().to_string();
+        let part = dir
+            .join("uploads")
+            .join(format!("{}.part", location.trim_start_matches("/uploads/")));
+        assert!(part.exists());
+
+        // Out of order
+        let response = client
+            .put(format!("{}?offset=5", location))
+            .private_cookie(cookie.clone())
+            .body(&photo)
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Conflict);
+        assert_eq!(response.headers().get_one("Upload-Offset"), Some("0"));
+
+        // Too big, though what fits is written before that's found out
+        let response = client
+            .put(format!("{}?offset=0", location))
+            .private_cookie(cookie.clone())
+            .body(vec![0; 9 * 1024 * 1024])
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::PayloadTooLarge);
+
+        let response = client
+            .put(format!("{}?offset=0", location))
+            .private_cookie(cookie.clone())
+            .body(&photo)
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::NoContent);
+        assert_eq!(
+            response.headers().get_one("Upload-Offset"),
+            Some(photo.len().to_string().as_str())
+        );
+
+        let response = client
+            .post(format!("{}/finish", location))
+            .header(ContentType::Form)
+            .private_cookie(cookie.clone())
+            .body("caption=")
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+
+        // Cut back to the upload's length, leaving nothing of the big chunk
+        let (path,): (String,) = sqlx::query_as("SELECT path FROM original_images")
+            .fetch_one(&db.0)
+            .await
+            .unwrap();
+        let stored = std::fs::read(dir.join("img").join(storage::key(&path))).unwrap();
+        assert_eq!(stored, photo);
+        assert!(!part.exists());
+
+        let response = client
+            .post(format!("{}/finish", location))
+            .header(ContentType::Form)
+            .private_cookie(cookie)
+            .body("caption=")
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::NotFound);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
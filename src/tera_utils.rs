@@ -1,4 +1,5 @@
 use crate::constants;
+use crate::models::models::TokenStatus;
 use log::error;
 use tera::Context;
 
@@ -42,4 +43,12 @@ pub fn render_template_with_logging(
             Err(e)
         }
     }
-}
\ No newline at end of file
+}
+
+/// Renders the page shown when an emailed link (signup verification, password
+/// reset) has expired, has already been used or doesn't exist
+pub fn render_link_expired(token_status: &TokenStatus) -> Result<String, tera::Error> {
+    let mut context = Context::new();
+    context.insert("reason", token_status.reason());
+    render_template_with_logging("link_expired.html", &context)
+}
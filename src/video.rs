@@ -0,0 +1,79 @@
+use crate::constants;
+use crate::errors;
+use image::DynamicImage;
+use log::info;
+use std::process::{Command, Output};
+
+fn run(mut command: Command) -> Result<Output, errors::AppError> {
+    let output = command.output()?;
+    if !output.status.success() {
+        return Err(errors::AppError {
+            code: 500,
+            message: format!(
+                "ffmpeg failed ({}): {}",
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ),
+        });
+    }
+    Ok(output)
+}
+
+/// Transcode the video at `original` to an MP4 at `path` that every browser can
+/// play, no wider than `VIDEO_MAX_WIDTH`
+///
+/// Metadata (GPS included) is dropped, and the index is moved to the start so
+/// playback can begin before the whole file has arrived. Takes a while, so call
+/// this from a blocking task.
+pub fn transcode(ffmpeg: &str, original: &str, path: &str) -> Result<(), errors::AppError> {
+    info!("Transcoding {} to {}", original, path);
+
+    let mut command = Command::new(ffmpeg);
+    command
+        .args(["-y", "-v", "error", "-i", original])
+        // The first video stream, and the first audio stream if there is one
+        .args(["-map", "0:v:0", "-map", "0:a:0?", "-map_metadata", "-1"])
+        .args(["-c:v", "libx264", "-preset", "veryfast", "-crf", "23"])
+        .args(["-pix_fmt", "yuv420p"])
+        .arg("-vf")
+        .arg(format!("scale='min({},iw)':-2", constants::VIDEO_MAX_WIDTH))
+        .args(["-c:a", "aac", "-movflags", "+faststart", "-f", "mp4", path]);
+    run(command)?;
+
+    Ok(())
+}
+
+/// The first frame of the video at `path`, shown until it is played
+pub fn poster_frame(ffmpeg: &str, path: &str) -> Result<DynamicImage, errors::AppError> {
+    info!("Getting poster frame of {}", path);
+
+    let mut command = Command::new(ffmpeg);
+    command.args(["-v", "error", "-i", path]);
+    // One frame, as a PNG on stdout
+    command.args(["-frames:v", "1", "-f", "image2pipe", "-c:v", "png", "-"]);
+    let output = run(command)?;
+
+    Ok(image::load_from_memory(&output.stdout)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_missing_ffmpeg_is_an_error() {
+        let ffmpeg = "./no-such-ffmpeg";
+
+        assert!(transcode(ffmpeg, "in.mov", "out.mp4").is_err());
+        assert!(poster_frame(ffmpeg, "out.mp4").is_err());
+    }
+
+    #[test]
+    fn test_failed_ffmpeg_is_an_error() {
+        // Any command that exits with a failure stands in for ffmpeg
+        let error = poster_frame("false", "out.mp4").unwrap_err();
+
+        assert_eq!(error.code, 500);
+        assert!(error.message.starts_with("ffmpeg failed"));
+    }
+}
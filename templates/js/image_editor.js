@@ -0,0 +1,99 @@
+// Loaded each time the editor opens, so state lives on one global object
+var imageEditor = {
+  croppie: null,
+  // Clockwise, applied by the server before cropping
+  rotation: 0,
+  points: null,
+};
+
+// Viewport sizes for each aspect ratio, fitting the 450px boundary
+var IMAGE_EDITOR_VIEWPORTS = {
+  "1:1": { width: 450, height: 450 },
+  "4:3": { width: 450, height: 338 },
+  "16:9": { width: 450, height: 253 },
+  "3:4": { width: 338, height: 450 },
+};
+
+// EXIF orientations that turn an image clockwise by 0, 90, 180 and 270 degrees
+var IMAGE_EDITOR_ORIENTATIONS = [1, 6, 3, 8];
+
+function startImageEditor(aspect) {
+  const element = document.getElementById("image-editor-croppie");
+  if (imageEditor.croppie != null) {
+    imageEditor.croppie.destroy();
+  }
+  imageEditor.croppie = new Croppie(element, {
+    viewport: IMAGE_EDITOR_VIEWPORTS[aspect],
+    boundary: { width: 450, height: 450 },
+    showZoomer: false,
+    enableResize: false,
+    enableOrientation: true,
+    enforceBoundary: true,
+    customClass: "croppie-container",
+    mouseWheelZoom: true,
+  });
+  imageEditor.croppie.bind({
+    url: element.dataset.url,
+    points: imageEditor.points,
+    orientation: IMAGE_EDITOR_ORIENTATIONS[imageEditor.rotation / 90],
+  });
+}
+
+// Start from the aspect ratio closest to the current crop
+function closestAspect(width, height) {
+  const ratio = width / height;
+  return Object.keys(IMAGE_EDITOR_VIEWPORTS).reduce((best, aspect) => {
+    const viewport = IMAGE_EDITOR_VIEWPORTS[aspect];
+    const bestViewport = IMAGE_EDITOR_VIEWPORTS[best];
+    return Math.abs(viewport.width / viewport.height - ratio) <
+      Math.abs(bestViewport.width / bestViewport.height - ratio)
+      ? aspect
+      : best;
+  });
+}
+
+function setAspectRatio(aspect) {
+  // The old crop won't fit the new shape
+  imageEditor.points = null;
+  startImageEditor(aspect);
+}
+
+function rotateEditedImage() {
+  // Croppie turns anticlockwise for positive degrees
+  imageEditor.croppie.rotate(-90);
+  imageEditor.rotation = (imageEditor.rotation + 90) % 360;
+}
+
+(() => {
+  const element = document.getElementById("image-editor-croppie");
+  imageEditor.croppie = null;
+  imageEditor.rotation = Number(element.dataset.rotation || 0);
+  imageEditor.points = null;
+  let aspect = "1:1";
+  if (element.dataset.crop) {
+    const [x, y, width, height] = element.dataset.crop.split(",").map(Number);
+    imageEditor.points = [x, y, x + width, y + height];
+    aspect = closestAspect(width, height);
+  }
+  document.getElementById("aspect-input").value = aspect;
+  startImageEditor(aspect);
+})();
+
+// Event Listeners
+
+if (!window.imageEditorListening) {
+  window.imageEditorListening = true;
+
+  // Send where the crop is in the original, the server cuts the image out itself
+  document.body.addEventListener("htmx:configRequest", (evt) => {
+    if (evt.detail.elt.id !== "image-editor-form") {
+      return;
+    }
+    const [x1, y1, x2, y2] = imageEditor.croppie.get().points.map(Number);
+    evt.detail.parameters["crop.x"] = x1;
+    evt.detail.parameters["crop.y"] = y1;
+    evt.detail.parameters["crop.width"] = x2 - x1;
+    evt.detail.parameters["crop.height"] = y2 - y1;
+    evt.detail.parameters["crop.rotation"] = imageEditor.rotation;
+  });
+}
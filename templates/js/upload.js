@@ -2,7 +2,15 @@
 
 var c = null;
 
-var modifiedFile = null;
+// Clockwise, applied by the server before cropping
+var rotation = 0;
+
+// Files bigger than this are sent in chunks that survive a dropped connection
+const CHUNK_SIZE = 4 * 1024 * 1024;
+const CHUNK_RETRIES = 5;
+
+// How many files of a batch are sent at once
+const BATCH_PARALLEL = 3;
 
 // Functions
 
@@ -14,7 +22,7 @@ const croppie = () => {
       boundary: { width: 450, height: 450 },
       showZoomer: false,
       enableResize: false,
-      enableOrientation: false,
+      enableOrientation: true,
       enforceBoundary: true,
       customClass: "croppie-container",
       mouseWheelZoom: true,
@@ -23,8 +31,19 @@ const croppie = () => {
   return c;
 };
 
+// Videos are uploaded whole, only images are cropped
+const isVideo = (file) => file.type.startsWith("video/");
+
+const isMedia = (file) => file.type.startsWith("image/") || isVideo(file);
+
+const selectedFile = () => document.getElementById("file-input").files[0];
+
 const uploadFile = () => {
   const img = event.target.files[0];
+  rotation = 0;
+  if (isVideo(img)) {
+    return;
+  }
   const url = URL.createObjectURL(img);
   croppie()
     .bind({
@@ -36,30 +55,214 @@ const uploadFile = () => {
     });
 };
 
-async function setModifiedFile() {
-  if (modifiedFile == null) {
-    const blob = await croppie().result({
-      type: "blob",
-      format: "jpeg",
-      quality: 1,
-      size: { width: 900 },
-    });
-    modifiedFile = new File([blob], Date.now().toString(), {
-      type: "image/jpeg",
-    });
+const rotateImage = () => {
+  // Croppie turns anticlockwise for positive degrees
+  croppie().rotate(-90);
+  rotation = (rotation + 90) % 360;
+};
+
+// Where the crop is in the original, the server cuts the image out itself
+const cropParameters = () => {
+  const file = selectedFile();
+  if (file && isVideo(file)) {
+    return {};
   }
-}
+  const [x1, y1, x2, y2] = croppie().get().points.map(Number);
+  return {
+    "crop.x": x1,
+    "crop.y": y1,
+    "crop.width": x2 - x1,
+    "crop.height": y2 - y1,
+    "crop.rotation": rotation,
+  };
+};
+
+const uploadOffset = async (location) => {
+  const response = await fetch(location, { method: "HEAD" });
+  if (!response.ok) {
+    throw new Error(`Upload lost: ${response.status}`);
+  }
+  return Number(response.headers.get("Upload-Offset"));
+};
+
+// Send the file a chunk at a time, carrying on from wherever the server got
+// to when a chunk fails
+const sendChunks = async (location, file, progress) => {
+  let offset = 0;
+  let failures = 0;
+  while (offset < file.size) {
+    try {
+      const response = await fetch(`${location}?offset=${offset}`, {
+        method: "PUT",
+        headers: { "Content-Type": "application/offset+octet-stream" },
+        body: file.slice(offset, offset + CHUNK_SIZE),
+      });
+      if (!response.ok && response.status !== 409) {
+        throw new Error(`Chunk failed: ${response.status}`);
+      }
+      offset = Number(response.headers.get("Upload-Offset"));
+      failures = 0;
+      progress.value = (offset / file.size) * 100;
+    } catch (error) {
+      failures += 1;
+      if (failures > CHUNK_RETRIES) {
+        throw error;
+      }
+      await new Promise((resolve) => setTimeout(resolve, 1000 * failures));
+      offset = await uploadOffset(location);
+    }
+  }
+};
+
+// Upload a file to the gallery at `galleryUrl` in chunks and finish it with
+// `fields`, returning the new gallery item
+const chunkedUpload = async (galleryUrl, file, fields, progress) => {
+  const create = new FormData();
+  create.append("file_name", file.name);
+  create.append("length", file.size);
+  const response = await fetch(`${galleryUrl}/uploads`, {
+    method: "POST",
+    body: create,
+  });
+  if (!response.ok) {
+    throw new Error(`Upload refused: ${response.status}`);
+  }
+  const location = response.headers.get("Location");
+
+  await sendChunks(location, file, progress);
+
+  const finish = new FormData();
+  for (const [name, value] of Object.entries(fields)) {
+    finish.append(name, value);
+  }
+  const tile = await fetch(`${location}/finish`, { method: "POST", body: finish });
+  if (!tile.ok) {
+    throw new Error((await tile.text()) || `Upload not finished: ${tile.status}`);
+  }
+  return tile.text();
+};
+
+const addToGallery = (html) => {
+  const gallery = document.getElementById("gallery");
+  gallery.insertAdjacentHTML("beforeend", html);
+  htmx.process(gallery.lastElementChild);
+};
+
+// Post one file of a batch on its own, so it has its own progress bar and a
+// failure loses nothing else, returning the server's report
+const postBatchFile = (url, file, progress) =>
+  new Promise((resolve, reject) => {
+    const form = new FormData();
+    form.append("files", file);
+    const request = new XMLHttpRequest();
+    request.open("POST", url);
+    request.upload.onprogress = (evt) => {
+      progress.value = (evt.loaded / evt.total) * 100;
+    };
+    request.onload = () => {
+      if (request.status === 200) {
+        resolve(request.responseText);
+      } else {
+        reject(new Error(`Upload failed: ${request.status}`));
+      }
+    };
+    request.onerror = () => reject(new Error("Upload failed"));
+    request.send(form);
+  });
+
+// Add the gallery items in a batch report to the gallery, returning its line
+// about the file
+const takeBatchReport = (html) => {
+  const report = document.createElement("template");
+  report.innerHTML = html;
+  const results = report.content.getElementById("batch_results");
+  const result = results.querySelector(".batch-result");
+  results.remove();
+  const gallery = document.getElementById("gallery");
+  for (const tile of [...report.content.children]) {
+    gallery.append(tile);
+    htmx.process(tile);
+  }
+  return result;
+};
+
+// Send every file of a batch, a few at a time, each with its own line in the
+// results showing its progress and then what happened to it. Big files go in
+// chunks like single uploads do.
+const batchUpload = async (form) => {
+  const url = form.getAttribute("hx-post");
+  const galleryUrl = url.replace(/\/batch$/, "");
+  const results = document.getElementById("batch_results");
+  results.replaceChildren();
+
+  const queue = [...form.querySelector("[name='files']").files].map((file) => {
+    const item = document.createElement("li");
+    item.className = "batch-result";
+    item.textContent = `${file.name}: `;
+    const progress = document.createElement("progress");
+    progress.className = "progress-bar";
+    progress.max = 100;
+    progress.value = 0;
+    item.append(progress);
+    results.append(item);
+    return { file, item, progress };
+  });
+
+  const uploadNext = async () => {
+    while (queue.length > 0) {
+      const { file, item, progress } = queue.shift();
+      try {
+        if (!isMedia(file)) {
+          throw new Error("Not an image or video");
+        } else if (file.size > CHUNK_SIZE) {
+          addToGallery(await chunkedUpload(galleryUrl, file, { caption: "" }, progress));
+          item.textContent = `${file.name}: uploaded`;
+        } else {
+          item.replaceWith(takeBatchReport(await postBatchFile(url, file, progress)));
+        }
+      } catch (error) {
+        item.classList.add("failed");
+        item.textContent = `${file.name}: ${error.message}`;
+      }
+    }
+  };
+
+  await Promise.all(Array.from({ length: BATCH_PARALLEL }, uploadNext));
+  form.reset();
+};
 
 // Event Listeners
 
 document.body.addEventListener("htmx:confirm", (evt) => {
-  if (modifiedFile == null) {
+  if (evt.detail.elt.id !== "upload-demo") {
+    return;
+  }
+  const form = evt.detail.elt;
+  const file = selectedFile();
+  if (file && file.size > CHUNK_SIZE) {
     evt.preventDefault();
-    setModifiedFile().then(() => evt.detail.issueRequest());
+    const fields = {
+      caption: form.querySelector("[name='caption']").value,
+      ...cropParameters(),
+    };
+    const progress = document.getElementById("progress");
+    chunkedUpload(form.getAttribute("hx-post"), file, fields, progress)
+      .then(addToGallery)
+      .catch((error) => alert(error.message));
   }
 });
 
+document.body.addEventListener("htmx:confirm", (evt) => {
+  if (evt.detail.elt.id !== "batch-upload") {
+    return;
+  }
+  evt.preventDefault();
+  batchUpload(evt.detail.elt);
+});
+
 document.body.addEventListener("htmx:configRequest", (evt) => {
-  evt.detail.parameters["modified_file"] = modifiedFile;
-  modifiedFile = null;
+  if (evt.detail.elt.id !== "upload-demo") {
+    return;
+  }
+  Object.assign(evt.detail.parameters, cropParameters());
 });